@@ -0,0 +1,853 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use gettextrs::gettext as i18n;
+use gtk4::glib;
+
+use crate::csv;
+use crate::devices;
+use crate::drivers;
+use crate::export;
+use crate::gpx;
+use crate::nmea;
+use crate::settings;
+use crate::split::{self, Split};
+use crate::track::GpsData;
+use crate::Format;
+
+/// Exit code for success.
+const EXIT_OK: i32 = 0;
+/// Exit code for command line errors. Same as `drivers::Error::WrongArg`.
+const EXIT_USAGE: i32 = 2;
+
+/// The commands the command line knows.
+const COMMANDS: [&str; 10] = [
+    "download",
+    "convert",
+    "erase",
+    "log-enable",
+    "log-disable",
+    "log-status",
+    "power-off",
+    "list-devices",
+    "list-ports",
+    "help",
+];
+
+/// Options for the commands.
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    model: Option<String>,
+    port: Option<String>,
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    /// The output formats. From the output file if empty.
+    formats: Vec<Format>,
+    /// How to split the output in several files.
+    split: Option<Split>,
+    /// Only keep the points recorded after.
+    since: Option<DateTime<Utc>>,
+    /// Only keep the points recorded after the last download from the
+    /// device, and record it.
+    new_only: bool,
+    erase: bool,
+    shutoff: bool,
+    timeout: Option<Duration>,
+    inactivity: Option<Duration>,
+    /// The gpsbabel executable.
+    gpsbabel: Option<PathBuf>,
+    export: export::Options,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Download(Options),
+    Convert(Options),
+    Erase(Options),
+    SetLogging(Options, bool),
+    LoggingStatus(Options),
+    Shutoff(Options),
+    ListDevices,
+    ListPorts(Options),
+    Help,
+}
+
+/// Return true if `arg` is a command line command, and gpsami
+/// should run without UI.
+pub fn is_command(arg: &str) -> bool {
+    COMMANDS.contains(&arg) || arg == "--help" || arg == "-h"
+}
+
+/// The exit code for the error.
+fn exit_code(err: &drivers::Error) -> i32 {
+    match *err {
+        drivers::Error::WrongArg => EXIT_USAGE,
+        drivers::Error::Unsupported => 3,
+        drivers::Error::NoDriver(_) => 4,
+        drivers::Error::Cancelled => 5,
+        drivers::Error::Failed(_) => 6,
+        drivers::Error::Io(_) => 7,
+        drivers::Error::Timeout => 8,
+        drivers::Error::GpsBabel { .. } => 9,
+    }
+}
+
+fn usage() -> String {
+    i18n(
+        "Usage: gpsami [COMMAND] [OPTIONS]
+
+Without command, start the application.
+
+Commands:
+  download       Download the tracks from the device
+  convert        Convert a GPX or NMEA file to another format
+  erase          Erase the tracks on the device
+  log-enable     Enable logging on the device
+  log-disable    Disable logging on the device
+  log-status     Tell if logging is enabled on the device
+  power-off      Power off the device
+  list-devices   List the supported devices
+  list-ports     List the ports, for the model if specified
+  help           Show this help
+
+Options:
+  -m, --model MODEL   The device model, as listed by list-devices
+  -p, --port PORT     The device port. Optional if there is only one
+  -i, --input FILE    The input file (convert)
+  -o, --output FILE   The output file (download, convert)
+  -f, --format FORMATS
+                      The output formats, comma separated, among gpx,
+                      kml, geojson, tcx, fit, csv, nmea and igc. By
+                      default from the output file extension, or gpx.
+                      With several formats, a file for each is written
+                      next to the output file (download, convert)
+  --split MODE        Write a file per piece, named after the output
+                      file and the start time: gap[:MINUTES] when no
+                      point is recorded for 30 minutes or MINUTES,
+                      day[:ZONE] on each day in local time or ZONE
+                      (utc or +HH:MM), or segment on each segment
+                      recorded by the device (download, convert)
+  --since TIME        Only keep the points recorded after TIME, in RFC
+                      3339 like 2024-05-04T12:00:00Z. The time of the
+                      last point downloaded is printed (download)
+  --new-only          Only keep the points recorded after the last
+                      download from the device, like the application.
+                      The device is told apart by the serial number of
+                      its port (download)
+  --columns COLUMNS   The CSV columns, comma separated, among time, lat,
+                      lon, ele, speed, course, hdop, sat and segment.
+                      All by default (download, convert)
+  --time-format TIME  The CSV time: utc, local or epoch. Default utc
+                      (download, convert)
+  --delimiter CHAR    The CSV delimiter, or tab. Default ,
+                      (download, convert)
+  --pilot NAME        The IGC pilot (download, convert)
+  --copilot NAME      The IGC second crew member (download, convert)
+  --glider-type TYPE  The IGC glider type (download, convert)
+  --glider-id ID      The IGC glider registration (download, convert)
+  --competition-id ID The IGC competition id (download, convert)
+  --competition-class CLASS
+                      The IGC competition class (download, convert)
+  -e, --erase         Erase the device after download (download)
+  --power-off         Power off the device after download (download)
+  --timeout SECONDS   Abort the operation after SECONDS
+  --inactivity SECONDS
+                      Abort if the device doesn't answer for SECONDS
+  --gpsbabel PROGRAM  The gpsbabel executable. Default gpsbabel from PATH
+
+Exit codes:
+  0 success, 2 incorrect argument, 3 unsupported, 4 no driver,
+  5 cancelled, 6 failed, 7 I/O error, 8 timeout,
+  9 gpsbabel missing, too old or without the device",
+    )
+}
+
+/// Parse the options in `args`.
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| iter.next().cloned())
+                .ok_or_else(|| format!("{}: {name}", i18n("Missing value for option")))
+        };
+        let seconds = |value: String| {
+            value
+                .parse()
+                .map(Duration::from_secs)
+                .map_err(|_| format!("{}: {name}", i18n("Invalid number of seconds for option")))
+        };
+        match name {
+            "-m" | "--model" => options.model = Some(value()?),
+            "-p" | "--port" => options.port = Some(value()?),
+            "-i" | "--input" => options.input = Some(PathBuf::from(value()?)),
+            "-o" | "--output" => options.output = Some(PathBuf::from(value()?)),
+            "-f" | "--format" => {
+                let formats = value()?;
+                options.formats = export::parse_formats(&formats)
+                    .ok_or_else(|| format!("{}: {formats}", i18n("Unknown format")))?;
+            }
+            "--split" => {
+                let split = value()?;
+                options.split = Some(
+                    Split::parse(&split)
+                        .ok_or_else(|| format!("{}: {split}", i18n("Invalid split")))?,
+                );
+            }
+            "--since" => {
+                let since = value()?;
+                options.since = Some(
+                    DateTime::parse_from_rfc3339(&since)
+                        .map_err(|_| format!("{}: {since}", i18n("Invalid time")))?
+                        .with_timezone(&Utc),
+                );
+            }
+            "--new-only" => options.new_only = true,
+            "--columns" => {
+                let columns = value()?;
+                options.export.csv.columns = csv::parse_columns(&columns)
+                    .ok_or_else(|| format!("{}: {columns}", i18n("Unknown column")))?;
+            }
+            "--time-format" => {
+                let time_format = value()?;
+                options.export.csv.time_format = csv::TimeFormat::from_id(&time_format)
+                    .ok_or_else(|| format!("{}: {time_format}", i18n("Unknown time format")))?;
+            }
+            "--delimiter" => {
+                let delimiter = value()?;
+                options.export.csv.delimiter = csv::parse_delimiter(&delimiter)
+                    .ok_or_else(|| format!("{}: {delimiter}", i18n("Invalid delimiter")))?;
+            }
+            "--pilot" => options.export.igc.pilot = Some(value()?),
+            "--copilot" => options.export.igc.copilot = Some(value()?),
+            "--glider-type" => options.export.igc.glider_type = Some(value()?),
+            "--glider-id" => options.export.igc.glider_id = Some(value()?),
+            "--competition-id" => options.export.igc.competition_id = Some(value()?),
+            "--competition-class" => options.export.igc.competition_class = Some(value()?),
+            "-e" | "--erase" => options.erase = true,
+            "--power-off" => options.shutoff = true,
+            "--timeout" => options.timeout = Some(seconds(value()?)?),
+            "--inactivity" => options.inactivity = Some(seconds(value()?)?),
+            "--gpsbabel" => options.gpsbabel = Some(PathBuf::from(value()?)),
+            _ => return Err(format!("{}: {arg}", i18n("Unknown option"))),
+        }
+    }
+    Ok(options)
+}
+
+/// Parse the command line arguments, without the program name.
+fn parse(args: &[String]) -> Result<Command, String> {
+    let command = args.first().map(String::as_str).unwrap_or("help");
+    let options = parse_options(args.get(1..).unwrap_or_default())?;
+    match command {
+        "download" => {
+            if options.output.is_none() {
+                return Err(i18n("Missing output file."));
+            }
+            Ok(Command::Download(options))
+        }
+        "convert" => {
+            if options.input.is_none() {
+                return Err(i18n("Missing input file."));
+            }
+            if options.output.is_none() {
+                return Err(i18n("Missing output file."));
+            }
+            Ok(Command::Convert(options))
+        }
+        "erase" => Ok(Command::Erase(options)),
+        "log-enable" => Ok(Command::SetLogging(options, true)),
+        "log-disable" => Ok(Command::SetLogging(options, false)),
+        "log-status" => Ok(Command::LoggingStatus(options)),
+        "power-off" => Ok(Command::Shutoff(options)),
+        "list-devices" => Ok(Command::ListDevices),
+        "list-ports" => Ok(Command::ListPorts(options)),
+        "help" | "--help" | "-h" => Ok(Command::Help),
+        _ => Err(format!("{}: {command}", i18n("Unknown command"))),
+    }
+}
+
+/// Setup the manager with the model and port from `options`.
+/// If no port is specified and there is only one for the model, use it.
+fn setup_manager(manager: &mut devices::Manager, options: &Options) -> drivers::Result<()> {
+    let model = options.model.as_ref().ok_or_else(|| {
+        eprintln!("{}", i18n("Missing model."));
+        drivers::Error::WrongArg
+    })?;
+    if manager.device_capability(model).is_none() {
+        eprintln!("{}: {model}", i18n("Unknown model"));
+        return Err(drivers::Error::WrongArg);
+    }
+    manager.set_model(model);
+
+    let port = match options.port {
+        Some(ref port) => port.clone(),
+        None => {
+            let ports = manager.get_ports_for_model(model).unwrap_or_default();
+            if ports.len() != 1 {
+                eprintln!(
+                    "{}",
+                    i18n("Can't determine the port. Specify it with --port.")
+                );
+                return Err(drivers::Error::WrongArg);
+            }
+            ports[0].path.to_string_lossy().into_owned()
+        }
+    };
+    manager.set_port(&port);
+
+    let mut timeouts = drivers::Timeouts::default();
+    if let Some(timeout) = options.timeout {
+        timeouts.download = timeout;
+        timeouts.erase = timeout;
+        timeouts.command = timeout;
+    }
+    if let Some(inactivity) = options.inactivity {
+        timeouts.inactivity = inactivity;
+    }
+    manager.set_timeouts(timeouts);
+    if let Some(ref gpsbabel) = options.gpsbabel {
+        manager.set_gpsbabel(gpsbabel);
+    }
+
+    Ok(())
+}
+
+fn get_device(
+    manager: &mut devices::Manager,
+    options: &Options,
+) -> drivers::Result<drivers::DriverRef> {
+    setup_manager(manager, options)?;
+    let device = manager.get_device()?;
+    if !device.open() {
+        return Err(drivers::Error::Failed(i18n("Open failed.")));
+    }
+    Ok(device)
+}
+
+/// Print the progress on stderr, if it is a terminal.
+fn print_progress(progress: drivers::Progress) {
+    let stderr = io::stderr();
+    if !stderr.is_terminal() {
+        return;
+    }
+    match progress.fraction() {
+        Some(fraction) => eprint!("\r{:3.0}%", fraction * 100.0),
+        None => eprint!("\r{}", progress.done),
+    }
+}
+
+/// Write `data` to the output file, in the formats from the options
+/// or the extension, split if requested. Return the files written.
+fn write_output(
+    options: &Options,
+    export: &export::Options,
+    data: &GpsData,
+) -> drivers::Result<Vec<PathBuf>> {
+    let output = options.output.as_ref().ok_or(drivers::Error::WrongArg)?;
+    let formats = if options.formats.is_empty() {
+        vec![Format::from_path(output).unwrap_or(export::FORMATS[0])]
+    } else {
+        options.formats.clone()
+    };
+    let pieces = match options.split {
+        Some(mode) => split::split(data, mode),
+        None => vec![data.clone()],
+    };
+    export::write_pieces(output, &formats, export, &pieces)
+}
+
+/// Print `message` followed by the files.
+fn print_files(message: &str, files: &[PathBuf]) {
+    let files: Vec<String> = files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect();
+    println!("{message} {}", files.join(", "));
+}
+
+fn download(manager: &mut devices::Manager, options: &Options) -> drivers::Result<()> {
+    let output = options.output.as_ref().ok_or(drivers::Error::WrongArg)?;
+    // fail before the device is erased.
+    export::check_writable(output)?;
+    let device = get_device(manager, options)?;
+    let store = glib::KeyFile::new();
+    let device_key = if options.new_only {
+        // the settings may not exist yet.
+        let _ = store.load_from_file(settings::file(), glib::KeyFileFlags::NONE);
+        let key = manager.device_key();
+        if key.is_none() {
+            eprintln!(
+                "{}",
+                i18n("The port has no serial number to tell the device from the others of its model: all the points are kept.")
+            );
+        }
+        key
+    } else {
+        None
+    };
+    let since = options.since.or_else(|| {
+        device_key
+            .as_ref()
+            .and_then(|key| settings::last_point(&store, key))
+    });
+    let cap = manager.capability().unwrap_or_default();
+    let mut export = options.export.clone();
+    if let Some(desc) = manager.device_desc() {
+        export.igc.device_id = Some(desc.id.clone());
+        export.igc.device_label = Some(desc.label.clone());
+    }
+    let result = export::download_and_write(
+        &*device,
+        &cap,
+        &export::DownloadOptions::new(options.erase, options.shutoff),
+        &print_progress,
+        &drivers::CancelToken::default(),
+        |data| {
+            log::debug!("downloaded {} points", data.points().count());
+            let files = match since {
+                Some(since) => {
+                    let mut data = data.clone();
+                    data.retain_after(since);
+                    if data.is_empty() {
+                        None
+                    } else {
+                        Some(write_output(options, &export, &data)?)
+                    }
+                }
+                None => Some(write_output(options, &export, data)?),
+            };
+            Ok((files, data.last_time()))
+        },
+    );
+    if io::stderr().is_terminal() {
+        eprintln!();
+    }
+    device.close();
+    match result? {
+        (None, _) => println!("{}", i18n("No new points to download.")),
+        (Some(files), last) => {
+            print_files(&i18n("Download finished:"), &files);
+            if let Some(last) = last {
+                println!("{} {}", i18n("Last point:"), last.to_rfc3339());
+                if let Some(key) = device_key {
+                    record_last_point(&store, &key, last);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Record `last` as the last point downloaded from the device `key`
+/// in the settings.
+fn record_last_point(store: &glib::KeyFile, key: &str, last: DateTime<Utc>) {
+    settings::set_last_point(store, key, last);
+    let saved = std::fs::create_dir_all(settings::dir())
+        .map_err(|err| err.to_string())
+        .and_then(|_| {
+            store
+                .save_to_file(settings::file())
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = saved {
+        eprintln!("{}: {err}", i18n("Can't record the last point"));
+    }
+}
+
+/// Read the input file and write it to the output file. Corrupted NMEA
+/// sentences are reported and skipped.
+fn convert(options: &Options) -> drivers::Result<()> {
+    let input = options.input.as_ref().ok_or(drivers::Error::WrongArg)?;
+    let file = io::BufReader::new(std::fs::File::open(input)?);
+    let data = match Format::from_path(input) {
+        Some(Format::Gpx) => gpx::read(file)?,
+        Some(Format::Nmea) => {
+            let (data, corrupted) = nmea::read(file)?;
+            for sentence in &corrupted {
+                eprintln!(
+                    "{}:{}: {}: {}",
+                    input.display(),
+                    sentence.line,
+                    i18n("Corrupted sentence"),
+                    sentence.sentence
+                );
+            }
+            data
+        }
+        _ => {
+            eprintln!("{}: {}", i18n("Can't read"), input.display());
+            return Err(drivers::Error::WrongArg);
+        }
+    };
+    let files = write_output(options, &options.export, &data)?;
+    print_files(&i18n("Conversion finished:"), &files);
+    Ok(())
+}
+
+fn erase(manager: &mut devices::Manager, options: &Options) -> drivers::Result<()> {
+    let device = get_device(manager, options)?;
+    device.erase(&drivers::CancelToken::default())?;
+    device.close();
+    println!("{}", i18n("Erase finished."));
+    Ok(())
+}
+
+fn set_logging(
+    manager: &mut devices::Manager,
+    options: &Options,
+    enable: bool,
+) -> drivers::Result<()> {
+    let device = get_device(manager, options)?;
+    device.set_logging(enable)?;
+    device.close();
+    if enable {
+        println!("{}", i18n("Logging enabled."));
+    } else {
+        println!("{}", i18n("Logging disabled."));
+    }
+    Ok(())
+}
+
+fn logging_status(manager: &mut devices::Manager, options: &Options) -> drivers::Result<()> {
+    let device = get_device(manager, options)?;
+    let enabled = device.logging(&drivers::CancelToken::default())?;
+    device.close();
+    println!("{}", if enabled { "on" } else { "off" });
+    Ok(())
+}
+
+fn shutoff(manager: &mut devices::Manager, options: &Options) -> drivers::Result<()> {
+    let device = get_device(manager, options)?;
+    device.shutoff()?;
+    device.close();
+    println!("{}", i18n("Device powered off."));
+    Ok(())
+}
+
+fn list_devices(manager: &devices::Manager) {
+    for device in manager.devices_desc() {
+        println!("{}\t{}", device.id, device.label);
+    }
+}
+
+fn list_ports(manager: &devices::Manager, options: &Options) -> drivers::Result<()> {
+    let ports = match options.model {
+        Some(ref model) => {
+            if manager.device_capability(model).is_none() {
+                eprintln!("{}: {model}", i18n("Unknown model"));
+                return Err(drivers::Error::WrongArg);
+            }
+            manager.get_ports_for_model(model).unwrap_or_default()
+        }
+        None => manager.all_ports(),
+    };
+    for port in ports {
+        println!("{}\t{}", port.path.display(), port.label);
+    }
+    Ok(())
+}
+
+/// Tell why the device `command` failed if gpsbabel can't drive the
+/// model, otherwise return `err`. gpsbabel is only checked then, as
+/// it is run for it.
+fn explain_failure(
+    manager: &devices::Manager,
+    command: &Command,
+    err: drivers::Error,
+) -> drivers::Error {
+    let options = match *command {
+        Command::Download(ref options)
+        | Command::Erase(ref options)
+        | Command::SetLogging(ref options, _)
+        | Command::LoggingStatus(ref options)
+        | Command::Shutoff(ref options) => options,
+        _ => return err,
+    };
+    match (err, options.model.as_deref()) {
+        (err @ drivers::Error::NoDriver(_), Some(model))
+        | (err @ drivers::Error::Failed(_), Some(model)) => {
+            manager.check_gpsbabel(Some(model)).err().unwrap_or(err)
+        }
+        (err, _) => err,
+    }
+}
+
+/// Run the command line. `args` doesn't include the program name.
+/// Return the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let command = match parse(args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("{message}");
+            eprintln!("{}", usage());
+            return EXIT_USAGE;
+        }
+    };
+
+    let mut manager = devices::Manager::new();
+    for err in manager.database_errors() {
+        eprintln!("{}: {err}", i18n("Error loading the device database"));
+    }
+    let result = match command {
+        Command::Download(ref options) => download(&mut manager, options),
+        Command::Convert(ref options) => convert(options),
+        Command::Erase(ref options) => erase(&mut manager, options),
+        Command::SetLogging(ref options, enable) => set_logging(&mut manager, options, enable),
+        Command::LoggingStatus(ref options) => logging_status(&mut manager, options),
+        Command::Shutoff(ref options) => shutoff(&mut manager, options),
+        Command::ListDevices => {
+            list_devices(&manager);
+            Ok(())
+        }
+        Command::ListPorts(ref options) => list_ports(&manager, options),
+        Command::Help => {
+            println!("{}", usage());
+            Ok(())
+        }
+    };
+    match result.map_err(|err| explain_failure(&manager, &command, err)) {
+        Ok(_) => EXIT_OK,
+        Err(err) => {
+            eprintln!("{}: {err}", i18n("Error"));
+            exit_code(&err)
+        }
+    }
+}
+
+#[cfg(test)]
+fn to_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn test_parse() {
+    let command = parse(&to_args(&[
+        "download",
+        "--model",
+        "m241",
+        "-p",
+        "/dev/ttyACM0",
+        "--output=track.gpx",
+        "--erase",
+    ]));
+    assert_eq!(
+        command,
+        Ok(Command::Download(Options {
+            model: Some("m241".to_string()),
+            port: Some("/dev/ttyACM0".to_string()),
+            input: None,
+            output: Some(PathBuf::from("track.gpx")),
+            formats: vec![],
+            split: None,
+            since: None,
+            new_only: false,
+            erase: true,
+            shutoff: false,
+            timeout: None,
+            inactivity: None,
+            gpsbabel: None,
+            export: export::Options::default(),
+        }))
+    );
+    assert_eq!(
+        parse(&to_args(&["erase", "-m", "mtk", "--timeout=120"])),
+        Ok(Command::Erase(Options {
+            model: Some("mtk".to_string()),
+            timeout: Some(Duration::from_secs(120)),
+            ..Options::default()
+        }))
+    );
+    assert_eq!(
+        parse(&to_args(&[
+            "erase",
+            "-m",
+            "m241",
+            "--gpsbabel",
+            "/opt/bin/gpsbabel"
+        ])),
+        Ok(Command::Erase(Options {
+            model: Some("m241".to_string()),
+            gpsbabel: Some(PathBuf::from("/opt/bin/gpsbabel")),
+            ..Options::default()
+        }))
+    );
+    assert!(parse(&to_args(&["erase", "--inactivity", "soon"])).is_err());
+    assert_eq!(
+        parse(&to_args(&["download", "-o", "track", "-f", "kml"])),
+        Ok(Command::Download(Options {
+            output: Some(PathBuf::from("track")),
+            formats: vec![Format::Kml],
+            ..Options::default()
+        }))
+    );
+    assert!(parse(&to_args(&["download", "-o", "track", "-f", "shp"])).is_err());
+    assert_eq!(
+        parse(&to_args(&[
+            "download",
+            "-o",
+            "track",
+            "--format=gpx,kml,geojson"
+        ])),
+        Ok(Command::Download(Options {
+            output: Some(PathBuf::from("track")),
+            formats: vec![Format::Gpx, Format::Kml, Format::GeoJson],
+            ..Options::default()
+        }))
+    );
+    assert!(parse(&to_args(&["download", "-o", "track", "-f", "gpx,shp"])).is_err());
+    assert_eq!(
+        parse(&to_args(&["download", "-o", "track", "--split", "day:utc"])),
+        Ok(Command::Download(Options {
+            output: Some(PathBuf::from("track")),
+            split: Some(Split::Day(split::Zone::Utc)),
+            ..Options::default()
+        }))
+    );
+    assert!(parse(&to_args(&["download", "-o", "track", "--split", "week"])).is_err());
+    assert_eq!(
+        parse(&to_args(&[
+            "download",
+            "-o",
+            "track",
+            "--since",
+            "2024-05-04T14:00:00+02:00"
+        ])),
+        Ok(Command::Download(Options {
+            output: Some(PathBuf::from("track")),
+            since: DateTime::from_timestamp(1_714_824_000, 0),
+            ..Options::default()
+        }))
+    );
+    assert!(parse(&to_args(&[
+        "download",
+        "-o",
+        "track",
+        "--since",
+        "yesterday"
+    ]))
+    .is_err());
+    assert_eq!(
+        parse(&to_args(&["download", "-o", "track", "--new-only"])),
+        Ok(Command::Download(Options {
+            output: Some(PathBuf::from("track")),
+            new_only: true,
+            ..Options::default()
+        }))
+    );
+    assert_eq!(
+        parse(&to_args(&[
+            "download",
+            "-o",
+            "track.csv",
+            "--columns=time,lat,lon",
+            "--time-format",
+            "epoch",
+            "--delimiter",
+            "tab",
+        ])),
+        Ok(Command::Download(Options {
+            output: Some(PathBuf::from("track.csv")),
+            export: export::Options {
+                csv: csv::Options {
+                    columns: vec![
+                        csv::Column::Time,
+                        csv::Column::Latitude,
+                        csv::Column::Longitude
+                    ],
+                    time_format: csv::TimeFormat::Epoch,
+                    delimiter: '\t',
+                },
+                ..export::Options::default()
+            },
+            ..Options::default()
+        }))
+    );
+    assert!(parse(&to_args(&["download", "-o", "t", "--columns", "lat,alt"])).is_err());
+
+    assert_eq!(
+        parse(&to_args(&["convert", "-i", "log.nmea", "-o", "track.gpx"])),
+        Ok(Command::Convert(Options {
+            input: Some(PathBuf::from("log.nmea")),
+            output: Some(PathBuf::from("track.gpx")),
+            ..Options::default()
+        }))
+    );
+    assert!(parse(&to_args(&["convert", "-o", "track.gpx"])).is_err());
+    assert_eq!(
+        parse(&to_args(&[
+            "download",
+            "-o",
+            "flight.igc",
+            "--pilot",
+            "Jane Doe",
+            "--glider-id=C-GABC",
+        ])),
+        Ok(Command::Download(Options {
+            output: Some(PathBuf::from("flight.igc")),
+            export: export::Options {
+                igc: crate::igc::Options {
+                    pilot: Some("Jane Doe".to_string()),
+                    glider_id: Some("C-GABC".to_string()),
+                    ..crate::igc::Options::default()
+                },
+                ..export::Options::default()
+            },
+            ..Options::default()
+        }))
+    );
+
+    assert!(parse(&to_args(&["download", "--model", "m241"])).is_err());
+    assert!(parse(&to_args(&["erase", "--model"])).is_err());
+    assert!(parse(&to_args(&["erase", "--bogus"])).is_err());
+    assert!(parse(&to_args(&["bogus"])).is_err());
+    assert_eq!(parse(&to_args(&[])), Ok(Command::Help));
+    assert_eq!(parse(&to_args(&["list-devices"])), Ok(Command::ListDevices));
+    assert_eq!(
+        parse(&to_args(&["log-disable", "-m", "mtk"])),
+        Ok(Command::SetLogging(
+            Options {
+                model: Some("mtk".to_string()),
+                ..Options::default()
+            },
+            false
+        ))
+    );
+}
+
+#[test]
+fn test_exit_code() {
+    assert_eq!(exit_code(&drivers::Error::WrongArg), EXIT_USAGE);
+    assert_eq!(exit_code(&drivers::Error::NoDriver(String::new())), 4);
+    assert_eq!(
+        exit_code(&drivers::Error::Io(io::Error::from(
+            io::ErrorKind::NotFound
+        ))),
+        7
+    );
+}
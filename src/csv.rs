@@ -0,0 +1,270 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::Write;
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+
+use crate::track::{GpsData, TrackPoint};
+
+/// A column of the CSV output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Column {
+    Time,
+    Latitude,
+    Longitude,
+    Elevation,
+    Speed,
+    Course,
+    Hdop,
+    Satellites,
+    /// The index of the segment, starting at 1.
+    Segment,
+}
+
+/// All the columns, in the default order.
+pub const COLUMNS: &[Column] = &[
+    Column::Time,
+    Column::Latitude,
+    Column::Longitude,
+    Column::Elevation,
+    Column::Speed,
+    Column::Course,
+    Column::Hdop,
+    Column::Satellites,
+    Column::Segment,
+];
+
+impl Column {
+    /// The id, as used on the command line and in the header.
+    pub fn id(self) -> &'static str {
+        match self {
+            Column::Time => "time",
+            Column::Latitude => "lat",
+            Column::Longitude => "lon",
+            Column::Elevation => "ele",
+            Column::Speed => "speed",
+            Column::Course => "course",
+            Column::Hdop => "hdop",
+            Column::Satellites => "sat",
+            Column::Segment => "segment",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Column> {
+        COLUMNS.iter().copied().find(|c| c.id() == id)
+    }
+}
+
+/// Parse a comma separated list of column ids.
+pub fn parse_columns(ids: &str) -> Option<Vec<Column>> {
+    ids.split(',')
+        .map(|id| Column::from_id(id.trim()))
+        .collect()
+}
+
+/// How to write the time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimeFormat {
+    /// ISO 8601 in UTC.
+    #[default]
+    Utc,
+    /// ISO 8601 in the local timezone.
+    Local,
+    /// Seconds since the Unix epoch.
+    Epoch,
+}
+
+/// All the time formats.
+pub const TIME_FORMATS: &[TimeFormat] = &[TimeFormat::Utc, TimeFormat::Local, TimeFormat::Epoch];
+
+impl TimeFormat {
+    pub fn id(self) -> &'static str {
+        match self {
+            TimeFormat::Utc => "utc",
+            TimeFormat::Local => "local",
+            TimeFormat::Epoch => "epoch",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<TimeFormat> {
+        TIME_FORMATS.iter().copied().find(|t| t.id() == id)
+    }
+
+    fn format(self, time: DateTime<Utc>) -> String {
+        match self {
+            TimeFormat::Utc => time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            TimeFormat::Local => time
+                .with_timezone(&Local)
+                .to_rfc3339_opts(SecondsFormat::AutoSi, false),
+            TimeFormat::Epoch => time.timestamp().to_string(),
+        }
+    }
+}
+
+/// The CSV output options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub columns: Vec<Column>,
+    pub time_format: TimeFormat,
+    pub delimiter: char,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            columns: COLUMNS.to_vec(),
+            time_format: TimeFormat::default(),
+            delimiter: ',',
+        }
+    }
+}
+
+/// Parse a delimiter: a single character, or "tab".
+pub fn parse_delimiter(delimiter: &str) -> Option<char> {
+    if delimiter == "tab" || delimiter == "\\t" {
+        return Some('\t');
+    }
+    let mut chars = delimiter.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c != '"' && c != '\n' && c != '\r' => Some(c),
+        _ => None,
+    }
+}
+
+/// Quote `field` if it contains the delimiter or a quote.
+fn quote(field: String, delimiter: char) -> String {
+    if field.contains(delimiter) || field.contains('"') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn field(point: &TrackPoint, segment: usize, column: Column, options: &Options) -> String {
+    let value = match column {
+        Column::Time => point.time.map(|time| options.time_format.format(time)),
+        Column::Latitude => Some(format!("{:.9}", point.lat)),
+        Column::Longitude => Some(format!("{:.9}", point.lon)),
+        Column::Elevation => point.altitude().map(|ele| format!("{ele:.3}")),
+        Column::Speed => point.speed.map(|speed| format!("{speed:.3}")),
+        Column::Course => point.course.map(|course| format!("{course:.2}")),
+        Column::Hdop => point.hdop.map(|hdop| format!("{hdop:.2}")),
+        Column::Satellites => point.satellites.map(|sat| sat.to_string()),
+        Column::Segment => Some(segment.to_string()),
+    };
+    quote(value.unwrap_or_default(), options.delimiter)
+}
+
+/// Write the track points of `data` as CSV, one line per point with
+/// a header line. Missing values are empty. Waypoints are ignored.
+pub fn write<W: Write>(out: &mut W, data: &GpsData, options: &Options) -> io::Result<()> {
+    let delimiter = options.delimiter.to_string();
+    let header: Vec<String> = options
+        .columns
+        .iter()
+        .map(|column| quote(column.id().to_string(), options.delimiter))
+        .collect();
+    writeln!(out, "{}", header.join(&delimiter))?;
+    let segments = data.tracks.iter().flat_map(|track| track.segments.iter());
+    for (index, segment) in segments.enumerate() {
+        for point in &segment.points {
+            let fields: Vec<String> = options
+                .columns
+                .iter()
+                .map(|&column| field(point, index + 1, column, options))
+                .collect();
+            writeln!(out, "{}", fields.join(&delimiter))?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_options() {
+    assert_eq!(
+        parse_columns("time, lat,lon"),
+        Some(vec![Column::Time, Column::Latitude, Column::Longitude])
+    );
+    assert_eq!(parse_columns("time,bogus"), None);
+    assert_eq!(TimeFormat::from_id("epoch"), Some(TimeFormat::Epoch));
+    assert_eq!(parse_delimiter(";"), Some(';'));
+    assert_eq!(parse_delimiter("tab"), Some('\t'));
+    assert_eq!(parse_delimiter(",,"), None);
+    assert_eq!(parse_delimiter("\""), None);
+}
+
+#[test]
+fn test_write() {
+    use crate::track::{test_data, test_point};
+
+    let point = TrackPoint {
+        speed: Some(1.25),
+        course: Some(90.0),
+        hdop: Some(1.1),
+        satellites: Some(7),
+        ..test_point(0)
+    };
+    // nothing but a position.
+    let data = test_data(vec![vec![point], vec![TrackPoint::default()]]);
+
+    let mut output = vec![];
+    write(&mut output, &data, &Options::default()).expect("Failed to write CSV");
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "time,lat,lon,ele,speed,course,hdop,sat,segment
+2024-05-04T12:00:00Z,45.500000000,-73.500000000,30.500,1.250,90.00,1.10,7,1
+,0.000000000,0.000000000,,,,,,2
+"
+    );
+
+    let options = Options {
+        columns: vec![Column::Segment, Column::Time, Column::Elevation],
+        time_format: TimeFormat::Epoch,
+        delimiter: '\t',
+    };
+    let mut output = vec![];
+    write(&mut output, &data, &options).expect("Failed to write CSV");
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "segment\ttime\tele\n1\t1714824000\t30.500\n2\t\t\n"
+    );
+
+    // The delimiter is the decimal separator: quote.
+    let options = Options {
+        columns: vec![Column::Elevation],
+        delimiter: '.',
+        ..Options::default()
+    };
+    let mut output = vec![];
+    write(&mut output, &data, &options).expect("Failed to write CSV");
+    assert_eq!(String::from_utf8(output).unwrap(), "ele\n\"30.500\"\n\n");
+
+    // From a barometric device.
+    let data = test_data(vec![vec![TrackPoint {
+        elevation: None,
+        pressure_altitude: Some(1230.4),
+        ..test_point(0)
+    }]]);
+    let options = Options {
+        columns: vec![Column::Elevation],
+        ..Options::default()
+    };
+    let mut output = vec![];
+    write(&mut output, &data, &options).expect("Failed to write CSV");
+    assert_eq!(String::from_utf8(output).unwrap(), "ele\n1230.400\n");
+}
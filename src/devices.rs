@@ -11,21 +11,73 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::sync::Arc;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use gettextrs::gettext as i18n;
+use gudev::prelude::DeviceExt;
 use serde::Deserialize;
+use thiserror::Error;
 
+use crate::config;
 use crate::drivers;
 use crate::gpsbabel;
 
 /// Device static capability
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct Capability {
     pub can_erase: bool,
     pub can_erase_only: bool,
-    can_log_enable: bool,
-    can_shutoff: bool,
+    pub can_log_enable: bool,
+    pub can_shutoff: bool,
+    /// The altitude is from a barometric sensor.
+    #[serde(default)]
+    pub barometric: bool,
+}
+
+/// A USB vendor and product id, written `0e8d:3329` in hexadecimal
+/// like lsusb does.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UsbId {
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl UsbId {
+    /// From the vendor and product ids in hexadecimal, as udev has
+    /// them in `ID_VENDOR_ID` and `ID_MODEL_ID`.
+    pub fn from_hex(vendor: &str, product: &str) -> Option<UsbId> {
+        Some(UsbId {
+            vendor: u16::from_str_radix(vendor, 16).ok()?,
+            product: u16::from_str_radix(product, 16).ok()?,
+        })
+    }
+}
+
+impl std::str::FromStr for UsbId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_once(':')
+            .and_then(|(vendor, product)| UsbId::from_hex(vendor, product))
+            .ok_or_else(|| format!("invalid USB id \"{s}\", expected VENDOR:PRODUCT"))
+    }
+}
+
+impl TryFrom<String> for UsbId {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for UsbId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor, self.product)
+    }
 }
 
 /// Describe a device
@@ -35,54 +87,308 @@ pub struct Desc {
     pub label: String,
     cap: Capability,
     driver: String,
+    /// The USB ids to recognize the device when it is plugged.
+    #[serde(default)]
+    pub usb_ids: Vec<UsbId>,
+}
+
+/// The generic USB serial bridges: Prolific PL2303, Silicon Labs
+/// CP210x and FTDI FT232. Any cable can have them.
+const USB_BRIDGES: &[UsbId] = &[
+    UsbId {
+        vendor: 0x067b,
+        product: 0x2303,
+    },
+    UsbId {
+        vendor: 0x10c4,
+        product: 0xea60,
+    },
+    UsbId {
+        vendor: 0x0403,
+        product: 0x6001,
+    },
+];
+
+/// The devices with `usb_id`. `model` if it is one of them. A generic
+/// USB serial bridge only confirms `model`. If they all have the same
+/// driver, the first one: it makes no difference to download. Otherwise
+/// all of them: it is ambiguous.
+fn detect_model<'a>(devices: &'a [Desc], usb_id: UsbId, model: Option<&str>) -> Vec<&'a Desc> {
+    let mut candidates: Vec<&Desc> = devices
+        .iter()
+        .filter(|&device| device.usb_ids.contains(&usb_id))
+        .collect();
+    if let Some(&device) = candidates
+        .iter()
+        .find(|&&device| Some(device.id.as_str()) == model)
+    {
+        return vec![device];
+    }
+    if USB_BRIDGES.contains(&usb_id) {
+        return vec![];
+    }
+    if candidates
+        .windows(2)
+        .all(|pair| pair[0].driver == pair[1].driver)
+    {
+        candidates.truncate(1);
+    }
+    candidates
+}
+
+/// Name of the device database file.
+const DATABASE_FILE: &str = "devices.json";
+
+/// Error loading a device database file.
+#[derive(Error, Debug)]
+#[error("{}:{line}:{column}: {message}", .path.display())]
+pub struct DatabaseError {
+    pub path: PathBuf,
+    /// Line of the error, starting at 1. 0 if not applicable.
+    pub line: usize,
+    /// Column of the error, starting at 1. 0 if not applicable.
+    pub column: usize,
+    pub message: String,
 }
 
 /// The device database.
-#[derive(Clone, Debug, Deserialize)]
-struct DeviceDb {
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct DeviceDb {
+    #[serde(default)]
     devices: Vec<Desc>,
+    #[serde(default)]
     drivers: Vec<drivers::Desc>,
 }
 
+impl DeviceDb {
+    /// The database built in the executable.
+    #[cfg(test)]
+    pub(crate) fn builtin() -> DeviceDb {
+        let (db, errors) = Self::load_all(&[]);
+        assert!(errors.is_empty(), "{:?}", errors);
+        db
+    }
+
+    /// Parse the database `content`, `path` being used for error reporting.
+    fn parse(path: &Path, content: &str) -> Result<DeviceDb, DatabaseError> {
+        serde_json::from_str(content).map_err(|err| DatabaseError {
+            path: path.to_path_buf(),
+            line: err.line(),
+            column: err.column(),
+            message: err.to_string(),
+        })
+    }
+
+    /// Load the database file at `path`. Return None if it doesn't exist.
+    fn load(path: &Path) -> Option<Result<DeviceDb, DatabaseError>> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Some(Self::parse(path, &content)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => Some(Err(DatabaseError {
+                path: path.to_path_buf(),
+                line: 0,
+                column: 0,
+                message: err.to_string(),
+            })),
+        }
+    }
+
+    /// Merge `other` into the database. Entries from `other` replace
+    /// the entries with the same id, or are added.
+    fn merge(&mut self, other: DeviceDb) {
+        for device in other.devices {
+            match self.devices.iter_mut().find(|d| d.id == device.id) {
+                Some(d) => *d = device,
+                None => self.devices.push(device),
+            }
+        }
+        for driver in other.drivers {
+            match self.drivers.iter_mut().find(|d| d.id == driver.id) {
+                Some(d) => *d = driver,
+                None => self.drivers.push(driver),
+            }
+        }
+    }
+
+    /// The paths of the database files merged on top of the built-in
+    /// one, in order: system then user.
+    fn paths() -> Vec<PathBuf> {
+        let mut paths = vec![Path::new(config::PKGDATADIR).join(DATABASE_FILE)];
+        if let Some(mut path) = dirs::config_dir() {
+            path.push("gpsami");
+            path.push(DATABASE_FILE);
+            paths.push(path);
+        }
+        paths
+    }
+
+    /// Load the built-in database and merge the files from `paths`.
+    /// Errors are returned per file, and the file skipped.
+    fn load_all(paths: &[PathBuf]) -> (DeviceDb, Vec<DatabaseError>) {
+        let mut errors = vec![];
+        let mut db = Self::parse(Path::new(DATABASE_FILE), include_str!("devices.json"))
+            .unwrap_or_else(|err| {
+                errors.push(err);
+                DeviceDb::default()
+            });
+        for path in paths {
+            match Self::load(path) {
+                Some(Ok(other)) => {
+                    log::debug!("loaded device database {path:?}");
+                    db.merge(other);
+                }
+                Some(Err(err)) => {
+                    log::error!("error loading device database {err}");
+                    errors.push(err);
+                }
+                None => {}
+            }
+        }
+        (db, errors)
+    }
+}
+
+/// Check that gpsbabel can drive devices: that it runs, isn't older
+/// than `gpsbabel::MIN_VERSION` and has their format. It runs gpsbabel,
+/// so it can be moved to a thread.
+pub struct GpsBabelCheck {
+    program: PathBuf,
+    /// How long each run of gpsbabel may take.
+    timeout: Duration,
+    /// The device ids and their gpsbabel format.
+    types: Vec<(String, String)>,
+}
+
+impl GpsBabelCheck {
+    /// Run the check. Return `Error::GpsBabel` with the devices gpsbabel
+    /// can't drive.
+    pub fn run(self) -> drivers::Result<()> {
+        if self.types.is_empty() {
+            return Ok(());
+        }
+        let (reason, types) = match gpsbabel::probe(&self.program, self.timeout) {
+            Err(drivers::Error::NoDriver(reason)) | Err(drivers::Error::Failed(reason)) => {
+                (reason.trim().to_string(), self.types)
+            }
+            Err(err) => (err.to_string(), self.types),
+            Ok(probe) if probe.is_outdated() => (
+                format!(
+                    "{} ({} < {})",
+                    i18n("gpsbabel is too old"),
+                    probe.version.unwrap(),
+                    gpsbabel::MIN_VERSION
+                ),
+                self.types,
+            ),
+            Ok(probe) => (
+                i18n("gpsbabel lacks their format"),
+                self.types
+                    .into_iter()
+                    .filter(|(_, type_)| !probe.has_type(type_))
+                    .collect(),
+            ),
+        };
+        if types.is_empty() {
+            return Ok(());
+        }
+        Err(drivers::Error::GpsBabel {
+            reason,
+            devices: types.into_iter().map(|(id, _)| id).collect(),
+        })
+    }
+}
+
+/// The port `id` at `path`, described by the udev `property` values.
+fn new_port(id: String, path: PathBuf, property: impl Fn(&str) -> Option<String>) -> drivers::Port {
+    let usb_id = property("ID_VENDOR_ID")
+        .and_then(|vendor| UsbId::from_hex(&vendor, &property("ID_MODEL_ID")?));
+    drivers::Port {
+        id,
+        label: property("ID_MODEL_FROM_DATABASE").unwrap_or_else(|| i18n("(Unknown)")),
+        path,
+        serial: property("ID_SERIAL_SHORT"),
+        usb_id,
+    }
+}
+
+/// The type of the port `name`, on `bus`, if it is supported.
+fn port_type(name: &str, bus: Option<&str>) -> Option<drivers::PortType> {
+    if bus == Some("usb") {
+        Some(drivers::PortType::UsbSerial)
+    } else if name.starts_with("rfcomm") {
+        Some(drivers::PortType::RfComm)
+    } else {
+        None
+    }
+}
+
+/// The port for the udev `device` from a uevent, and its type, if it
+/// is supported.
+pub fn port_from_udev(device: &gudev::Device) -> Option<(drivers::PortType, drivers::Port)> {
+    let name = device.name()?;
+    let path = PathBuf::from(device.device_file()?.as_str());
+    let property = |key: &str| device.property(key).map(|value| value.to_string());
+    let port_type = port_type(&name, property("ID_BUS").as_deref())?;
+    Some((port_type, new_port(name.to_string(), path, property)))
+}
+
 /// The device manager. Where the magic happens.
 pub struct Manager {
     model: Option<String>,
     port: Option<String>,
     devices: Vec<Desc>,
     drivers: Vec<drivers::Desc>,
+    database_errors: Vec<DatabaseError>,
+    timeouts: drivers::Timeouts,
+    /// The gpsbabel executable.
+    gpsbabel: PathBuf,
 
-    udev_context: libudev::Context,
-    pub gudev_client: gudev::Client, // gudev client. We need to keep it alive.
+    udev_context: Option<libudev::Context>,
+    pub gudev_client: Option<gudev::Client>, // gudev client. We need to keep it alive.
     device_filter: Option<Vec<drivers::PortType>>,
 }
 
 impl Manager {
     pub fn new() -> Self {
-        let devices_db: DeviceDb = serde_json::from_str(include_str!("devices.json")).unwrap();
+        let (devices_db, database_errors) = DeviceDb::load_all(&DeviceDb::paths());
 
-        let client = gudev::Client::new(&["tty"]);
-
-        let context = libudev::Context::new();
-        if context.is_err() {
-            // XXX not sure how do handle the error.
-        }
+        let mut manager = Self::with_database(devices_db);
+        manager.database_errors = database_errors;
+        manager.gudev_client = Some(gudev::Client::new(&["tty"]));
+        // XXX not sure how do handle the error. Without it, no port is listed.
+        manager.udev_context = libudev::Context::new().ok();
+        manager
+    }
 
+    /// Create a manager for the devices of `db`, without udev: it
+    /// doesn't list the ports nor watch them.
+    pub(crate) fn with_database(db: DeviceDb) -> Self {
         Manager {
             model: None,
             port: None,
-            devices: devices_db.devices,
-            drivers: devices_db.drivers,
-            udev_context: context.unwrap(),
-            gudev_client: client,
+            devices: db.devices,
+            drivers: db.drivers,
+            database_errors: vec![],
+            timeouts: drivers::Timeouts::default(),
+            gpsbabel: PathBuf::from(gpsbabel::DEFAULT_PROGRAM),
+            udev_context: None,
+            gudev_client: None,
             device_filter: None,
         }
     }
 
     fn listen_for_devices(&mut self, port_type: Vec<drivers::PortType>) {
-        // XXX set the listener event filtering...
         self.device_filter = Some(port_type);
     }
 
+    /// Return true if the ports of `port_type` are for the current model.
+    pub fn listens_to(&self, port_type: drivers::PortType) -> bool {
+        self.device_filter
+            .as_ref()
+            .map(|filter| filter.contains(&port_type))
+            .unwrap_or(false)
+    }
+
     pub fn set_model(&mut self, model: &str) {
         let port_filter = self.get_port_filter_for_model(model);
         self.model = Some(model.to_owned());
@@ -93,10 +399,64 @@ impl Manager {
         self.port = Some(port.to_owned());
     }
 
+    /// Set the timeouts for the devices returned by `get_device()`.
+    pub fn set_timeouts(&mut self, timeouts: drivers::Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Set the gpsbabel executable, a path or a name looked up in PATH.
+    pub fn set_gpsbabel(&mut self, program: &Path) {
+        self.gpsbabel = program.to_owned();
+    }
+
+    /// The errors that occurred loading the device database files.
+    pub fn database_errors(&self) -> &[DatabaseError] {
+        &self.database_errors
+    }
+
     pub fn devices_desc(&self) -> &Vec<Desc> {
         &self.devices
     }
 
+    /// The description of the current model.
+    pub fn device_desc(&self) -> Option<&Desc> {
+        let model = self.model.as_ref()?;
+        self.devices.iter().find(|&device| &device.id == model)
+    }
+
+    /// A key identifying the current device: the model and the serial
+    /// number of the port. None if the port has no serial number, as
+    /// the units of the model can't be told apart. Only alphanumeric
+    /// characters, `-` and `_` are used.
+    pub fn device_key(&self) -> Option<String> {
+        let model = self.model.as_ref()?;
+        let serial = self.port.as_ref().and_then(|port| {
+            self.all_ports()
+                .into_iter()
+                .find(|p| p.path.as_os_str() == port.as_str())
+                .and_then(|p| p.serial)
+        })?;
+        let key = format!("{model}_{serial}");
+        Some(
+            key.chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// The capability of the current model.
+    pub fn capability(&self) -> Option<Capability> {
+        self.model
+            .as_ref()
+            .and_then(|model| self.device_capability(model))
+    }
+
     pub fn device_capability(&self, model: &str) -> Option<Capability> {
         if model.is_empty() {
             return None;
@@ -111,7 +471,10 @@ impl Manager {
     fn list_ports(&self, port_filters: Vec<drivers::PortType>) -> Vec<drivers::Port> {
         let mut dv: Vec<drivers::Port> = vec![];
         for port_filter in port_filters {
-            let enumerator = libudev::Enumerator::new(&self.udev_context);
+            let enumerator = match &self.udev_context {
+                Some(context) => libudev::Enumerator::new(context),
+                None => return Vec::new(),
+            };
             if enumerator.is_err() {
                 return Vec::new();
             }
@@ -149,13 +512,14 @@ impl Manager {
             let ds = devices.unwrap();
             let mut dv2: Vec<drivers::Port> = ds
                 .map(|dev| {
-                    let path = dev.devnode().unwrap().to_path_buf();
-                    let id = dev.sysname().to_string_lossy().into_owned();
-                    let label = match dev.property_value("ID_MODEL_FROM_DATABASE") {
-                        Some(s) => s.to_string_lossy().into_owned(),
-                        None => i18n("(Unknown)"),
-                    };
-                    drivers::Port { id, label, path }
+                    new_port(
+                        dev.sysname().to_string_lossy().into_owned(),
+                        dev.devnode().unwrap().to_path_buf(),
+                        |key| {
+                            dev.property_value(key)
+                                .map(|s| s.to_string_lossy().into_owned())
+                        },
+                    )
                 })
                 .collect();
             dv.append(&mut dv2);
@@ -164,6 +528,33 @@ impl Manager {
         dv
     }
 
+    /// List the ports of all the supported types.
+    pub fn all_ports(&self) -> Vec<drivers::Port> {
+        self.list_ports(vec![
+            drivers::PortType::UsbSerial,
+            drivers::PortType::RfComm,
+        ])
+    }
+
+    /// The ports of the devices recognized by their USB ids, with the
+    /// model ids, as `detect_model()` picks them: `model` is preferred,
+    /// and there are several if the model is ambiguous.
+    pub fn detect_devices(&self, model: Option<&str>) -> Vec<(drivers::Port, Vec<String>)> {
+        self.all_ports()
+            .into_iter()
+            .filter_map(|port| {
+                let ids: Vec<String> = detect_model(&self.devices, port.usb_id?, model)
+                    .iter()
+                    .map(|device| device.id.clone())
+                    .collect();
+                if ids.is_empty() {
+                    return None;
+                }
+                Some((port, ids))
+            })
+            .collect()
+    }
+
     fn get_port_filter_for_model(&self, model: &str) -> Vec<drivers::PortType> {
         match self.devices.iter().find(|&device| device.id == model) {
             Some(device) => match self
@@ -183,30 +574,76 @@ impl Manager {
         Some(self.list_ports(port_filter))
     }
 
+    /// The check of gpsbabel for the devices using it, or only `model`
+    /// if set. See `GpsBabelCheck`.
+    pub fn gpsbabel_check(&self, model: Option<&str>) -> GpsBabelCheck {
+        let types = self
+            .devices
+            .iter()
+            .filter(|&device| model.map(|model| device.id == model).unwrap_or(true))
+            .filter_map(|device| {
+                let driver = self
+                    .drivers
+                    .iter()
+                    .find(|&driver| driver.id == device.driver)?;
+                match driver.backend {
+                    Some(drivers::Backend::GpsBabel { ref type_, .. }) => {
+                        Some((device.id.clone(), type_.clone()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        GpsBabelCheck {
+            program: self.gpsbabel.clone(),
+            timeout: self.timeouts.command,
+            types,
+        }
+    }
+
+    /// Check gpsbabel for the devices using it, or only `model` if set.
+    pub fn check_gpsbabel(&self, model: Option<&str>) -> drivers::Result<()> {
+        self.gpsbabel_check(model).run()
+    }
+
     // Get a driver for the device from the current manager.
-    pub fn get_device(&self) -> Option<Arc<dyn drivers::Driver + Send + Sync>> {
-        self.model.as_ref()?;
+    pub fn get_device(&self) -> drivers::Result<drivers::DriverRef> {
+        self.get_device_with_timeouts(self.timeouts)
+    }
 
-        let capability: Capability;
-        let driver_id = match self.devices.iter().find(|&device| {
-            if let Some(ref model) = self.model {
-                return &device.id == model;
-            }
-            false
-        }) {
-            Some(device) => {
-                capability = device.cap.clone();
-                device.driver.clone()
-            }
-            None => return None,
-        };
-        match driver_id.as_str() {
-            "baroiq" | "dg-100" | "dg-200" | "navilink" | "m241" | "mtk" => match self.port {
-                Some(ref p) => Some(Arc::new(gpsbabel::GpsBabel::new(driver_id, p, capability))),
-                _ => None,
-            },
-            _ => None,
-        }
+    /// Get a driver for the device with `timeouts` instead of the
+    /// manager's.
+    pub fn get_device_with_timeouts(
+        &self,
+        timeouts: drivers::Timeouts,
+    ) -> drivers::Result<drivers::DriverRef> {
+        let model = self
+            .model
+            .as_ref()
+            .ok_or_else(|| drivers::Error::NoDriver(i18n("No model selected.")))?;
+        let port = self
+            .port
+            .as_ref()
+            .ok_or_else(|| drivers::Error::NoDriver(i18n("No port selected.")))?;
+        let device = self
+            .devices
+            .iter()
+            .find(|&device| &device.id == model)
+            .ok_or_else(|| {
+                drivers::Error::NoDriver(format!("{}: {model}", i18n("Unknown model")))
+            })?;
+        let driver = self
+            .drivers
+            .iter()
+            .find(|&driver| driver.id == device.driver)
+            .ok_or_else(|| {
+                drivers::Error::NoDriver(format!(
+                    "{}: {}",
+                    i18n("Driver not in the device database"),
+                    device.driver
+                ))
+            })?;
+        driver.new_driver(port, device.cap.clone(), timeouts, &self.gpsbabel)
     }
 }
 
@@ -228,3 +665,202 @@ fn test_database() {
     let devices_db: DeviceDb = serde_json::from_str(include_str!("devices.json")).unwrap();
     assert!(!devices_db.devices.is_empty());
 }
+
+#[test]
+fn test_database_files() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let system = dir.path().join("system.json");
+    let user = dir.path().join("user.json");
+    let broken = dir.path().join("broken.json");
+    let missing = dir.path().join("missing.json");
+
+    let mut file = std::fs::File::create(&system).unwrap();
+    file.write_all(
+        br#"{ "drivers": [ { "id": "gt-31", "ports": [ "UsbSerial" ] } ],
+    "devices": [ { "id": "gt-31", "label": "GT-31", "driver": "gt-31",
+      "cap": { "can_erase": false, "can_erase_only": false,
+               "can_log_enable": false, "can_shutoff": false } } ] }"#,
+    )
+    .unwrap();
+    let mut file = std::fs::File::create(&user).unwrap();
+    file.write_all(
+        br#"{ "devices": [ { "id": "m241", "label": "My M-241", "driver": "m241",
+      "cap": { "can_erase": false, "can_erase_only": false,
+               "can_log_enable": false, "can_shutoff": false } } ] }"#,
+    )
+    .unwrap();
+    let mut file = std::fs::File::create(&broken).unwrap();
+    file.write_all(b"{\n  \"devices\": [ }").unwrap();
+
+    let (db, errors) = DeviceDb::load_all(&[system, missing, broken.clone(), user]);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, broken);
+    assert_eq!(errors[0].line, 2);
+    assert_eq!(errors[0].column, 16);
+
+    assert!(db.devices.iter().any(|d| d.id == "gt-31"));
+    assert!(db.drivers.iter().any(|d| d.id == "gt-31"));
+    let m241 = db.devices.iter().find(|d| d.id == "m241").unwrap();
+    assert_eq!(m241.label, "My M-241");
+    assert!(!m241.cap.can_erase);
+    assert_eq!(db.devices.iter().filter(|d| d.id == "m241").count(), 1);
+}
+
+#[test]
+fn test_database_drivers() {
+    // All the devices in the database must have a working driver.
+    let (db, errors) = DeviceDb::load_all(&[]);
+    assert!(errors.is_empty());
+    for device in &db.devices {
+        let driver = db.drivers.iter().find(|d| d.id == device.driver);
+        assert!(driver.is_some(), "No driver for {}", device.id);
+        let driver = driver.unwrap().new_driver(
+            "/dev/ttyS0",
+            device.cap.clone(),
+            drivers::Timeouts::default(),
+            Path::new(gpsbabel::DEFAULT_PROGRAM),
+        );
+        assert!(driver.is_ok(), "No backend for {}", device.id);
+    }
+}
+
+#[test]
+fn test_check_gpsbabel() {
+    use crate::gpsbabel::FakeGpsBabel;
+
+    let mut manager = Manager::with_database(DeviceDb::builtin());
+    let types = "serial\tr-r---\tskytraq\t\tSkyTraq\tskytraq\t\n";
+    let fake = FakeGpsBabel::new("", "", 0, None)
+        .with_output("-V", "GPSBabel Version 1.8.0")
+        .with_output("-^3", types);
+    manager.set_gpsbabel(&fake.program());
+    assert!(manager.check_gpsbabel(Some("skytraq")).is_ok());
+    // native
+    assert!(manager.check_gpsbabel(Some("m241")).is_ok());
+    assert_eq!(fake.invocations().len(), 2);
+    match manager.check_gpsbabel(None) {
+        Err(drivers::Error::GpsBabel { reason, devices }) => {
+            assert_eq!(reason, "gpsbabel lacks their format");
+            assert!(devices.iter().any(|d| d == "baroiq"));
+            assert!(!devices.iter().any(|d| d == "skytraq" || d == "m241"));
+        }
+        result => panic!("Unexpected {:?}", result),
+    }
+
+    let fake = FakeGpsBabel::new("", "", 0, None)
+        .with_output("-V", "GPSBabel Version 1.4.4")
+        .with_output("-^3", types);
+    manager.set_gpsbabel(&fake.program());
+    let err = manager.check_gpsbabel(Some("skytraq")).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "gpsbabel is too old (1.4.4 < 1.5.0): skytraq"
+    );
+
+    manager.set_gpsbabel(Path::new("/nonexistent/gpsbabel"));
+    match manager.check_gpsbabel(None) {
+        Err(drivers::Error::GpsBabel { reason, devices }) => {
+            assert_eq!(reason, "gpsbabel not found: /nonexistent/gpsbabel");
+            assert!(devices.iter().any(|d| d == "skytraq"));
+            assert!(!devices.iter().any(|d| d == "m241"));
+        }
+        result => panic!("Unexpected {:?}", result),
+    }
+}
+
+#[test]
+fn test_usb_id() {
+    let usb_id: UsbId = "0e8d:3329".parse().unwrap();
+    assert_eq!(
+        usb_id,
+        UsbId {
+            vendor: 0x0e8d,
+            product: 0x3329
+        }
+    );
+    assert_eq!(usb_id.to_string(), "0e8d:3329");
+    assert_eq!(UsbId::from_hex("0E8D", "3329"), Some(usb_id));
+    assert!("0e8d".parse::<UsbId>().is_err());
+    assert!("0e8d:xyz".parse::<UsbId>().is_err());
+
+    let (db, _) = DeviceDb::load_all(&[]);
+    let ids = |model| -> Vec<&str> {
+        detect_model(&db.devices, usb_id, model)
+            .iter()
+            .map(|d| d.id.as_str())
+            .collect()
+    };
+    // From a clean state: the Holux have the same driver.
+    assert_eq!(ids(None), vec!["m241"]);
+    assert_eq!(ids(Some("m1200e")), vec!["m1200e"]);
+    // Not a candidate.
+    assert_eq!(ids(Some("mtk")), vec!["m241"]);
+    assert_eq!(ids(Some("dg-100")), vec!["m241"]);
+    assert!(detect_model(&db.devices, "1234:5678".parse().unwrap(), None).is_empty());
+    // A generic USB serial bridge only confirms the model.
+    let bridge = "067b:2303".parse().unwrap();
+    assert!(detect_model(&db.devices, bridge, None).is_empty());
+    assert!(detect_model(&db.devices, bridge, Some("m241")).is_empty());
+    assert_eq!(detect_model(&db.devices, bridge, Some("dg-100")).len(), 1);
+
+    // Ambiguous: the drivers differ.
+    let db = DeviceDb::parse(
+        Path::new("test.json"),
+        r#"{ "devices": [
+      { "id": "a", "label": "A", "driver": "x", "usb_ids": [ "1234:5678" ],
+        "cap": { "can_erase": false, "can_erase_only": false,
+                 "can_log_enable": false, "can_shutoff": false } },
+      { "id": "b", "label": "B", "driver": "y", "usb_ids": [ "1234:5678" ],
+        "cap": { "can_erase": false, "can_erase_only": false,
+                 "can_log_enable": false, "can_shutoff": false } } ] }"#,
+    )
+    .unwrap();
+    let ambiguous = detect_model(&db.devices, "1234:5678".parse().unwrap(), None);
+    assert_eq!(ambiguous.len(), 2);
+
+    let err = DeviceDb::parse(
+        Path::new("test.json"),
+        r#"{ "devices": [ { "id": "x", "label": "X", "driver": "x", "usb_ids": [ "x" ],
+      "cap": { "can_erase": false, "can_erase_only": false,
+               "can_log_enable": false, "can_shutoff": false } } ] }"#,
+    )
+    .unwrap_err();
+    assert!(err.message.contains("invalid USB id"));
+}
+
+#[test]
+fn test_new_port() {
+    use std::collections::HashMap;
+
+    let properties: HashMap<&str, &str> = [
+        ("ID_BUS", "usb"),
+        ("ID_VENDOR_ID", "0e8d"),
+        ("ID_MODEL_ID", "3329"),
+        ("ID_SERIAL_SHORT", "0001"),
+    ]
+    .iter()
+    .copied()
+    .collect();
+    let port = new_port(
+        "ttyACM0".to_string(),
+        PathBuf::from("/dev/ttyACM0"),
+        |key| properties.get(key).map(|value| value.to_string()),
+    );
+    assert_eq!(port.label, "(Unknown)");
+    assert_eq!(port.serial.as_deref(), Some("0001"));
+    assert_eq!(port.usb_id, "0e8d:3329".parse().ok());
+
+    let port = new_port("rfcomm0".to_string(), PathBuf::from("/dev/rfcomm0"), |_| {
+        None
+    });
+    assert!(port.usb_id.is_none());
+
+    assert_eq!(
+        port_type("ttyACM0", Some("usb")),
+        Some(drivers::PortType::UsbSerial)
+    );
+    assert_eq!(port_type("rfcomm0", None), Some(drivers::PortType::RfComm));
+    assert_eq!(port_type("ttyS0", None), None);
+}
@@ -15,61 +15,236 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use gettextrs::gettext as i18n;
 use serde::Deserialize;
-use tempfile::TempDir;
 use thiserror::Error;
 
-use crate::Format;
+use crate::devices::{Capability, UsbId};
+use crate::gpsbabel::GpsBabel;
+use crate::mtk;
+use crate::track::GpsData;
 
 #[derive(Debug)]
 pub struct Port {
     pub id: String,
     pub label: String,
     pub path: PathBuf,
+    /// The serial number of the USB device, if known.
+    pub serial: Option<String>,
+    /// The ids of the USB device, if known.
+    pub usb_id: Option<UsbId>,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
 pub enum PortType {
     None,
     UsbSerial,
     RfComm, // Bluetooth Serial
 }
 
+/// The backend implementing a driver.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Use gpsbabel, with `type` for -i and the type `options`.
+    GpsBabel {
+        #[serde(rename = "type")]
+        type_: String,
+        #[serde(default)]
+        options: Vec<String>,
+    },
+    /// A native driver, by id.
+    Native(String),
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Desc {
     pub id: String,
     // the port to look for.
     pub ports: Vec<PortType>,
+    #[serde(default)]
+    pub backend: Option<Backend>,
+}
+
+/// A driver instance, as returned by `devices::Manager`.
+pub type DriverRef = Arc<dyn Driver + Send + Sync>;
+
+/// Constructor for a native driver, from port, capability and timeouts.
+type NativeConstructor = fn(&str, Capability, Timeouts) -> DriverRef;
+
+/// The native drivers. The id is what `Backend::Native` refers to.
+const NATIVE_DRIVERS: &[(&str, NativeConstructor)] = &[
+    ("mtk", |port, cap, timeouts| {
+        Arc::new(mtk::Mtk::new(port, cap, mtk::Variant::Mtk, timeouts))
+    }),
+    ("m241", |port, cap, timeouts| {
+        Arc::new(mtk::Mtk::new(port, cap, mtk::Variant::Holux, timeouts))
+    }),
+];
+
+impl Desc {
+    /// Create the driver for a device on `port`. The gpsbabel backend
+    /// runs `gpsbabel`.
+    pub fn new_driver(
+        &self,
+        port: &str,
+        capability: Capability,
+        timeouts: Timeouts,
+        gpsbabel: &Path,
+    ) -> Result<DriverRef> {
+        match self.backend {
+            Some(Backend::GpsBabel {
+                ref type_,
+                ref options,
+            }) => Ok(Arc::new(GpsBabel::new(
+                gpsbabel, type_, options, port, capability, timeouts,
+            ))),
+            Some(Backend::Native(ref id)) => NATIVE_DRIVERS
+                .iter()
+                .find(|(native, _)| native == id)
+                .map(|(_, constructor)| constructor(port, capability, timeouts))
+                .ok_or_else(|| {
+                    Error::NoDriver(format!(
+                        "{}: {id}",
+                        i18n("This version doesn't have the native driver")
+                    ))
+                }),
+            None => Err(Error::NoDriver(format!(
+                "{}: {}",
+                i18n("No backend defined for driver"),
+                self.id
+            ))),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Unsupported")]
     Unsupported,
-    #[error("No driver")]
-    NoDriver,
+    #[error("No driver: {0}")]
+    NoDriver(String),
     #[error("Cancelled")]
     Cancelled,
+    #[error("Timeout")]
+    Timeout,
     #[error("Incorrect argument")]
     WrongArg,
     #[error("Failed: {0}")]
     Failed(String),
     #[error("IO error {0}")]
     Io(#[from] io::Error),
+    /// gpsbabel can't drive the `devices`, for `reason`.
+    #[error("{reason}: {}", .devices.join(", "))]
+    GpsBabel {
+        reason: String,
+        devices: Vec<String>,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Progress of an operation: `done` out of `total`, in bytes or
+/// records depending on the driver. `total` is 0 if unknown.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+impl Progress {
+    /// The fraction done, between 0.0 and 1.0, if the total is known.
+    pub fn fraction(&self) -> Option<f64> {
+        if self.total == 0 {
+            None
+        } else {
+            Some((self.done as f64 / self.total as f64).min(1.0))
+        }
+    }
+}
+
+/// Time limits for the operations on the device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    /// Maximum duration of a download.
+    pub download: Duration,
+    /// Maximum duration of an erase.
+    pub erase: Duration,
+    /// Maximum duration of the other commands.
+    pub command: Duration,
+    /// Maximum time the device can stay without answering
+    /// or making progress.
+    pub inactivity: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            download: Duration::from_secs(3600),
+            erase: Duration::from_secs(300),
+            command: Duration::from_secs(60),
+            inactivity: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Token to cancel an operation in progress. Clones share the state.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Request the cancellation.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Return `Error::Cancelled` if the cancellation was requested.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pub trait Driver {
     /// open the device
     fn open(&self) -> bool;
     /// close the device
     fn close(&self) -> bool;
-    /// Download the track in specified format
-    /// Return the PathBuf pointing to the datafile.
-    fn download(&self, format: Format, erase: bool, tempdir: &TempDir) -> Result<PathBuf>;
+    /// Download the tracks and waypoints. Erase and / or power off
+    /// the device after. `progress` is called as the data is read.
+    /// Return `Error::Cancelled` if `cancel` is triggered.
+    fn download(
+        &self,
+        erase: bool,
+        shutoff: bool,
+        progress: &dyn Fn(Progress),
+        cancel: &CancelToken,
+    ) -> Result<GpsData>;
     /// Erase the tracks
-    fn erase(&self) -> Result<()>;
+    fn erase(&self, cancel: &CancelToken) -> Result<()>;
+    /// Enable or disable logging on the device.
+    fn set_logging(&self, _enable: bool) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+    /// Return whether logging is enabled on the device.
+    /// Return `Error::Cancelled` if `cancel` is triggered.
+    fn logging(&self, _cancel: &CancelToken) -> Result<bool> {
+        Err(Error::Unsupported)
+    }
+    /// Power off the device.
+    fn shutoff(&self) -> Result<()> {
+        Err(Error::Unsupported)
+    }
 }
@@ -0,0 +1,807 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use gettextrs::gettext as i18n;
+
+use crate::csv;
+use crate::devices::Capability;
+use crate::drivers::{CancelToken, Driver, Error, Progress, Result};
+use crate::fit;
+use crate::geojson;
+use crate::gpx;
+use crate::igc;
+use crate::kml;
+use crate::nmea;
+use crate::tcx;
+use crate::track::GpsData;
+use crate::Format;
+
+/// The time format for `{start}` in a file name template.
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d_%H%M%S";
+
+/// The formats the data can be exported to. The first is the default.
+pub const FORMATS: &[Format] = &[
+    Format::Gpx,
+    Format::Kml,
+    Format::GeoJson,
+    Format::Tcx,
+    Format::Fit,
+    Format::Csv,
+    Format::Nmea,
+    Format::Igc,
+];
+
+impl Format {
+    /// The id, as used on the command line.
+    pub fn id(self) -> Option<&'static str> {
+        match self {
+            Format::Gpx => Some("gpx"),
+            Format::Kml => Some("kml"),
+            Format::GeoJson => Some("geojson"),
+            Format::Tcx => Some("tcx"),
+            Format::Fit => Some("fit"),
+            Format::Csv => Some("csv"),
+            Format::Nmea => Some("nmea"),
+            Format::Igc => Some("igc"),
+            Format::None => None,
+        }
+    }
+
+    /// The file extension, without the dot.
+    pub fn extension(self) -> Option<&'static str> {
+        self.id()
+    }
+
+    /// The name to display.
+    pub fn label(self) -> &'static str {
+        match self {
+            Format::Gpx => "GPX",
+            Format::Kml => "KML",
+            Format::GeoJson => "GeoJSON",
+            Format::Tcx => "TCX",
+            Format::Fit => "FIT",
+            Format::Csv => "CSV",
+            Format::Nmea => "NMEA",
+            Format::Igc => "IGC",
+            Format::None => "",
+        }
+    }
+
+    /// The format from its id.
+    pub fn from_id(id: &str) -> Option<Format> {
+        FORMATS.iter().copied().find(|f| f.id() == Some(id))
+    }
+
+    /// The format for `path`, from its extension.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        FORMATS
+            .iter()
+            .copied()
+            .find(|f| f.extension() == Some(extension.as_str()))
+    }
+}
+
+/// Return `path` with the extension of `format`, unless it already
+/// has the extension of one of the formats.
+pub fn with_extension(path: &Path, format: Format) -> PathBuf {
+    if Format::from_path(path).is_some() {
+        return path.to_path_buf();
+    }
+    match format.extension() {
+        Some(extension) => {
+            let mut path = path.as_os_str().to_owned();
+            path.push(".");
+            path.push(extension);
+            PathBuf::from(path)
+        }
+        None => path.to_path_buf(),
+    }
+}
+
+/// Return `path` with the extension of `format`, replacing the
+/// extension of one of the formats if it has one.
+pub fn path_for_format(path: &Path, format: Format) -> PathBuf {
+    let path = match Format::from_path(path) {
+        Some(_) => path.with_extension(""),
+        None => path.to_path_buf(),
+    };
+    with_extension(&path, format)
+}
+
+/// Parse a comma separated list of format ids.
+pub fn parse_formats(ids: &str) -> Option<Vec<Format>> {
+    let mut formats = vec![];
+    for id in ids.split(',') {
+        let format = Format::from_id(id.trim())?;
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    Some(formats)
+}
+
+/// Options for the formats that have some.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    pub csv: csv::Options,
+    pub igc: igc::Options,
+}
+
+/// Write `data` in `format`.
+pub fn write<W: Write>(
+    out: &mut W,
+    format: Format,
+    options: &Options,
+    data: &GpsData,
+) -> Result<()> {
+    match format {
+        Format::Gpx => gpx::write(out, data)?,
+        Format::Kml => kml::write(out, data)?,
+        Format::GeoJson => geojson::write(out, data)?,
+        Format::Tcx => tcx::write(out, data)?,
+        Format::Fit => fit::write(out, data).map_err(invalid_input)?,
+        Format::Csv => csv::write(out, data, &options.csv)?,
+        Format::Nmea => nmea::write(out, data)?,
+        Format::Igc => igc::write(out, data, &options.igc).map_err(invalid_input)?,
+        Format::None => return Err(Error::WrongArg),
+    }
+    Ok(())
+}
+
+/// The error for a writer error: the data the format can't hold is
+/// a failure with the message, not an I/O error.
+fn invalid_input(err: io::Error) -> Error {
+    if err.kind() == io::ErrorKind::InvalidInput {
+        Error::Failed(err.to_string())
+    } else {
+        Error::Io(err)
+    }
+}
+
+/// Write `data` in `format` to the file at `path`.
+pub fn write_file(path: &Path, format: Format, options: &Options, data: &GpsData) -> Result<()> {
+    let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+    write(&mut file, format, options, data)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Check that the file `path` can be created, before downloading.
+pub fn check_writable(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        return Err(Error::Failed(format!(
+            "{}: {}",
+            i18n("Is a folder"),
+            path.display()
+        )));
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    tempfile::Builder::new()
+        .prefix(".gpsami")
+        .tempfile_in(dir)?;
+    Ok(())
+}
+
+/// What to do with the device after downloading.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownloadOptions {
+    pub erase: bool,
+    pub shutoff: bool,
+    /// Where to save the data if it can't be written after erasing.
+    pub rescue_dir: PathBuf,
+}
+
+impl DownloadOptions {
+    /// The options to `erase` and `shutoff`, rescuing the data in the
+    /// data directory.
+    pub fn new(erase: bool, shutoff: bool) -> DownloadOptions {
+        let mut rescue_dir = dirs::data_local_dir().unwrap_or_else(std::env::temp_dir);
+        rescue_dir.push("gpsami");
+        DownloadOptions {
+            erase,
+            shutoff,
+            rescue_dir,
+        }
+    }
+}
+
+/// Save `data` as GPX in `dir`, when it couldn't be written after
+/// erasing the device. Return the file.
+fn write_rescue(dir: &Path, data: &GpsData) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let name = format!("rescue-{}", Local::now().format(DEFAULT_TIME_FORMAT));
+    let path = unique_path(dir, &name, &[Format::Gpx])?;
+    write_file(&path, Format::Gpx, &Options::default(), data)?;
+    Ok(path)
+}
+
+/// Download from `device` and `write` the data. With `erase`, the
+/// device is erased, and powered off with `shutoff`, only once the
+/// data is written, if `cap` allows erasing on its own. Otherwise it
+/// is erased by the download: check the destination before. If
+/// writing fails then, the data is saved to a rescue file in
+/// `rescue_dir`, named in the error. Fail before downloading if
+/// `shutoff` isn't supported.
+pub fn download_and_write<T, F>(
+    device: &dyn Driver,
+    cap: &Capability,
+    options: &DownloadOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancelToken,
+    write: F,
+) -> Result<T>
+where
+    F: FnOnce(&GpsData) -> Result<T>,
+{
+    let (erase, shutoff) = (options.erase, options.shutoff);
+    if shutoff && !cap.can_shutoff {
+        return Err(Error::Unsupported);
+    }
+    if erase && cap.can_erase_only {
+        let data = device.download(false, false, progress, cancel)?;
+        let written = write(&data)?;
+        device.erase(cancel)?;
+        if shutoff {
+            device.shutoff()?;
+        }
+        return Ok(written);
+    }
+    let data = device.download(erase, shutoff, progress, cancel)?;
+    match write(&data) {
+        Err(err) if erase => {
+            let rescue = write_rescue(&options.rescue_dir, &data).map_err(|rescue_err| {
+                log::error!("Can't write the rescue file: {rescue_err}");
+                Error::Failed(format!(
+                    "{err}. {}",
+                    i18n("The device was erased and the tracks are lost.")
+                ))
+            })?;
+            Err(Error::Failed(format!(
+                "{err}. {} {}",
+                i18n("The tracks were saved to"),
+                rescue.display()
+            )))
+        }
+        result => result,
+    }
+}
+
+/// Expand the file name `template`. `{model}` is the device model and
+/// `{start}` the time of the first point, with an optional strftime
+/// format after a colon: `{start:%Y-%m-%d}`. Return None if the
+/// template is invalid, a field is empty, or it isn't a file name.
+pub fn expand_template<Tz: TimeZone>(
+    template: &str,
+    model: &str,
+    start: &DateTime<Tz>,
+) -> Option<String>
+where
+    Tz::Offset: fmt::Display,
+{
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        name.push_str(&rest[..open]);
+        let close = rest[open..].find('}')? + open;
+        let (field, format) = match rest[open + 1..close].split_once(':') {
+            Some((field, format)) => (field, format),
+            None => (&rest[open + 1..close], DEFAULT_TIME_FORMAT),
+        };
+        let value = match field {
+            "model" => model.to_string(),
+            "start" => {
+                let items: Vec<Item> = StrftimeItems::new(format).collect();
+                if items.iter().any(|item| matches!(item, Item::Error)) {
+                    return None;
+                }
+                start.format_with_items(items.into_iter()).to_string()
+            }
+            _ => return None,
+        };
+        if value.is_empty() {
+            return None;
+        }
+        name.push_str(&value.replace(std::path::is_separator, "-"));
+        rest = &rest[close + 1..];
+    }
+    name.push_str(rest);
+    if name.is_empty() || name.contains(std::path::is_separator) {
+        return None;
+    }
+    Some(name)
+}
+
+/// Create the empty files for `formats` at `path`, if none exist.
+/// Return false if one does.
+fn reserve(path: &Path, formats: &[Format]) -> io::Result<bool> {
+    let mut created = vec![];
+    for &format in formats {
+        let file = with_extension(path, format);
+        let result = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file);
+        if let Err(err) = result {
+            for file in &created {
+                let _ = std::fs::remove_file(file);
+            }
+            return match err.kind() {
+                io::ErrorKind::AlreadyExists => Ok(false),
+                _ => Err(err),
+            };
+        }
+        created.push(file);
+    }
+    Ok(true)
+}
+
+/// A path in `dir` for the file `name`, with the extension of the
+/// first of `formats`, where none of the files for `formats` exist.
+/// A number is appended to the name if needed. The files are created
+/// empty, so that nothing else takes them before they are written.
+pub fn unique_path(dir: &Path, name: &str, formats: &[Format]) -> io::Result<PathBuf> {
+    let name = Path::new(name);
+    let stem = match Format::from_path(name) {
+        Some(_) => name.with_extension(""),
+        None => name.to_path_buf(),
+    };
+    let format = formats.first().copied().unwrap_or(FORMATS[0]);
+    let mut n = 0;
+    loop {
+        let mut candidate = stem.as_os_str().to_owned();
+        if n > 0 {
+            candidate.push(format!("-{n}"));
+        }
+        let path = dir.join(candidate);
+        if reserve(&path, formats)? {
+            return Ok(with_extension(&path, format));
+        }
+        n += 1;
+    }
+}
+
+/// Write `data` in each of `formats`. With one format, write to `path`.
+/// Otherwise write each file next to `path` with the extension of the
+/// format. Return the files written.
+pub fn write_files(
+    path: &Path,
+    formats: &[Format],
+    options: &Options,
+    data: &GpsData,
+) -> Result<Vec<PathBuf>> {
+    let paths: Vec<(PathBuf, Format)> = match formats {
+        [] => return Err(Error::WrongArg),
+        [format] => vec![(path.to_path_buf(), *format)],
+        _ => formats
+            .iter()
+            .map(|&format| (path_for_format(path, format), format))
+            .collect(),
+    };
+    for (path, format) in &paths {
+        write_file(path, *format, options, data)?;
+    }
+    Ok(paths.into_iter().map(|(path, _)| path).collect())
+}
+
+/// The path for a piece of the data to write to `path`: the file stem
+/// followed by the local start time of the piece, where none of the
+/// files for `formats` exist. They are created by `unique_path()`.
+pub fn piece_path(path: &Path, formats: &[Format], piece: &GpsData) -> io::Result<PathBuf> {
+    let start = piece
+        .points()
+        .find_map(|point| point.time)
+        .unwrap_or_else(Utc::now)
+        .with_timezone(&Local);
+    let stem = match Format::from_path(path) {
+        Some(_) => path.with_extension(""),
+        None => path.to_path_buf(),
+    };
+    let name = format!(
+        "{}_{}",
+        stem.file_name().unwrap_or_default().to_string_lossy(),
+        start.format(DEFAULT_TIME_FORMAT)
+    );
+    unique_path(path.parent().unwrap_or(Path::new("")), &name, formats)
+}
+
+/// Write each of `pieces` with `write_files()`. With several pieces,
+/// each is written to its `piece_path()`. Return the files written.
+pub fn write_pieces(
+    path: &Path,
+    formats: &[Format],
+    options: &Options,
+    pieces: &[GpsData],
+) -> Result<Vec<PathBuf>> {
+    if let [piece] = pieces {
+        return write_files(path, formats, options, piece);
+    }
+    write_unique_pieces(formats, options, pieces, |piece| {
+        Ok(piece_path(path, formats, piece)?)
+    })
+}
+
+/// Write each of `pieces` with `write_files()` to the path `reserve`
+/// returns from `unique_path()`. If it fails, the files reserved are
+/// removed, so that they don't stay empty and take the names. Return
+/// the files written.
+pub fn write_unique_pieces<F>(
+    formats: &[Format],
+    options: &Options,
+    pieces: &[GpsData],
+    mut reserve: F,
+) -> Result<Vec<PathBuf>>
+where
+    F: FnMut(&GpsData) -> Result<PathBuf>,
+{
+    let mut reserved = vec![];
+    let mut files = vec![];
+    for piece in pieces {
+        let written = reserve(piece).and_then(|path| {
+            reserved.extend(formats.iter().map(|&format| path_for_format(&path, format)));
+            write_files(&path, formats, options, piece)
+        });
+        match written {
+            Ok(written) => files.extend(written),
+            Err(err) => {
+                for file in &reserved {
+                    let _ = std::fs::remove_file(file);
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[test]
+fn test_format() {
+    assert_eq!(Format::from_id("kml"), Some(Format::Kml));
+    assert_eq!(Format::from_id("bogus"), None);
+    assert_eq!(
+        Format::from_path(Path::new("/tmp/track.GPX")),
+        Some(Format::Gpx)
+    );
+    assert_eq!(Format::from_path(Path::new("/tmp/track")), None);
+
+    assert_eq!(
+        with_extension(Path::new("/tmp/track"), Format::Kml),
+        PathBuf::from("/tmp/track.kml")
+    );
+    assert_eq!(
+        with_extension(Path::new("/tmp/track.gpx"), Format::Kml),
+        PathBuf::from("/tmp/track.gpx")
+    );
+    assert_eq!(
+        path_for_format(Path::new("/tmp/track.gpx"), Format::Kml),
+        PathBuf::from("/tmp/track.kml")
+    );
+    assert_eq!(
+        path_for_format(Path::new("/tmp/track.2024"), Format::Kml),
+        PathBuf::from("/tmp/track.2024.kml")
+    );
+
+    assert_eq!(
+        parse_formats("gpx, kml,gpx"),
+        Some(vec![Format::Gpx, Format::Kml])
+    );
+    assert_eq!(parse_formats("gpx,shp"), None);
+}
+
+#[test]
+fn test_template() {
+    use chrono::Utc;
+
+    let start = DateTime::<Utc>::from_timestamp(1_714_824_000, 0).unwrap();
+    assert_eq!(
+        expand_template("{model}_{start:%Y-%m-%d_%H%M}.gpx", "m241", &start).as_deref(),
+        Some("m241_2024-05-04_1200.gpx")
+    );
+    assert_eq!(
+        expand_template("{start}", "m241", &start).as_deref(),
+        Some("2024-05-04_120000")
+    );
+    assert_eq!(
+        expand_template("{start:%D}", "m241", &start).as_deref(),
+        Some("05-04-24")
+    );
+    assert_eq!(expand_template("{model", "m241", &start), None);
+    assert_eq!(expand_template("{bogus}", "m241", &start), None);
+    assert_eq!(expand_template("{start:%Q}", "m241", &start), None);
+    assert_eq!(expand_template("tracks/{model}", "m241", &start), None);
+    // no model.
+    assert_eq!(expand_template("{model}.gpx", "", &start), None);
+}
+
+#[test]
+fn test_unique_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let formats = [Format::Gpx, Format::Kml];
+    std::fs::write(dir.path().join("track.kml"), "").unwrap();
+    assert_eq!(
+        unique_path(dir.path(), "track.gpx", &formats).unwrap(),
+        dir.path().join("track-1.gpx")
+    );
+    assert!(!dir.path().join("track.gpx").exists());
+    // The files are taken.
+    assert!(dir.path().join("track-1.gpx").exists());
+    assert!(dir.path().join("track-1.kml").exists());
+    assert_eq!(
+        unique_path(dir.path(), "track", &formats).unwrap(),
+        dir.path().join("track-2.gpx")
+    );
+    assert_eq!(
+        unique_path(dir.path(), "other.gpx", &formats).unwrap(),
+        dir.path().join("other.gpx")
+    );
+    assert!(unique_path(&dir.path().join("missing"), "track", &formats).is_err());
+}
+
+#[test]
+fn test_write_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = GpsData::default();
+    let options = Options::default();
+
+    let path = dir.path().join("track.gpx");
+    let files = write_files(&path, &[Format::Kml], &options, &data).unwrap();
+    assert_eq!(files, vec![path]);
+
+    let path = dir.path().join("both");
+    let files = write_files(&path, &[Format::Gpx, Format::GeoJson], &options, &data).unwrap();
+    assert_eq!(
+        files,
+        vec![dir.path().join("both.gpx"), dir.path().join("both.geojson")]
+    );
+    assert!(files.iter().all(|file| file.exists()));
+
+    assert!(write_files(&path, &[], &options, &data).is_err());
+}
+
+#[test]
+fn test_write_pieces() {
+    use crate::track::{Track, TrackPoint, TrackSegment};
+
+    let dir = tempfile::tempdir().unwrap();
+    let piece = |timestamp| GpsData {
+        tracks: vec![Track {
+            name: None,
+            segments: vec![TrackSegment {
+                points: vec![TrackPoint {
+                    time: DateTime::from_timestamp(timestamp, 0),
+                    ..TrackPoint::default()
+                }],
+            }],
+        }],
+        waypoints: vec![],
+    };
+    let pieces = [piece(1_714_824_000), piece(1_714_910_400)];
+    let path = dir.path().join("track.gpx");
+    let files = write_pieces(&path, &[Format::Gpx], &Options::default(), &pieces).unwrap();
+    assert_eq!(files.len(), 2);
+    assert!(!path.exists());
+    for (file, piece) in files.iter().zip(&pieces) {
+        let start = piece.points().next().unwrap().time.unwrap();
+        let name = format!(
+            "track_{}.gpx",
+            start.with_timezone(&Local).format(DEFAULT_TIME_FORMAT)
+        );
+        assert_eq!(file, &dir.path().join(name));
+        assert!(file.exists());
+    }
+
+    // Existing files aren't overwritten.
+    let files2 = write_pieces(&path, &[Format::Gpx], &Options::default(), &pieces).unwrap();
+    assert_eq!(files2.len(), 2);
+    assert!(files2.iter().all(|file| !files.contains(file)));
+
+    // One piece is written to the path.
+    let files = write_pieces(&path, &[Format::Gpx], &Options::default(), &pieces[..1]).unwrap();
+    assert_eq!(files, vec![path.clone()]);
+
+    // Failing: no file is left reserved. The second piece is over 24
+    // hours, too long for IGC.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("track.gpx");
+    let mut long = piece(1_714_910_400);
+    long.tracks[0].segments[0].points.push(TrackPoint {
+        time: DateTime::from_timestamp(1_715_000_000, 0),
+        ..TrackPoint::default()
+    });
+    let pieces = [piece(1_714_824_000), long];
+    let result = write_pieces(
+        &path,
+        &[Format::Gpx, Format::Igc],
+        &Options::default(),
+        &pieces,
+    );
+    assert!(matches!(result, Err(Error::Failed(_))));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+/// A driver that records the operations.
+#[cfg(test)]
+#[derive(Default)]
+struct FakeDriver {
+    operations: std::cell::RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl Driver for FakeDriver {
+    fn open(&self) -> bool {
+        true
+    }
+
+    fn close(&self) -> bool {
+        true
+    }
+
+    fn download(
+        &self,
+        erase: bool,
+        shutoff: bool,
+        _progress: &dyn Fn(Progress),
+        _cancel: &CancelToken,
+    ) -> Result<GpsData> {
+        self.operations
+            .borrow_mut()
+            .push(format!("download erase={erase} shutoff={shutoff}"));
+        Ok(GpsData::default())
+    }
+
+    fn erase(&self, _cancel: &CancelToken) -> Result<()> {
+        self.operations.borrow_mut().push("erase".to_string());
+        Ok(())
+    }
+
+    fn shutoff(&self) -> Result<()> {
+        self.operations.borrow_mut().push("shutoff".to_string());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_download_and_write() {
+    let cancel = CancelToken::default();
+    let dir = tempfile::tempdir().unwrap();
+    let options = |erase, shutoff| DownloadOptions {
+        rescue_dir: dir.path().join("rescue"),
+        ..DownloadOptions::new(erase, shutoff)
+    };
+    let cap = Capability {
+        can_erase: true,
+        can_erase_only: true,
+        can_shutoff: true,
+        ..Capability::default()
+    };
+
+    // Erased after writing, then powered off.
+    let device = FakeDriver::default();
+    let result = download_and_write(
+        &device,
+        &cap,
+        &options(true, true),
+        &|_| {},
+        &cancel,
+        |_| Ok(1),
+    );
+    assert_eq!(result.unwrap(), 1);
+    assert_eq!(
+        *device.operations.borrow(),
+        ["download erase=false shutoff=false", "erase", "shutoff"]
+    );
+
+    // Not erased if writing fails.
+    let device = FakeDriver::default();
+    let result: Result<()> = download_and_write(
+        &device,
+        &cap,
+        &options(true, true),
+        &|_| {},
+        &cancel,
+        |_| Err(Error::WrongArg),
+    );
+    assert!(matches!(result, Err(Error::WrongArg)));
+    assert_eq!(
+        *device.operations.borrow(),
+        ["download erase=false shutoff=false"]
+    );
+
+    // Without erase, power off with the download.
+    let device = FakeDriver::default();
+    assert!(download_and_write(
+        &device,
+        &cap,
+        &options(false, true),
+        &|_| {},
+        &cancel,
+        |_| Ok(())
+    )
+    .is_ok());
+    assert_eq!(
+        *device.operations.borrow(),
+        ["download erase=false shutoff=true"]
+    );
+
+    // Erased by the download: the data is rescued if writing fails.
+    let cap = Capability {
+        can_erase: true,
+        ..Capability::default()
+    };
+    let device = FakeDriver::default();
+    let result: Result<()> = download_and_write(
+        &device,
+        &cap,
+        &options(true, false),
+        &|_| {},
+        &cancel,
+        |_| Err(Error::WrongArg),
+    );
+    assert_eq!(
+        *device.operations.borrow(),
+        ["download erase=true shutoff=false"]
+    );
+    match result {
+        Err(Error::Failed(message)) => {
+            assert!(message.starts_with("Incorrect argument. The tracks were saved to "));
+            let rescue = message.rsplit(' ').next().unwrap();
+            assert!(Path::new(rescue).starts_with(dir.path().join("rescue")));
+            assert!(Path::new(rescue).exists());
+        }
+        result => panic!("Unexpected {:?}", result),
+    }
+
+    // Can't power off: nothing is downloaded nor erased.
+    for cap in [
+        cap.clone(),
+        Capability {
+            can_erase_only: true,
+            ..cap
+        },
+    ] {
+        let device = FakeDriver::default();
+        let result = download_and_write(
+            &device,
+            &cap,
+            &options(true, true),
+            &|_| {},
+            &cancel,
+            |_| Ok(()),
+        );
+        assert!(matches!(result, Err(Error::Unsupported)));
+        assert!(device.operations.borrow().is_empty());
+    }
+}
+
+#[test]
+fn test_check_writable() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(check_writable(&dir.path().join("track.gpx")).is_ok());
+    assert!(check_writable(dir.path()).is_err());
+    assert!(check_writable(&dir.path().join("missing").join("track.gpx")).is_err());
+    // Nothing is left behind.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
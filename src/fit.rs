@@ -0,0 +1,628 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::convert::TryFrom;
+use std::io;
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+
+use crate::track::{GpsData, TrackPoint, TrackSegment};
+
+/// Size of the file header.
+const HEADER_SIZE: u8 = 14;
+/// Protocol version 2.0.
+const PROTOCOL_VERSION: u8 = 0x20;
+/// Profile version 21.40.
+const PROFILE_VERSION: u16 = 2140;
+/// FIT timestamps are seconds since 1989-12-31T00:00:00Z.
+const FIT_EPOCH: i64 = 631_065_600;
+
+// Base types.
+const ENUM: u8 = 0x00;
+const UINT16: u8 = 0x84;
+const SINT32: u8 = 0x85;
+const UINT32: u8 = 0x86;
+
+// Invalid values.
+const INVALID_UINT16: u16 = 0xffff;
+const INVALID_SINT32: i32 = 0x7fff_ffff;
+const INVALID_UINT32: u32 = 0xffff_ffff;
+
+// Global message numbers.
+const MESG_FILE_ID: u16 = 0;
+const MESG_SESSION: u16 = 18;
+const MESG_LAP: u16 = 19;
+const MESG_RECORD: u16 = 20;
+const MESG_ACTIVITY: u16 = 34;
+
+// Enum values.
+const FILE_ACTIVITY: u8 = 4;
+const MANUFACTURER_DEVELOPMENT: u16 = 255;
+const EVENT_SESSION: u8 = 8;
+const EVENT_LAP: u8 = 9;
+const EVENT_ACTIVITY: u8 = 26;
+const EVENT_TYPE_STOP: u8 = 1;
+const SPORT_GENERIC: u8 = 0;
+const ACTIVITY_MANUAL: u8 = 0;
+
+/// A message definition: local and global message number, and the
+/// fields as (number, size, base type).
+struct Message {
+    local: u8,
+    global: u16,
+    fields: &'static [(u8, u8, u8)],
+}
+
+const FILE_ID: Message = Message {
+    local: 0,
+    global: MESG_FILE_ID,
+    fields: &[
+        (0, 1, ENUM),   // type
+        (1, 2, UINT16), // manufacturer
+        (2, 2, UINT16), // product
+        (4, 4, UINT32), // time_created
+    ],
+};
+
+const RECORD: Message = Message {
+    local: 1,
+    global: MESG_RECORD,
+    fields: &[
+        (253, 4, UINT32), // timestamp
+        (0, 4, SINT32),   // position_lat
+        (1, 4, SINT32),   // position_long
+        (2, 2, UINT16),   // altitude, scale 5, offset 500
+        (5, 4, UINT32),   // distance, scale 100
+        (6, 2, UINT16),   // speed, scale 1000
+    ],
+};
+
+const LAP: Message = Message {
+    local: 2,
+    global: MESG_LAP,
+    fields: &[
+        (253, 4, UINT32), // timestamp
+        (254, 2, UINT16), // message_index
+        (0, 1, ENUM),     // event
+        (1, 1, ENUM),     // event_type
+        (2, 4, UINT32),   // start_time
+        (7, 4, UINT32),   // total_elapsed_time, scale 1000
+        (8, 4, UINT32),   // total_timer_time, scale 1000
+        (9, 4, UINT32),   // total_distance, scale 100
+    ],
+};
+
+const SESSION: Message = Message {
+    local: 3,
+    global: MESG_SESSION,
+    fields: &[
+        (253, 4, UINT32), // timestamp
+        (0, 1, ENUM),     // event
+        (1, 1, ENUM),     // event_type
+        (2, 4, UINT32),   // start_time
+        (5, 1, ENUM),     // sport
+        (7, 4, UINT32),   // total_elapsed_time, scale 1000
+        (8, 4, UINT32),   // total_timer_time, scale 1000
+        (9, 4, UINT32),   // total_distance, scale 100
+        (25, 2, UINT16),  // first_lap_index
+        (26, 2, UINT16),  // num_laps
+    ],
+};
+
+const ACTIVITY: Message = Message {
+    local: 4,
+    global: MESG_ACTIVITY,
+    fields: &[
+        (253, 4, UINT32), // timestamp
+        (0, 4, UINT32),   // total_timer_time, scale 1000
+        (1, 2, UINT16),   // num_sessions
+        (2, 1, ENUM),     // type
+        (3, 1, ENUM),     // event
+        (4, 1, ENUM),     // event_type
+    ],
+};
+
+const CRC_TABLE: [u16; 16] = [
+    0x0000, 0xcc01, 0xd801, 0x1400, 0xf001, 0x3c00, 0x2800, 0xe401, 0xa001, 0x6c00, 0x7800, 0xb401,
+    0x5000, 0x9c01, 0x8801, 0x4400,
+];
+
+/// The FIT CRC of `data`, starting with `crc`.
+fn crc(crc: u16, data: &[u8]) -> u16 {
+    data.iter().fold(crc, |mut crc, byte| {
+        let tmp = CRC_TABLE[(crc & 0xf) as usize];
+        crc = (crc >> 4) & 0x0fff;
+        crc = crc ^ tmp ^ CRC_TABLE[(byte & 0xf) as usize];
+        let tmp = CRC_TABLE[(crc & 0xf) as usize];
+        crc = (crc >> 4) & 0x0fff;
+        crc ^ tmp ^ CRC_TABLE[((byte >> 4) & 0xf) as usize]
+    })
+}
+
+/// `value` as an uint16, invalid if it is out of range.
+fn to_u16(value: f64) -> u16 {
+    let value = value.round();
+    if value >= 0.0 && value < f64::from(INVALID_UINT16) {
+        value as u16
+    } else {
+        INVALID_UINT16
+    }
+}
+
+/// `value` as an uint32, clamped to the valid range.
+fn clamp_u32(value: f64) -> u32 {
+    value.round().max(0.0).min(f64::from(INVALID_UINT32 - 1)) as u32
+}
+
+/// `time` in the FIT epoch, invalid if it can't be represented.
+fn timestamp(time: Option<DateTime<Utc>>) -> u32 {
+    time.and_then(|t| u32::try_from(t.timestamp() - FIT_EPOCH).ok())
+        .filter(|t| *t != INVALID_UINT32)
+        .unwrap_or(INVALID_UINT32)
+}
+
+/// `degrees` in semicircles. 180° would be the invalid value.
+fn semicircles(degrees: f64) -> i32 {
+    (degrees * (f64::from(1_u32 << 31) / 180.0))
+        .round()
+        .max(f64::from(i32::MIN))
+        .min(f64::from(INVALID_SINT32 - 1)) as i32
+}
+
+/// Seconds between `begin` and `end`, scaled by 1000. Clamped, as
+/// the time can go backwards in a log.
+fn elapsed(span: Option<(DateTime<Utc>, DateTime<Utc>)>) -> u32 {
+    span.map(|(begin, end)| clamp_u32((end - begin).num_milliseconds() as f64))
+        .unwrap_or(0)
+}
+
+/// `distance` in meters, scaled by 100.
+fn distance(distance: f64) -> u32 {
+    clamp_u32(distance * 100.0)
+}
+
+/// Encode the records of a FIT file.
+#[derive(Default)]
+struct Encoder {
+    data: Vec<u8>,
+}
+
+impl Encoder {
+    fn define(&mut self, message: &Message) {
+        self.data.push(0x40 | message.local);
+        self.data.push(0); // reserved
+        self.data.push(0); // little endian
+        self.data.extend_from_slice(&message.global.to_le_bytes());
+        self.data.push(message.fields.len() as u8);
+        for &(number, size, base_type) in message.fields {
+            self.data.extend_from_slice(&[number, size, base_type]);
+        }
+    }
+
+    fn start(&mut self, message: &Message) {
+        self.data.push(message.local);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn record(&mut self, point: &TrackPoint, distance: f64) {
+        self.start(&RECORD);
+        self.u32(timestamp(point.time));
+        self.i32(semicircles(point.lat));
+        self.i32(semicircles(point.lon));
+        self.u16(
+            point
+                .altitude()
+                .map(|ele| to_u16((ele + 500.0) * 5.0))
+                .unwrap_or(INVALID_UINT16),
+        );
+        self.u32(self::distance(distance));
+        self.u16(
+            point
+                .speed
+                .map(|speed| to_u16(speed * 1000.0))
+                .unwrap_or(INVALID_UINT16),
+        );
+    }
+
+    fn lap(&mut self, index: u16, segment: &TrackSegment) {
+        let span = segment.time_span();
+        let time = elapsed(span);
+        self.start(&LAP);
+        self.u32(timestamp(span.map(|s| s.1)));
+        self.u16(index);
+        self.u8(EVENT_LAP);
+        self.u8(EVENT_TYPE_STOP);
+        self.u32(timestamp(span.map(|s| s.0)));
+        self.u32(time);
+        self.u32(time);
+        self.u32(distance(segment.distance()));
+    }
+}
+
+/// Write the track points of `data` as a FIT activity. Each segment
+/// is a lap. Points without a time and waypoints are ignored. Fail
+/// with `InvalidInput` if there are more laps than FIT can count.
+pub fn write<W: Write>(out: &mut W, data: &GpsData) -> io::Result<()> {
+    let segments: Vec<TrackSegment> = data
+        .tracks
+        .iter()
+        .flat_map(|track| track.segments.iter())
+        .map(TrackSegment::timed)
+        .filter(|segment| !segment.points.is_empty())
+        .collect();
+    let times: Vec<DateTime<Utc>> = segments
+        .iter()
+        .filter_map(|segment| segment.time_span())
+        .flat_map(|(begin, end)| vec![begin, end])
+        .collect();
+    let span = times.first().map(|begin| (*begin, *times.last().unwrap()));
+    let end = timestamp(span.map(|s| s.1));
+
+    let mut encoder = Encoder::default();
+    encoder.define(&FILE_ID);
+    encoder.start(&FILE_ID);
+    encoder.u8(FILE_ACTIVITY);
+    encoder.u16(MANUFACTURER_DEVELOPMENT);
+    encoder.u16(0);
+    encoder.u32(timestamp(span.map(|s| s.0)));
+
+    encoder.define(&RECORD);
+    let mut total_distance = 0.0;
+    for segment in &segments {
+        let mut previous: Option<&TrackPoint> = None;
+        for point in &segment.points {
+            if let Some(previous) = previous {
+                total_distance += previous.distance(point);
+            }
+            encoder.record(point, total_distance);
+            previous = Some(point);
+        }
+    }
+
+    // the lap count is an uint16, and 0xffff is the invalid value.
+    let too_many_laps = || io::Error::new(io::ErrorKind::InvalidInput, "Too many laps for FIT");
+    let num_laps = u16::try_from(segments.len())
+        .ok()
+        .filter(|laps| *laps != INVALID_UINT16)
+        .ok_or_else(too_many_laps)?;
+    encoder.define(&LAP);
+    for (index, segment) in segments.iter().enumerate() {
+        encoder.lap(u16::try_from(index).map_err(|_| too_many_laps())?, segment);
+    }
+
+    let total_time = segments
+        .iter()
+        .map(|segment| elapsed(segment.time_span()))
+        .fold(0_u32, |total, time| {
+            total.saturating_add(time).min(INVALID_UINT32 - 1)
+        });
+    encoder.define(&SESSION);
+    encoder.start(&SESSION);
+    encoder.u32(end);
+    encoder.u8(EVENT_SESSION);
+    encoder.u8(EVENT_TYPE_STOP);
+    encoder.u32(timestamp(span.map(|s| s.0)));
+    encoder.u8(SPORT_GENERIC);
+    encoder.u32(elapsed(span));
+    encoder.u32(total_time);
+    encoder.u32(distance(total_distance));
+    encoder.u16(0);
+    encoder.u16(num_laps);
+
+    encoder.define(&ACTIVITY);
+    encoder.start(&ACTIVITY);
+    encoder.u32(end);
+    encoder.u32(total_time);
+    encoder.u16(1);
+    encoder.u8(ACTIVITY_MANUAL);
+    encoder.u8(EVENT_ACTIVITY);
+    encoder.u8(EVENT_TYPE_STOP);
+
+    let mut header = vec![HEADER_SIZE, PROTOCOL_VERSION];
+    header.extend_from_slice(&PROFILE_VERSION.to_le_bytes());
+    header.extend_from_slice(&(encoder.data.len() as u32).to_le_bytes());
+    header.extend_from_slice(b".FIT");
+    let header_crc = crc(0, &header);
+    header.extend_from_slice(&header_crc.to_le_bytes());
+
+    let file_crc = crc(crc(0, &header), &encoder.data);
+    out.write_all(&header)?;
+    out.write_all(&encoder.data)?;
+    out.write_all(&file_crc.to_le_bytes())
+}
+
+/// A decoder for the tests. It doesn't share anything with the
+/// encoder: the profile values are from the FIT SDK documentation and
+/// the CRC is computed bit by bit.
+#[cfg(test)]
+mod decoder {
+    use std::collections::HashMap;
+
+    use chrono::DateTime;
+
+    use crate::track::TrackPoint;
+
+    /// Seconds between the UNIX and FIT epochs.
+    const EPOCH: i64 = 631_065_600;
+    /// The record message.
+    const RECORD: u16 = 20;
+
+    /// A decoded FIT file: the global message numbers in order and the
+    /// points from the record messages.
+    #[derive(Default)]
+    pub struct Decoded {
+        pub messages: Vec<u16>,
+        pub points: Vec<TrackPoint>,
+    }
+
+    /// CRC-16/ARC, reflected polynomial 0xa001.
+    fn crc(data: &[u8]) -> u16 {
+        data.iter().fold(0, |crc, byte| {
+            (0..8).fold(crc ^ u16::from(*byte), |crc, _| {
+                if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xa001
+                } else {
+                    crc >> 1
+                }
+            })
+        })
+    }
+
+    /// The value of a field, `None` if it is the invalid value of its
+    /// base type.
+    fn field_value(data: &[u8], base_type: u8) -> Result<Option<i64>, String> {
+        let (value, invalid) = match (base_type, data.len()) {
+            (0x00, 1) => (i64::from(data[0]), 0xff),
+            (0x84, 2) => (i64::from(u16::from_le_bytes([data[0], data[1]])), 0xffff),
+            (0x85, 4) => (
+                i64::from(i32::from_le_bytes([data[0], data[1], data[2], data[3]])),
+                0x7fff_ffff,
+            ),
+            (0x86, 4) => (
+                i64::from(u32::from_le_bytes([data[0], data[1], data[2], data[3]])),
+                0xffff_ffff,
+            ),
+            _ => {
+                return Err(format!(
+                    "Unexpected base type {base_type:#x} of size {}",
+                    data.len()
+                ))
+            }
+        };
+        Ok(Some(value).filter(|value| *value != invalid))
+    }
+
+    /// A field definition: number, size and base type.
+    type Field = (u8, u8, u8);
+
+    /// Decode a FIT file, checking the CRCs.
+    pub fn decode(file: &[u8]) -> Result<Decoded, String> {
+        let header_size = file[0] as usize;
+        if &file[8..12] != b".FIT" {
+            return Err("Not a FIT file".to_string());
+        }
+        if header_size == 14 && crc(&file[0..14]) != 0 {
+            return Err("Header CRC error".to_string());
+        }
+        if crc(file) != 0 {
+            return Err("CRC error".to_string());
+        }
+        let data_size = u32::from_le_bytes([file[4], file[5], file[6], file[7]]) as usize;
+        let data = &file[header_size..header_size + data_size];
+
+        let mut definitions: HashMap<u8, (u16, Vec<Field>)> = HashMap::new();
+        let mut decoded = Decoded::default();
+        let mut pos = 0;
+        while pos < data.len() {
+            let header = data[pos];
+            pos += 1;
+            let local = header & 0x0f;
+            if header & 0x40 != 0 {
+                if data[pos + 1] != 0 {
+                    return Err("Big endian message".to_string());
+                }
+                let global = u16::from_le_bytes([data[pos + 2], data[pos + 3]]);
+                let count = data[pos + 4] as usize;
+                pos += 5;
+                let fields = data[pos..pos + count * 3]
+                    .chunks(3)
+                    .map(|f| (f[0], f[1], f[2]))
+                    .collect();
+                pos += count * 3;
+                definitions.insert(local, (global, fields));
+            } else {
+                let (global, fields) = definitions
+                    .get(&local)
+                    .ok_or_else(|| format!("Undefined local message {local}"))?;
+                decoded.messages.push(*global);
+                let mut point = TrackPoint::default();
+                for &(number, size, base_type) in fields {
+                    let value = field_value(&data[pos..pos + size as usize], base_type)?;
+                    pos += size as usize;
+                    if *global != RECORD {
+                        continue;
+                    }
+                    // record fields: timestamp, position_lat and
+                    // position_long in semicircles, altitude with scale
+                    // 5 and offset 500, speed with scale 1000.
+                    let semicircles = |v: i64| v as f64 * 180.0 / 2_147_483_648.0;
+                    match (number, value) {
+                        (253, Some(v)) => point.time = DateTime::from_timestamp(v + EPOCH, 0),
+                        (0, Some(v)) => point.lat = semicircles(v),
+                        (1, Some(v)) => point.lon = semicircles(v),
+                        (2, Some(v)) => point.elevation = Some(v as f64 / 5.0 - 500.0),
+                        (6, Some(v)) => point.speed = Some(v as f64 / 1000.0),
+                        _ => {}
+                    }
+                }
+                if *global == RECORD {
+                    decoded.points.push(point);
+                }
+            }
+        }
+        Ok(decoded)
+    }
+}
+
+#[test]
+fn test_crc() {
+    // The FIT CRC is CRC-16/ARC.
+    assert_eq!(crc(0, b"123456789"), 0xbb3d);
+}
+
+#[test]
+fn test_write() {
+    use crate::track::{test_data, test_point};
+
+    let point = TrackPoint {
+        speed: Some(1.25),
+        ..test_point(0)
+    };
+    let point2 = TrackPoint {
+        lat: 45.501,
+        elevation: None,
+        ..test_point(60)
+    };
+    let data = test_data(vec![
+        vec![point.clone(), point2.clone()],
+        vec![point.clone()],
+    ]);
+
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write FIT");
+    let decoded = decoder::decode(&output).expect("Failed to decode FIT");
+    assert_eq!(
+        decoded.messages,
+        // file_id, record, lap, session and activity.
+        vec![0, 20, 20, 20, 19, 19, 18, 34]
+    );
+    assert_eq!(decoded.points.len(), 3);
+    let decoded_point = &decoded.points[0];
+    assert_eq!(decoded_point.time, point.time);
+    assert!((decoded_point.lat - point.lat).abs() < 1e-6);
+    assert!((decoded_point.lon - point.lon).abs() < 1e-6);
+    // the altitude scale is 1/5 m.
+    assert!((decoded_point.elevation.unwrap() - 30.5).abs() <= 0.2);
+    assert_eq!(decoded_point.speed, Some(1.25));
+    assert_eq!(decoded.points[1].time, point2.time);
+    assert_eq!(decoded.points[1].elevation, None);
+    assert_eq!(decoded.points[1].speed, None);
+
+    // Corrupt the data.
+    output[20] ^= 0xff;
+    assert!(decoder::decode(&output).is_err());
+
+    // Points without time are skipped, and 180° is a valid position.
+    let data = test_data(vec![
+        vec![TrackPoint {
+            time: None,
+            ..point.clone()
+        }],
+        vec![
+            TrackPoint {
+                lon: 180.0,
+                ..point.clone()
+            },
+            TrackPoint {
+                time: None,
+                ..point2.clone()
+            },
+            TrackPoint {
+                lon: -180.0,
+                ..point2.clone()
+            },
+        ],
+    ]);
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write FIT");
+    let decoded = decoder::decode(&output).expect("Failed to decode FIT");
+    assert_eq!(
+        decoded
+            .messages
+            .iter()
+            .filter(|&&message| message == 19)
+            .count(),
+        1
+    );
+    assert_eq!(decoded.points.len(), 2);
+    assert!(decoded.points.iter().all(|point| point.time.is_some()));
+    assert!((decoded.points[0].lon - 180.0).abs() < 1e-6);
+    assert!((decoded.points[1].lon + 180.0).abs() < 1e-6);
+}
+
+#[test]
+fn test_too_many_laps() {
+    use crate::track::{test_data, test_point};
+
+    let data = test_data(vec![vec![test_point(0)]; usize::from(INVALID_UINT16)]);
+    let err = write(&mut vec![], &data).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let data = test_data(vec![vec![test_point(0)]; usize::from(INVALID_UINT16 - 1)]);
+    assert!(write(&mut vec![], &data).is_ok());
+}
+
+#[test]
+fn test_reference() {
+    use crate::track::{test_data, test_point};
+
+    let point = |seconds, lat, lon, elevation, speed| TrackPoint {
+        lat,
+        lon,
+        elevation,
+        speed,
+        ..test_point(seconds)
+    };
+    let data = test_data(vec![
+        vec![
+            point(0, 45.5, -73.5, Some(30.4), Some(1.25)),
+            point(60, 45.501, -73.5, None, None),
+        ],
+        // the time goes backwards, the elevation and the speed are out
+        // of range, and the last point has no time.
+        vec![
+            point(120, 45.502, -73.5, Some(-600.0), Some(70.0)),
+            point(90, 45.503, -73.5, Some(13000.0), Some(65.534)),
+            TrackPoint {
+                time: None,
+                ..point(0, 45.503, -73.499, Some(12606.8), Some(0.0))
+            },
+        ],
+    ]);
+
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write FIT");
+    assert_eq!(
+        output,
+        include_bytes!("../testdata/fit/reference.fit").to_vec()
+    );
+}
@@ -0,0 +1,235 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::Write;
+
+use chrono::SecondsFormat;
+use serde_json::{json, Value};
+
+use crate::track::{GpsData, Track, TrackPoint, TrackSegment, Waypoint};
+
+/// The GeoJSON position of `point`: [lon, lat, ele]
+fn position(point: &TrackPoint) -> Value {
+    match point.altitude() {
+        Some(ele) => json!([point.lon, point.lat, ele]),
+        None => json!([point.lon, point.lat]),
+    }
+}
+
+fn time(point: &TrackPoint) -> Value {
+    point
+        .time
+        .map(|t| Value::from(t.to_rfc3339_opts(SecondsFormat::AutoSi, true)))
+        .unwrap_or(Value::Null)
+}
+
+fn segment_positions(segment: &TrackSegment) -> Value {
+    segment.points.iter().map(position).collect()
+}
+
+fn segment_times(segment: &TrackSegment) -> Value {
+    segment.points.iter().map(time).collect()
+}
+
+/// The features of a track. Its segments are a LineString if there is
+/// one, a MultiLineString otherwise. The time of each point is in the
+/// `coordTimes` property, with the same layout as the coordinates. A
+/// line needs two positions: the segments of a single point are Point
+/// features, and the empty ones are skipped.
+fn track_features(track: &Track) -> Vec<Value> {
+    let (lines, points): (Vec<&TrackSegment>, Vec<&TrackSegment>) = track
+        .segments
+        .iter()
+        .filter(|segment| !segment.points.is_empty())
+        .partition(|segment| segment.points.len() >= 2);
+    let name = track.name.as_deref();
+    let mut features = vec![];
+    if !lines.is_empty() {
+        features.push(line_feature(&lines, name));
+    }
+    features.extend(
+        points
+            .iter()
+            .map(|segment| point_feature(&segment.points[0], name, None)),
+    );
+    features
+}
+
+/// A LineString for one segment, a MultiLineString for several.
+fn line_feature(segments: &[&TrackSegment], name: Option<&str>) -> Value {
+    let (geometry, times) = if segments.len() == 1 {
+        let segment = segments[0];
+        (
+            json!({
+                "type": "LineString",
+                "coordinates": segment_positions(segment),
+            }),
+            segment_times(segment),
+        )
+    } else {
+        (
+            json!({
+                "type": "MultiLineString",
+                "coordinates": segments.iter().map(|segment| segment_positions(segment)).collect::<Value>(),
+            }),
+            segments
+                .iter()
+                .map(|segment| segment_times(segment))
+                .collect(),
+        )
+    };
+    let mut properties = json!({ "coordTimes": times });
+    if let Some(name) = name {
+        properties["name"] = Value::from(name);
+    }
+    json!({
+        "type": "Feature",
+        "geometry": geometry,
+        "properties": properties,
+    })
+}
+
+fn point_feature(point: &TrackPoint, name: Option<&str>, desc: Option<&str>) -> Value {
+    let mut properties = json!({});
+    if let Some(name) = name {
+        properties["name"] = Value::from(name);
+    }
+    if let Some(desc) = desc {
+        properties["desc"] = Value::from(desc);
+    }
+    if point.time.is_some() {
+        properties["time"] = time(point);
+    }
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Point",
+            "coordinates": position(point),
+        },
+        "properties": properties,
+    })
+}
+
+fn waypoint_feature(waypoint: &Waypoint) -> Value {
+    point_feature(
+        &waypoint.point,
+        waypoint.name.as_deref(),
+        waypoint.description.as_deref(),
+    )
+}
+
+/// Write `data` as a GeoJSON FeatureCollection.
+pub fn write<W: Write>(out: &mut W, data: &GpsData) -> io::Result<()> {
+    let features: Vec<Value> = data
+        .waypoints
+        .iter()
+        .map(waypoint_feature)
+        .chain(data.tracks.iter().flat_map(track_features))
+        .collect();
+    let collection = json!({
+        "type": "FeatureCollection",
+        "features": features,
+    });
+    serde_json::to_writer_pretty(&mut *out, &collection)?;
+    writeln!(out)
+}
+
+#[test]
+fn test_write() {
+    use crate::track::test_point;
+
+    let point = test_point(0);
+    let mut data = GpsData {
+        tracks: vec![Track {
+            name: Some("Track 1".to_string()),
+            segments: vec![TrackSegment {
+                points: vec![point.clone(), TrackPoint::default()],
+            }],
+        }],
+        // no elevation: no third coordinate.
+        waypoints: vec![Waypoint {
+            name: Some("Home".to_string()),
+            description: None,
+            point: TrackPoint {
+                elevation: None,
+                ..point.clone()
+            },
+        }],
+    };
+
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write GeoJSON");
+    let value: Value = serde_json::from_slice(&output).expect("Invalid JSON");
+    assert_eq!(value["type"], "FeatureCollection");
+    let features = value["features"].as_array().unwrap();
+    assert_eq!(features.len(), 2);
+    assert_eq!(features[0]["geometry"]["type"], "Point");
+    assert_eq!(features[0]["geometry"]["coordinates"], json!([-73.5, 45.5]));
+    assert_eq!(features[0]["properties"]["name"], "Home");
+    assert_eq!(features[1]["geometry"]["type"], "LineString");
+    assert_eq!(features[1]["properties"]["name"], "Track 1");
+    assert_eq!(
+        features[1]["geometry"]["coordinates"][0],
+        json!([-73.5, 45.5, 30.5])
+    );
+    assert_eq!(
+        features[1]["properties"]["coordTimes"],
+        json!(["2024-05-04T12:00:00Z", null])
+    );
+
+    // A line needs two positions.
+    data.tracks[0]
+        .segments
+        .push(TrackSegment { points: vec![] });
+    data.tracks[0].segments.push(TrackSegment {
+        points: vec![point.clone()],
+    });
+    data.tracks[0].segments.push(TrackSegment {
+        points: vec![point.clone(), test_point(60)],
+    });
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write GeoJSON");
+    let value: Value = serde_json::from_slice(&output).expect("Invalid JSON");
+    let features = value["features"].as_array().unwrap();
+    assert_eq!(features.len(), 3);
+    let track = &features[1];
+    assert_eq!(track["geometry"]["type"], "MultiLineString");
+    let lines = track["geometry"]["coordinates"].as_array().unwrap();
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().all(|line| line.as_array().unwrap().len() >= 2));
+    assert_eq!(
+        track["properties"]["coordTimes"][1],
+        json!(["2024-05-04T12:00:00Z", "2024-05-04T12:01:00Z"])
+    );
+    assert_eq!(features[2]["geometry"]["type"], "Point");
+    assert_eq!(
+        features[2]["geometry"]["coordinates"],
+        json!([-73.5, 45.5, 30.5])
+    );
+    assert_eq!(features[2]["properties"]["name"], "Track 1");
+    assert_eq!(features[2]["properties"]["time"], "2024-05-04T12:00:00Z");
+
+    // Nothing usable.
+    data.tracks = vec![Track {
+        name: None,
+        segments: vec![TrackSegment { points: vec![] }],
+    }];
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write GeoJSON");
+    let value: Value = serde_json::from_slice(&output).expect("Invalid JSON");
+    assert_eq!(value["features"].as_array().unwrap().len(), 1);
+}
@@ -14,59 +14,81 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::path::PathBuf;
-use std::process::Command;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use tempfile::TempDir;
+use gettextrs::gettext as i18n;
 
 use crate::devices::Capability;
+use crate::drivers::CancelToken;
 use crate::drivers::Driver;
 use crate::drivers::Error;
-use crate::Format;
+use crate::drivers::Progress;
+use crate::drivers::Timeouts;
+use crate::gpx;
+use crate::track::GpsData;
+
+/// Interval to check for the process end or the cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The gpsbabel executable, looked up in PATH, unless configured.
+pub const DEFAULT_PROGRAM: &str = "gpsbabel";
+
+/// What the gpsbabel process printed.
+enum Output {
+    /// Any output.
+    Activity,
+    /// A status line.
+    Status(Progress),
+}
 
 /// GpsBabel "driver". Will use gpsbabel to connect to device.
 pub struct GpsBabel {
+    /// The gpsbabel executable.
+    program: PathBuf,
     device_id: String,
     port: String,
     cap: Capability,
+    timeouts: Timeouts,
 }
 
 impl GpsBabel {
-    pub fn new(device: String, port: &str, capability: Capability) -> Self {
+    /// New driver running `program` for gpsbabel `type_` with `options`.
+    pub fn new(
+        program: &Path,
+        type_: &str,
+        options: &[String],
+        port: &str,
+        capability: Capability,
+        timeouts: Timeouts,
+    ) -> Self {
+        let mut device = String::from(type_);
+        for option in options {
+            device.push(',');
+            device.push_str(option);
+        }
         GpsBabel {
+            program: program.to_owned(),
             device_id: device,
             port: port.to_owned(),
             cap: capability,
+            timeouts,
         }
     }
 
-    /// Return a string associated with the format.
-    /// Or None
-    fn format_to_string(format: &Format) -> Option<&'static str> {
-        match *format {
-            Format::Gpx => Some("gpx"),
-            Format::Kml => Some("kml"),
-            _ => None,
-        }
-    }
-
-    /// Return an extension (with .) associated with the format.
-    /// Or None
-    fn format_to_extension(format: &Format) -> Option<&'static str> {
-        match *format {
-            Format::Gpx => Some(".gpx"),
-            Format::Kml => Some(".kml"),
-            _ => None,
-        }
-    }
-
-    /// Build the basic command line for the device on port, eventually for delete
-    /// after download or erase only.
+    /// Build the basic command line running `program` for the device on port,
+    /// eventually for delete after download or erase only. `status` makes
+    /// gpsbabel print its progress.
     fn build_basic_command_line(
+        program: &Path,
         device_id: &str,
         port: &str,
         erase: bool,
         erase_only: bool,
+        status: bool,
     ) -> Command {
         let mut device_string = String::from(device_id);
         // erase and erase_only can't be true at the same time.
@@ -76,7 +98,11 @@ impl GpsBabel {
         } else if erase_only {
             device_string.push_str(",erase_only");
         }
-        let mut command = Command::new("gpsbabel");
+        let mut command = Command::new(program);
+        // must be before -f as the input is read when it is parsed.
+        if status {
+            command.arg("-vs");
+        }
         command
             .arg("-t")
             .arg("-w")
@@ -87,6 +113,220 @@ impl GpsBabel {
 
         command
     }
+
+    /// Parse a status line printed by gpsbabel with `-vs`:
+    /// `percent/count/total`.
+    fn parse_status(line: &str) -> Option<Progress> {
+        let mut parts = line.trim().split('/');
+        let _percent: u32 = parts.next()?.parse().ok()?;
+        let done = parts.next()?.parse().ok()?;
+        let total = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Progress { done, total })
+    }
+
+    /// Read `stream` until the end, passing each chunk to `f`, and
+    /// report the activity to `sender`.
+    fn read_chunks<R: Read>(
+        mut stream: R,
+        sender: &mpsc::Sender<Output>,
+        mut f: impl FnMut(&[u8]),
+    ) {
+        let mut buf = [0_u8; 1024];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    f(&buf[0..len]);
+                    let _ = sender.send(Output::Activity);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    log::error!("Error reading output: {err}");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Send the status in the stdout `line`, if it is one.
+    fn parse_output(line: &[u8], sender: &mpsc::Sender<Output>) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        match GpsBabel::parse_status(line) {
+            Some(status) => {
+                let _ = sender.send(Output::Status(status));
+            }
+            None => log::debug!("stdout: {line}"),
+        }
+    }
+
+    /// The error for a command that couldn't be started.
+    fn spawn_error(command: &Command, err: std::io::Error) -> Error {
+        if err.kind() == std::io::ErrorKind::NotFound {
+            Error::NoDriver(format!(
+                "{}: {}",
+                i18n("gpsbabel not found"),
+                command.get_program().to_string_lossy()
+            ))
+        } else {
+            err.into()
+        }
+    }
+
+    /// Run `program` with `arg` and return its output. The process is
+    /// killed after `timeout`.
+    fn output(program: &Path, arg: &str, timeout: Duration) -> Result<String, Error> {
+        // files, so that nothing has to be read while waiting.
+        let mut stdout = tempfile::tempfile()?;
+        let mut stderr = tempfile::tempfile()?;
+        let mut command = Command::new(program);
+        command
+            .arg(arg)
+            .stdout(stdout.try_clone()?)
+            .stderr(stderr.try_clone()?);
+        let mut child = command
+            .spawn()
+            .map_err(|err| GpsBabel::spawn_error(&command, err))?;
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if started.elapsed() >= timeout {
+                log::debug!("{}, killing gpsbabel", Error::Timeout);
+                child.kill()?;
+                child.wait()?;
+                return Err(Error::Timeout);
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        let read = |file: &mut std::fs::File| -> std::io::Result<String> {
+            let mut output = vec![];
+            file.rewind()?;
+            file.read_to_end(&mut output)?;
+            Ok(String::from_utf8_lossy(&output).into_owned())
+        };
+        if !status.success() {
+            let err_output = read(&mut stderr)?;
+            log::error!("{}: {}", status, err_output);
+            return Err(Error::Failed(err_output));
+        }
+        Ok(read(&mut stdout)?)
+    }
+
+    /// Run the command, for at most `timeout`. Return an error with
+    /// stderr if it fails.
+    fn run(command: &mut Command, timeout: Duration, cancel: &CancelToken) -> Result<(), Error> {
+        GpsBabel::run_with_progress(command, timeout, timeout, &|_| {}, cancel)
+    }
+
+    /// Run the command, calling `progress` with the status gpsbabel
+    /// prints on stdout. The process is killed if `cancel` is triggered,
+    /// after `timeout` or if it prints nothing for `inactivity` once it
+    /// printed a first status. gpsbabel only prints a status for some
+    /// formats, so a silent run is only limited by `timeout`.
+    /// Return an error with stderr if it fails.
+    fn run_with_progress(
+        command: &mut Command,
+        timeout: Duration,
+        inactivity: Duration,
+        progress: &dyn Fn(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        cancel.check()?;
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| GpsBabel::spawn_error(command, err))?;
+        // read the pipes in threads so that the child doesn't block on
+        // either, while we wait for it.
+        let (sender, receiver) = mpsc::channel();
+        let stderr = child.stderr.take();
+        let stderr_sender = sender.clone();
+        let stderr_reader = thread::spawn(move || {
+            let mut output = vec![];
+            if let Some(stderr) = stderr {
+                GpsBabel::read_chunks(stderr, &stderr_sender, |chunk| {
+                    output.extend_from_slice(chunk)
+                });
+            }
+            String::from_utf8_lossy(&output).into_owned()
+        });
+
+        let stdout = child.stdout.take();
+        let stdout_reader = thread::spawn(move || {
+            if let Some(stdout) = stdout {
+                let mut pending = vec![];
+                GpsBabel::read_chunks(stdout, &sender, |chunk| {
+                    pending.extend_from_slice(chunk);
+                    // the status is terminated by a carriage return.
+                    while let Some(idx) = pending.iter().position(|c| *c == b'\r' || *c == b'\n') {
+                        let line: Vec<u8> = pending.drain(0..=idx).collect();
+                        GpsBabel::parse_output(&line, &sender);
+                    }
+                });
+                GpsBabel::parse_output(&pending, &sender);
+            }
+        });
+
+        let started = Instant::now();
+        // the inactivity clock starts with the first status, then any
+        // output counts as activity.
+        let mut last_activity: Option<Instant> = None;
+        let status = loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(output) => {
+                    if let Output::Status(status) = output {
+                        last_activity = Some(Instant::now());
+                        progress(status);
+                    } else if last_activity.is_some() {
+                        last_activity = Some(Instant::now());
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(POLL_INTERVAL),
+            }
+            let error = if cancel.is_cancelled() {
+                Some(Error::Cancelled)
+            } else if last_activity.is_some_and(|last| last.elapsed() >= inactivity)
+                || started.elapsed() >= timeout
+            {
+                Some(Error::Timeout)
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                log::debug!("{error}, killing gpsbabel");
+                // don't wait for the readers: a grandchild process
+                // could still hold the pipes.
+                child.kill()?;
+                child.wait()?;
+                return Err(error);
+            }
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+        };
+        let _ = stdout_reader.join();
+        receiver.try_iter().for_each(|output| {
+            if let Output::Status(status) = output {
+                progress(status);
+            }
+        });
+        let err_output = stderr_reader.join().unwrap_or_default();
+        if !status.success() {
+            log::error!("{}: {}", status, err_output);
+            return Err(Error::Failed(err_output));
+        }
+        Ok(())
+    }
 }
 
 impl Driver for GpsBabel {
@@ -98,68 +338,219 @@ impl Driver for GpsBabel {
         true
     }
 
-    /// Download the data into a file. Return the PathBuf to said file on success.
-    /// Caller is responsible for deleting the file.
-    fn download(&self, format: Format, erase: bool, tempdir: &TempDir) -> Result<PathBuf, Error> {
+    /// Download the data. gpsbabel outputs GPX into a temporary file
+    /// that is then parsed. The temporary directory is removed on
+    /// return, including when cancelled.
+    fn download(
+        &self,
+        erase: bool,
+        shutoff: bool,
+        progress: &dyn Fn(Progress),
+        cancel: &CancelToken,
+    ) -> Result<GpsData, Error> {
         // we requested erase at the same time and it is not supported.
         if erase && !self.cap.can_erase {
             return Err(Error::Unsupported);
         }
-
-        let fmt_string_opt = Self::format_to_string(&format);
-        if fmt_string_opt.is_none() {
-            // invalid format
-            return Err(Error::WrongArg);
+        if shutoff && !self.cap.can_shutoff {
+            return Err(Error::Unsupported);
         }
-        let fmt_string = fmt_string_opt.unwrap();
-
-        let extension_opt = Self::format_to_extension(&format);
-        if extension_opt.is_none() {
-            // invalid format
-            return Err(Error::WrongArg);
+        let mut device_id = self.device_id.clone();
+        if shutoff {
+            device_id.push_str(",power_off");
         }
-        let extension = extension_opt.unwrap();
 
-        let outfile = tempdir.path().join(String::from("gpsami") + extension);
+        let tempdir = tempfile::tempdir()?;
+        let outfile = tempdir.path().join("gpsami.gpx");
 
         /* gpsbabel -t -w -i m241 -f /dev/ttyACM0 -o gpx -F $1 */
-        let output = GpsBabel::build_basic_command_line(&self.device_id, &self.port, erase, false)
+        GpsBabel::run_with_progress(
+            GpsBabel::build_basic_command_line(
+                &self.program,
+                &device_id,
+                &self.port,
+                erase,
+                false,
+                true,
+            )
             .arg("-o")
-            .arg(fmt_string) // format
+            .arg("gpx")
             .arg("-F")
-            .arg(String::from(outfile.to_str().unwrap()))
-            .output()?;
-        log::debug!("stdout: {}", String::from_utf8_lossy(&output.stdout));
-        if !output.status.success() {
-            let err_output = String::from_utf8_lossy(&output.stderr);
-            log::error!("{}: {}", output.status, err_output);
-            return Err(Error::Failed(err_output.into_owned()));
+            .arg(String::from(outfile.to_str().unwrap())),
+            self.timeouts.download,
+            self.timeouts.inactivity,
+            progress,
+            cancel,
+        )?;
+        let file = std::fs::File::open(outfile)?;
+        let mut data = gpx::read(std::io::BufReader::new(file))?;
+        // A barograph only has the pressure altitude. The formats
+        // without it use it as the elevation.
+        if self.cap.barometric {
+            for point in data.points_mut() {
+                point.pressure_altitude = point.elevation.take();
+            }
         }
-        Ok(outfile)
+        Ok(data)
     }
 
     /// Erase the logs on the device. Return an error if not capable.
-    fn erase(&self) -> Result<(), Error> {
+    fn erase(&self, cancel: &CancelToken) -> Result<(), Error> {
         // Device doesn't support "erase only"
         if !self.cap.can_erase_only {
             return Err(Error::Unsupported);
         }
         /* gpsbabel -t -w -i m241,erase_only -f /dev/ttyACM0 */
-        let output = GpsBabel::build_basic_command_line(&self.device_id, &self.port, false, true)
-            .output()?;
-        log::debug!("stdout: {}", String::from_utf8_lossy(&output.stdout));
-        if !output.status.success() {
-            let err_output = String::from_utf8_lossy(&output.stderr);
-            log::error!("{}: {}", output.status, err_output);
-            return Err(Error::Failed(err_output.into_owned()));
+        GpsBabel::run(
+            &mut GpsBabel::build_basic_command_line(
+                &self.program,
+                &self.device_id,
+                &self.port,
+                false,
+                true,
+                false,
+            ),
+            self.timeouts.erase,
+            cancel,
+        )
+    }
+
+    /// Enable logging on the device. gpsbabel can neither disable
+    /// logging, nor tell if it is enabled.
+    fn set_logging(&self, enable: bool) -> Result<(), Error> {
+        if !enable || !self.cap.can_log_enable {
+            return Err(Error::Unsupported);
         }
-        Ok(())
+        /* gpsbabel -t -w -i m241,log_enable -f /dev/ttyACM0 */
+        let device_id = format!("{},log_enable", self.device_id);
+        GpsBabel::run(
+            &mut GpsBabel::build_basic_command_line(
+                &self.program,
+                &device_id,
+                &self.port,
+                false,
+                false,
+                false,
+            ),
+            self.timeouts.command,
+            &CancelToken::default(),
+        )
+    }
+
+    /// Power off the device.
+    fn shutoff(&self) -> Result<(), Error> {
+        if !self.cap.can_shutoff {
+            return Err(Error::Unsupported);
+        }
+        /* gpsbabel -t -w -i navilink,power_off -f /dev/ttyUSB0 */
+        let device_id = format!("{},power_off", self.device_id);
+        GpsBabel::run(
+            &mut GpsBabel::build_basic_command_line(
+                &self.program,
+                &device_id,
+                &self.port,
+                false,
+                false,
+                false,
+            ),
+            self.timeouts.command,
+            &CancelToken::default(),
+        )
     }
 }
 
+/// The oldest gpsbabel version known to work.
+pub const MIN_VERSION: Version = Version(1, 5, 0);
+
+/// A gpsbabel version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// What a gpsbabel executable provides.
+#[derive(Debug, Default, PartialEq)]
+pub struct Probe {
+    /// The version, if it could be parsed.
+    pub version: Option<Version>,
+    /// The formats, as passed to -i.
+    pub types: Vec<String>,
+}
+
+impl Probe {
+    /// Return true if the version is older than `MIN_VERSION`. An
+    /// unknown version isn't.
+    pub fn is_outdated(&self) -> bool {
+        self.version
+            .map(|version| version < MIN_VERSION)
+            .unwrap_or(false)
+    }
+
+    /// Return true if gpsbabel has the format `type_`.
+    pub fn has_type(&self, type_: &str) -> bool {
+        self.types.iter().any(|t| t == type_)
+    }
+}
+
+/// Parse the version printed by `gpsbabel -V`, like
+/// `GPSBabel Version 1.8.0`.
+fn parse_version(output: &str) -> Option<Version> {
+    let mut words = output.split_whitespace();
+    words.find(|word| word.eq_ignore_ascii_case("version"))?;
+    // ignore a suffix like `-beta20190720`.
+    let mut numbers = words.next()?.split('.').map(|number| {
+        let digits = number
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(number.len());
+        number[..digits].parse::<u32>().ok()
+    });
+    let major = numbers.next()??;
+    let minor = numbers.next().unwrap_or(Some(0))?;
+    let patch = numbers.next().unwrap_or(Some(0))?;
+    Some(Version(major, minor, patch))
+}
+
+/// Parse the formats listed by `gpsbabel -^3`: tab separated lines
+/// with the kind, the modes and the name, followed by the lines of
+/// their options.
+fn parse_types(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 3 || fields[0] == "option" || fields[2].is_empty() {
+                return None;
+            }
+            Some(fields[2].to_string())
+        })
+        .collect()
+}
+
+/// Run `program` to find its version and the formats it has. Each run
+/// is killed after `timeout`.
+pub fn probe(program: &Path, timeout: Duration) -> Result<Probe, Error> {
+    let version = GpsBabel::output(program, "-V", timeout)?;
+    let types = GpsBabel::output(program, "-^3", timeout)?;
+    Ok(Probe {
+        version: parse_version(&version),
+        types: parse_types(&types),
+    })
+}
+
 #[test]
 fn test_command_builder() {
-    let command = GpsBabel::build_basic_command_line("foo", "ttyS0", false, false);
+    let command = GpsBabel::build_basic_command_line(
+        Path::new("gpsbabel"),
+        "foo",
+        "ttyS0",
+        false,
+        false,
+        false,
+    );
     assert_eq!(
         format!("{:?}", command),
         "\"gpsbabel\" \"-t\" \"-w\" \"-i\" \"foo\" \"-f\" \"ttyS0\""
@@ -167,21 +558,420 @@ fn test_command_builder() {
 }
 
 #[test]
-fn test_format() {
-    let result = GpsBabel::format_to_string(&Format::Gpx);
-    assert!(result.is_some());
-    assert_eq!(result, Some("gpx"));
+fn test_options() {
+    let gpsbabel = GpsBabel::new(
+        Path::new(DEFAULT_PROGRAM),
+        "skytraq",
+        &["baud=115200".to_string()],
+        "ttyS0",
+        Capability::default(),
+        Timeouts::default(),
+    );
+    let command = GpsBabel::build_basic_command_line(
+        &gpsbabel.program,
+        &gpsbabel.device_id,
+        "ttyS0",
+        true,
+        false,
+        false,
+    );
+    assert_eq!(
+        format!("{:?}", command),
+        "\"gpsbabel\" \"-t\" \"-w\" \"-i\" \"skytraq,baud=115200,erase\" \"-f\" \"ttyS0\""
+    )
+}
+
+#[test]
+fn test_status() {
+    let command = GpsBabel::build_basic_command_line(
+        Path::new("gpsbabel"),
+        "foo",
+        "ttyS0",
+        false,
+        false,
+        true,
+    );
+    assert_eq!(
+        format!("{:?}", command),
+        "\"gpsbabel\" \"-vs\" \"-t\" \"-w\" \"-i\" \"foo\" \"-f\" \"ttyS0\""
+    );
+
+    assert_eq!(
+        GpsBabel::parse_status("50/128/256"),
+        Some(Progress {
+            done: 128,
+            total: 256
+        })
+    );
+    assert_eq!(GpsBabel::parse_status("Downloading 50%"), None);
+    assert_eq!(GpsBabel::parse_status("1/2/3/4"), None);
+}
+
+#[test]
+fn test_run() {
+    let timeout = Duration::from_secs(10);
+    let progress = std::cell::RefCell::new(vec![]);
+    let result = GpsBabel::run_with_progress(
+        Command::new("sh")
+            .arg("-c")
+            .arg("printf '50/1/2\\r100/2/2\\r'"),
+        timeout,
+        timeout,
+        &|p| progress.borrow_mut().push(p.done),
+        &CancelToken::default(),
+    );
+    assert!(result.is_ok());
+    assert_eq!(*progress.borrow(), vec![1, 2]);
+
+    let result = GpsBabel::run(
+        Command::new("sh").arg("-c").arg("echo failed >&2; exit 1"),
+        timeout,
+        &CancelToken::default(),
+    );
+    assert!(matches!(result, Err(Error::Failed(ref e)) if e == "failed\n"));
+
+    let cancel = CancelToken::default();
+    let canceller = cancel.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        canceller.cancel();
+    });
+    let result = GpsBabel::run(Command::new("sleep").arg("10"), timeout, &cancel);
+    assert!(matches!(result, Err(Error::Cancelled)));
+
+    let result = GpsBabel::run(
+        Command::new("sleep").arg("10"),
+        Duration::from_millis(200),
+        &CancelToken::default(),
+    );
+    assert!(matches!(result, Err(Error::Timeout)));
+
+    // silent: only the overall timeout applies.
+    let result = GpsBabel::run_with_progress(
+        Command::new("sleep").arg("0.5"),
+        timeout,
+        Duration::from_millis(200),
+        &|_| {},
+        &CancelToken::default(),
+    );
+    assert!(result.is_ok());
+    let result = GpsBabel::run_with_progress(
+        Command::new("sleep").arg("10"),
+        Duration::from_millis(500),
+        Duration::from_millis(200),
+        &|_| {},
+        &CancelToken::default(),
+    );
+    assert!(matches!(result, Err(Error::Timeout)));
+
+    // after the first status, output other than the status is
+    // activity too.
+    let result = GpsBabel::run_with_progress(
+        Command::new("sh")
+            .arg("-c")
+            .arg("printf '25/1/4\\r'; for i in 1 2 3 4; do echo $i >&2; sleep 0.1; done"),
+        timeout,
+        Duration::from_millis(300),
+        &|_| {},
+        &CancelToken::default(),
+    );
+    assert!(result.is_ok());
+
+    // stalled after the first status.
+    let result = GpsBabel::run_with_progress(
+        Command::new("sh")
+            .arg("-c")
+            .arg("printf '50/1/2\\r'; sleep 10"),
+        timeout,
+        Duration::from_millis(200),
+        &|_| {},
+        &CancelToken::default(),
+    );
+    assert!(matches!(result, Err(Error::Timeout)));
+}
+
+/// A fake gpsbabel: a script that records its arguments, prints the
+/// scripted stdout and stderr, writes the scripted GPX to the -F file,
+/// and exits with the scripted code. `with_output()` scripts the
+/// stdout for a first argument, like `-V`.
+#[cfg(test)]
+pub struct FakeGpsBabel {
+    dir: tempfile::TempDir,
+}
+
+#[cfg(test)]
+impl FakeGpsBabel {
+    pub fn new(stdout: &str, stderr: &str, code: i32, gpx: Option<&str>) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("stdout"), stdout).unwrap();
+        std::fs::write(dir.path().join("stderr"), stderr).unwrap();
+        if let Some(gpx) = gpx {
+            std::fs::write(dir.path().join("track.gpx"), gpx).unwrap();
+        }
+        let script = format!(
+            "#!/bin/sh
+cd '{}'
+first=\"$1\"
+for arg in \"$@\"; do echo \"$arg\"; done >> args
+echo >> args
+while [ $# -gt 0 ]; do
+    if [ \"$1\" = -F ]; then output=\"$2\"; fi
+    shift
+done
+if [ -n \"$output\" ] && [ -f track.gpx ]; then cp track.gpx \"$output\"; fi
+if [ -f \"stdout$first\" ]; then cat \"stdout$first\"; else cat stdout; fi
+cat stderr >&2
+exit {code}
+",
+            dir.path().display()
+        );
+        let program = dir.path().join("gpsbabel");
+        std::fs::write(&program, script).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        FakeGpsBabel { dir }
+    }
+
+    /// Print `stdout` when the first argument is `arg`.
+    pub fn with_output(self, arg: &str, stdout: &str) -> Self {
+        std::fs::write(self.dir.path().join(format!("stdout{arg}")), stdout).unwrap();
+        self
+    }
+
+    pub fn program(&self) -> PathBuf {
+        self.dir.path().join("gpsbabel")
+    }
+
+    /// The driver for `type_` running the fake.
+    fn driver(&self, type_: &str, cap: Capability) -> GpsBabel {
+        GpsBabel::new(
+            &self.program(),
+            type_,
+            &[],
+            "/dev/ttyS0",
+            cap,
+            Timeouts::default(),
+        )
+    }
+
+    /// The arguments of each invocation.
+    pub fn invocations(&self) -> Vec<Vec<String>> {
+        let args = std::fs::read_to_string(self.dir.path().join("args")).unwrap_or_default();
+        args.split_terminator("\n\n")
+            .map(|args| args.lines().map(str::to_string).collect())
+            .collect()
+    }
+}
+
+#[test]
+fn test_fake_download() {
+    let gpx = r#"<?xml version="1.0"?>
+<gpx version="1.1" creator="gpsbabel"><trk><trkseg>
+<trkpt lat="45.5" lon="-73.5"><ele>32</ele><time>2024-05-04T12:00:00Z</time></trkpt>
+</trkseg></trk></gpx>"#;
+    let fake = FakeGpsBabel::new("50/1/2\r100/2/2\r", "", 0, Some(gpx));
+    let cap = Capability {
+        can_erase: true,
+        ..Capability::default()
+    };
+    let progress = std::cell::RefCell::new(vec![]);
+    let data = fake
+        .driver("m241", cap)
+        .download(
+            true,
+            false,
+            &|p| progress.borrow_mut().push(p.done),
+            &CancelToken::default(),
+        )
+        .expect("Download failed");
+    assert_eq!(data.points().count(), 1);
+    assert_eq!(data.points().next().unwrap().elevation, Some(32.0));
+    assert_eq!(*progress.borrow(), vec![1, 2]);
+
+    let invocations = fake.invocations();
+    assert_eq!(invocations.len(), 1);
+    assert_eq!(
+        invocations[0][..10],
+        [
+            "-vs",
+            "-t",
+            "-w",
+            "-i",
+            "m241,erase",
+            "-f",
+            "/dev/ttyS0",
+            "-o",
+            "gpx",
+            "-F"
+        ]
+    );
+    // The temporary output is removed.
+    assert!(!Path::new(&invocations[0][10]).exists());
+
+    // Erase and power off aren't supported: gpsbabel isn't run.
+    let driver = fake.driver("m241", Capability::default());
+    for (erase, shutoff) in [(true, false), (false, true)] {
+        assert!(matches!(
+            driver.download(erase, shutoff, &|_| {}, &CancelToken::default()),
+            Err(Error::Unsupported)
+        ));
+    }
+    assert_eq!(fake.invocations().len(), 1);
+}
+
+#[test]
+fn test_fake_erase() {
+    let fake = FakeGpsBabel::new("", "", 0, None);
+    let cap = Capability {
+        can_erase_only: true,
+        can_log_enable: true,
+        ..Capability::default()
+    };
+    let driver = fake.driver("m241", cap);
+    assert!(driver.erase(&CancelToken::default()).is_ok());
+    assert!(driver.set_logging(true).is_ok());
+    assert!(matches!(driver.set_logging(false), Err(Error::Unsupported)));
+    assert!(matches!(driver.shutoff(), Err(Error::Unsupported)));
+    assert_eq!(
+        fake.invocations(),
+        vec![
+            vec!["-t", "-w", "-i", "m241,erase_only", "-f", "/dev/ttyS0"],
+            vec!["-t", "-w", "-i", "m241,log_enable", "-f", "/dev/ttyS0"],
+        ]
+    );
+
+    let driver = fake.driver("m241", Capability::default());
+    assert!(matches!(
+        driver.erase(&CancelToken::default()),
+        Err(Error::Unsupported)
+    ));
+    assert_eq!(fake.invocations().len(), 2);
+}
+
+#[test]
+fn test_fake_failure() {
+    let fake = FakeGpsBabel::new("", "m241: Can't init /dev/ttyS0\n", 1, None);
+    let cap = Capability {
+        can_erase_only: true,
+        ..Capability::default()
+    };
+    let result = fake
+        .driver("m241", cap.clone())
+        .erase(&CancelToken::default());
+    assert!(matches!(result, Err(Error::Failed(ref e)) if e == "m241: Can't init /dev/ttyS0\n"));
+
+    // A format this gpsbabel doesn't know.
+    let fake = FakeGpsBabel::new("", "main: Unknown input format 'bogus'\n", 1, None);
+    let result = fake
+        .driver("bogus", cap)
+        .download(false, false, &|_| {}, &CancelToken::default());
+    assert!(matches!(result, Err(Error::Failed(ref e)) if e.contains("Unknown input format")));
+    assert_eq!(fake.invocations()[0][4], "bogus");
 
-    let result = GpsBabel::format_to_string(&Format::None);
-    assert!(result.is_none());
+    // Success, but no output file.
+    let fake = FakeGpsBabel::new("", "", 0, None);
+    let result = fake.driver("m241", Capability::default()).download(
+        false,
+        false,
+        &|_| {},
+        &CancelToken::default(),
+    );
+    assert!(matches!(result, Err(Error::Io(_))));
 }
 
 #[test]
-fn test_extensions() {
-    let result = GpsBabel::format_to_extension(&Format::Gpx);
-    assert!(result.is_some());
-    assert_eq!(result, Some(".gpx"));
+fn test_missing_program() {
+    let cap = Capability {
+        can_erase_only: true,
+        ..Capability::default()
+    };
+    for program in ["/nonexistent/gpsbabel", "gpsbabel-nonexistent"] {
+        let driver = GpsBabel::new(
+            Path::new(program),
+            "m241",
+            &[],
+            "/dev/ttyS0",
+            cap.clone(),
+            Timeouts::default(),
+        );
+        let result = driver.erase(&CancelToken::default());
+        assert!(matches!(result, Err(Error::NoDriver(ref e)) if e.ends_with(program)));
+    }
+}
+
+#[test]
+fn test_parse_version() {
+    assert_eq!(
+        parse_version("\nGPSBabel Version 1.8.0\n\n"),
+        Some(Version(1, 8, 0))
+    );
+    assert_eq!(
+        parse_version("GPSBabel Version 1.4.4-beta20130429"),
+        Some(Version(1, 4, 4))
+    );
+    assert_eq!(
+        parse_version("GPSBabel Version 1.6"),
+        Some(Version(1, 6, 0))
+    );
+    assert_eq!(parse_version("GPSBabel Version x"), None);
+    assert_eq!(parse_version("usage"), None);
+    assert!(Version(1, 4, 4) < MIN_VERSION);
+    assert!(Version(1, 10, 0) > MIN_VERSION);
+    assert_eq!(MIN_VERSION.to_string(), "1.5.0");
+}
+
+/// Formats as listed by `gpsbabel -^3`.
+#[cfg(test)]
+const TYPES: &str = "file\trwrwrw\tgpx\tgpx\tGPX XML\tgpx\t
+option\tgpx\tsnlen\tLength of generated shortnames\tinteger\t32\t1\t\t
+serial\tr-r---\tskytraq\t\tSkyTraq Venus based loggers (download)\tskytraq\t
+option\tskytraq\terase\tErase device data after download\tboolean\t0\t\t\t
+serial\tr-r---\tdg-100\t\tGlobalSat DG-100/BT-335 Download\tdg-100\t
+";
+
+#[test]
+fn test_probe() {
+    assert_eq!(parse_types(TYPES), vec!["gpx", "skytraq", "dg-100"]);
+    let timeout = Timeouts::default().command;
+
+    let fake = FakeGpsBabel::new("", "", 0, None)
+        .with_output("-V", "\nGPSBabel Version 1.8.0\n\n")
+        .with_output("-^3", TYPES);
+    let result = probe(&fake.program(), timeout).expect("Probe failed");
+    assert_eq!(result.version, Some(Version(1, 8, 0)));
+    assert!(!result.is_outdated());
+    assert!(result.has_type("skytraq"));
+    assert!(!result.has_type("baroiq"));
+    assert!(!result.has_type("erase"));
+    assert_eq!(
+        fake.invocations(),
+        vec![vec!["-V".to_string()], vec!["-^3".to_string()]]
+    );
+
+    let fake = FakeGpsBabel::new("", "", 0, None).with_output("-V", "GPSBabel Version 1.4.4");
+    assert!(probe(&fake.program(), timeout).unwrap().is_outdated());
+
+    let fake = FakeGpsBabel::new("", "gpsbabel: crashed\n", 1, None);
+    assert!(
+        matches!(probe(&fake.program(), timeout), Err(Error::Failed(ref e)) if e == "gpsbabel: crashed\n")
+    );
+
+    assert!(matches!(
+        probe(Path::new("/nonexistent/gpsbabel"), timeout),
+        Err(Error::NoDriver(_))
+    ));
 
-    let result = GpsBabel::format_to_string(&Format::None);
-    assert!(result.is_none());
+    // hangs.
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempfile::tempdir().unwrap();
+    let hang = dir.path().join("gpsbabel");
+    std::fs::write(&hang, "#!/bin/sh\nexec sleep 10\n").unwrap();
+    std::fs::set_permissions(&hang, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let started = Instant::now();
+    assert!(matches!(
+        probe(&hang, Duration::from_millis(300)),
+        Err(Error::Timeout)
+    ));
+    assert!(started.elapsed() < Duration::from_secs(5));
 }
@@ -0,0 +1,363 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::{BufRead, Write};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+
+use crate::drivers::{Error, Result};
+use crate::track::{FixType, GpsData, Track, TrackPoint, TrackSegment, Waypoint};
+
+/// Write the point elements that GPX 1.0 puts before the name.
+fn write_point_position<W: Write>(out: &mut W, point: &TrackPoint, indent: &str) -> io::Result<()> {
+    if let Some(ele) = point.altitude() {
+        writeln!(out, "{indent}<ele>{ele:.3}</ele>")?;
+    }
+    if let Some(time) = point.time {
+        writeln!(
+            out,
+            "{indent}<time>{}</time>",
+            time.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+        )?;
+    }
+    if let Some(course) = point.course {
+        writeln!(out, "{indent}<course>{course:.2}</course>")?;
+    }
+    if let Some(speed) = point.speed {
+        writeln!(out, "{indent}<speed>{speed:.3}</speed>")?;
+    }
+    Ok(())
+}
+
+/// Write the point elements that GPX 1.0 puts after the name and the
+/// description.
+fn write_point_quality<W: Write>(out: &mut W, point: &TrackPoint, indent: &str) -> io::Result<()> {
+    if let Some(fix) = point.fix {
+        writeln!(out, "{indent}<fix>{}</fix>", fix.to_gpx())?;
+    }
+    if let Some(sat) = point.satellites {
+        writeln!(out, "{indent}<sat>{sat}</sat>")?;
+    }
+    if let Some(hdop) = point.hdop {
+        writeln!(out, "{indent}<hdop>{hdop:.2}</hdop>")?;
+    }
+    Ok(())
+}
+
+/// Write `data` as GPX 1.0. Version 1.0 allows the speed.
+pub fn write<W: Write>(out: &mut W, data: &GpsData) -> io::Result<()> {
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        out,
+        "<gpx version=\"1.0\" creator=\"gpsami\" xmlns=\"http://www.topografix.com/GPX/1/0\">"
+    )?;
+    for waypoint in &data.waypoints {
+        let point = &waypoint.point;
+        writeln!(
+            out,
+            "  <wpt lat=\"{:.9}\" lon=\"{:.9}\">",
+            point.lat, point.lon
+        )?;
+        write_point_position(out, point, "    ")?;
+        if let Some(ref name) = waypoint.name {
+            writeln!(out, "    <name>{}</name>", escape(name.as_str()))?;
+        }
+        if let Some(ref desc) = waypoint.description {
+            writeln!(out, "    <desc>{}</desc>", escape(desc.as_str()))?;
+        }
+        write_point_quality(out, point, "    ")?;
+        writeln!(out, "  </wpt>")?;
+    }
+    for track in &data.tracks {
+        writeln!(out, "  <trk>")?;
+        if let Some(ref name) = track.name {
+            writeln!(out, "    <name>{}</name>", escape(name.as_str()))?;
+        }
+        for segment in &track.segments {
+            writeln!(out, "    <trkseg>")?;
+            for point in &segment.points {
+                writeln!(
+                    out,
+                    "      <trkpt lat=\"{:.9}\" lon=\"{:.9}\">",
+                    point.lat, point.lon
+                )?;
+                write_point_position(out, point, "        ")?;
+                write_point_quality(out, point, "        ")?;
+                writeln!(out, "      </trkpt>")?;
+            }
+            writeln!(out, "    </trkseg>")?;
+        }
+        writeln!(out, "  </trk>")?;
+    }
+    writeln!(out, "</gpx>")
+}
+
+/// Create a point from the lat and lon attributes of `element`.
+fn point_from_element(element: &BytesStart) -> Result<TrackPoint> {
+    let mut point = TrackPoint::default();
+    for attr in element.attributes().flatten() {
+        let value = attr
+            .unescape_value()
+            .map_err(|e| Error::Failed(e.to_string()))?;
+        match attr.key.local_name().as_ref() {
+            b"lat" => point.lat = value.parse().unwrap_or_default(),
+            b"lon" => point.lon = value.parse().unwrap_or_default(),
+            _ => {}
+        }
+    }
+    Ok(point)
+}
+
+/// The GPX element being parsed that can contain points.
+enum Context {
+    None,
+    Waypoint(Waypoint),
+    TrackPoint(TrackPoint),
+}
+
+impl Context {
+    fn point(&mut self) -> Option<&mut TrackPoint> {
+        match *self {
+            Context::Waypoint(ref mut w) => Some(&mut w.point),
+            Context::TrackPoint(ref mut p) => Some(p),
+            Context::None => None,
+        }
+    }
+}
+
+/// Add `point` to the last segment of `data`, creating it if needed.
+fn push_point(data: &mut GpsData, point: TrackPoint) {
+    if data.tracks.is_empty() {
+        data.tracks.push(Track::default());
+    }
+    let track = data.tracks.last_mut().unwrap();
+    if track.segments.is_empty() {
+        track.segments.push(TrackSegment::default());
+    }
+    track.segments.last_mut().unwrap().points.push(point);
+}
+
+/// Read GPX (1.0 or 1.1) from `input`.
+pub fn read<R: BufRead>(input: R) -> Result<GpsData> {
+    let mut reader = Reader::from_reader(input);
+    reader.config_mut().trim_text(true);
+
+    let mut data = GpsData::default();
+    let mut context = Context::None;
+    let mut text = String::new();
+    let mut buf = vec![];
+    loop {
+        let event = reader.read_event_into(&mut buf).map_err(|e| {
+            Error::Failed(format!("GPX error at {}: {}", reader.buffer_position(), e))
+        })?;
+        match event {
+            Event::Start(ref e) => {
+                text.clear();
+                match e.local_name().as_ref() {
+                    b"trk" => data.tracks.push(Track::default()),
+                    b"trkseg" => {
+                        if data.tracks.is_empty() {
+                            data.tracks.push(Track::default());
+                        }
+                        let track = data.tracks.last_mut().unwrap();
+                        track.segments.push(TrackSegment::default());
+                    }
+                    b"trkpt" => context = Context::TrackPoint(point_from_element(e)?),
+                    b"wpt" => {
+                        context = Context::Waypoint(Waypoint {
+                            point: point_from_element(e)?,
+                            ..Waypoint::default()
+                        })
+                    }
+                    _ => {}
+                }
+            }
+            Event::Empty(ref e) => match e.local_name().as_ref() {
+                b"trkpt" => push_point(&mut data, point_from_element(e)?),
+                b"wpt" => data.waypoints.push(Waypoint {
+                    point: point_from_element(e)?,
+                    ..Waypoint::default()
+                }),
+                _ => {}
+            },
+            Event::Text(ref t) => {
+                text = t
+                    .unescape()
+                    .map_err(|e| Error::Failed(e.to_string()))?
+                    .into_owned();
+            }
+            Event::End(ref e) => {
+                let name = e.local_name();
+                match name.as_ref() {
+                    b"trkpt" => {
+                        if let Context::TrackPoint(point) =
+                            std::mem::replace(&mut context, Context::None)
+                        {
+                            push_point(&mut data, point);
+                        }
+                    }
+                    b"wpt" => {
+                        if let Context::Waypoint(waypoint) =
+                            std::mem::replace(&mut context, Context::None)
+                        {
+                            data.waypoints.push(waypoint);
+                        }
+                    }
+                    b"name" => match context {
+                        Context::Waypoint(ref mut w) => w.name = Some(text.clone()),
+                        Context::None => {
+                            if let Some(track) = data.tracks.last_mut() {
+                                if track.name.is_none() && track.segments.is_empty() {
+                                    track.name = Some(text.clone());
+                                }
+                            }
+                        }
+                        _ => {}
+                    },
+                    b"desc" => {
+                        if let Context::Waypoint(ref mut w) = context {
+                            w.description = Some(text.clone());
+                        }
+                    }
+                    element => {
+                        if let Some(point) = context.point() {
+                            match element {
+                                b"ele" => point.elevation = text.parse().ok(),
+                                b"time" => {
+                                    point.time = DateTime::parse_from_rfc3339(&text)
+                                        .ok()
+                                        .map(|t| t.with_timezone(&Utc))
+                                }
+                                b"course" => point.course = text.parse().ok(),
+                                b"speed" => point.speed = text.parse().ok(),
+                                b"sat" => point.satellites = text.parse().ok(),
+                                b"hdop" => point.hdop = text.parse().ok(),
+                                b"fix" => point.fix = FixType::from_gpx(&text),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(data)
+}
+
+#[test]
+fn test_read_gpsbabel() {
+    let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.0" creator="GPSBabel - https://www.gpsbabel.org" xmlns="http://www.topografix.com/GPX/1/0">
+  <time>2024-05-04T12:00:00.000Z</time>
+  <wpt lat="45.500000000" lon="-73.500000000">
+    <ele>31.000000</ele>
+    <name>Home &amp; away</name>
+  </wpt>
+  <trk>
+    <name>Track 1</name>
+    <trkseg>
+      <trkpt lat="45.500000000" lon="-73.500000000">
+        <ele>30.500000</ele>
+        <time>2024-05-04T12:00:00Z</time>
+        <speed>1.250000</speed>
+        <fix>3d</fix>
+        <sat>7</sat>
+        <hdop>1.100000</hdop>
+      </trkpt>
+      <trkpt lat="45.600000000" lon="-73.600000000"/>
+    </trkseg>
+  </trk>
+</gpx>
+"#;
+    let data = read(gpx.as_bytes()).expect("Failed to read GPX");
+    assert_eq!(data.waypoints.len(), 1);
+    assert_eq!(data.waypoints[0].name.as_deref(), Some("Home & away"));
+    assert_eq!(data.waypoints[0].point.elevation, Some(31.0));
+    assert_eq!(data.tracks.len(), 1);
+    assert_eq!(data.tracks[0].name.as_deref(), Some("Track 1"));
+    let points: Vec<&TrackPoint> = data.points().collect();
+    assert_eq!(points.len(), 2);
+    assert_eq!(points[0].lat, 45.5);
+    assert_eq!(points[0].speed, Some(1.25));
+    assert_eq!(points[0].satellites, Some(7));
+    assert_eq!(points[0].fix, Some(FixType::ThreeD));
+    assert_eq!(points[0].time.unwrap().timestamp(), 1_714_824_000);
+    assert_eq!(points[1].lon, -73.6);
+}
+
+#[test]
+fn test_round_trip() {
+    use crate::track::test_point;
+
+    let point = TrackPoint {
+        speed: Some(1.25),
+        course: Some(271.5),
+        hdop: Some(1.1),
+        satellites: Some(7),
+        fix: Some(FixType::Dgps),
+        ..test_point(0)
+    };
+    // below the sea level, without time.
+    let point2 = TrackPoint {
+        lat: -33.856784,
+        lon: 151.215297,
+        elevation: Some(-12.5),
+        ..TrackPoint::default()
+    };
+    let data = GpsData {
+        tracks: vec![Track {
+            name: Some("<Track>".to_string()),
+            segments: vec![TrackSegment {
+                points: vec![point.clone(), point2],
+            }],
+        }],
+        waypoints: vec![Waypoint {
+            name: Some("Home".to_string()),
+            description: Some("Door".to_string()),
+            point,
+        }],
+    };
+
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write GPX");
+    // The order of the elements of a waypoint in GPX 1.0.
+    let gpx = String::from_utf8_lossy(&output);
+    let elements: Vec<&str> = gpx
+        .split("<wpt ")
+        .nth(1)
+        .and_then(|wpt| wpt.split("</wpt>").next())
+        .unwrap()
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.trim().strip_prefix('<'))
+        .filter_map(|line| line.split('>').next())
+        .collect();
+    assert_eq!(
+        elements,
+        ["ele", "time", "course", "speed", "name", "desc", "fix", "sat", "hdop"]
+    );
+    let data2 = read(output.as_slice()).expect("Failed to read GPX");
+    assert_eq!(data, data2);
+}
@@ -0,0 +1,270 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::Write;
+
+use gettextrs::gettext as i18n;
+
+use crate::track::{FixType, GpsData, TrackPoint};
+
+/// Manufacturer code for the recorders not approved by the IGC.
+const MANUFACTURER: &str = "XXX";
+/// Fix accuracy in meters, for the header.
+const FIX_ACCURACY: u32 = 35;
+
+/// The IGC output options: the pilot and glider, and the recorder.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    pub pilot: Option<String>,
+    pub copilot: Option<String>,
+    pub glider_type: Option<String>,
+    pub glider_id: Option<String>,
+    pub competition_id: Option<String>,
+    pub competition_class: Option<String>,
+    /// The device id, from the device database.
+    pub device_id: Option<String>,
+    /// The device label, from the device database.
+    pub device_label: Option<String>,
+}
+
+/// The header value: no line breaks or control characters.
+fn header_value(value: Option<&String>) -> String {
+    value
+        .map(|v| v.chars().filter(|c| !c.is_control()).collect())
+        .unwrap_or_default()
+}
+
+/// The logger id: 3 alphanumeric characters from the device id.
+fn logger_id(device_id: Option<&String>) -> String {
+    let mut id: String = device_id
+        .map(|id| {
+            id.chars()
+                .filter(char::is_ascii_alphanumeric)
+                .take(3)
+                .collect::<String>()
+                .to_ascii_uppercase()
+        })
+        .unwrap_or_default();
+    while id.len() < 3 {
+        id.push('0');
+    }
+    id
+}
+
+/// Format `degrees` as IGC: (D)DDMMmmm and the hemisphere.
+fn format_position(degrees: f64, deg_width: usize, hemispheres: (char, char)) -> String {
+    let milli_minutes = (degrees.abs() * 60_000.0).round() as u64;
+    let deg = milli_minutes / 60_000;
+    let minutes = milli_minutes % 60_000;
+    let hemisphere = if degrees < 0.0 {
+        hemispheres.1
+    } else {
+        hemispheres.0
+    };
+    format!("{deg:0deg_width$}{minutes:05}{hemisphere}")
+}
+
+/// An altitude on 5 characters.
+fn format_altitude(altitude: Option<f64>) -> String {
+    let altitude = altitude
+        .map(|a| a.round().clamp(-9999.0, 99999.0) as i32)
+        .unwrap_or(0);
+    format!("{altitude:05}")
+}
+
+/// Return true if `point` has a GPS position. A barograph records
+/// none: its points are at 0,0.
+fn has_position(point: &TrackPoint) -> bool {
+    !matches!(point.fix, Some(FixType::None)) && (point.lat != 0.0 || point.lon != 0.0)
+}
+
+/// The B record of `point`, at `position` if it has none. A 3D fix is
+/// valid, otherwise there is no GPS altitude.
+fn fix_record(point: &TrackPoint, position: (f64, f64)) -> Option<String> {
+    let time = point.time?;
+    let valid = point.elevation.is_some()
+        && !matches!(point.fix, Some(FixType::None) | Some(FixType::TwoD));
+    let (lat, lon) = if has_position(point) {
+        (point.lat, point.lon)
+    } else {
+        position
+    };
+    Some(format!(
+        "B{}{}{}{}{}{}",
+        time.format("%H%M%S"),
+        format_position(lat, 2, ('N', 'S')),
+        format_position(lon, 3, ('E', 'W')),
+        if valid { 'A' } else { 'V' },
+        format_altitude(point.pressure_altitude),
+        format_altitude(if valid { point.elevation } else { None }),
+    ))
+}
+
+/// Write the track points of `data` as an IGC flight log. The header
+/// comes from `options`. Waypoints are ignored. The points without a
+/// position are at the last known one. The date is the UTC date of
+/// the first fix, and the times wrap past midnight: fail with
+/// `InvalidInput` if the points span 24 hours or more, as the times
+/// would be ambiguous.
+pub fn write<W: Write>(out: &mut W, data: &GpsData, options: &Options) -> io::Result<()> {
+    let times: Vec<_> = data.points().filter_map(|point| point.time).collect();
+    if let (Some(first), Some(last)) = (times.iter().min(), times.iter().max()) {
+        if *last - *first >= chrono::Duration::days(1) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                i18n("An IGC file holds less than 24 hours. Split the tracks by day."),
+            ));
+        }
+    }
+    let date = times
+        .first()
+        .map(|time| time.format("%d%m%y").to_string())
+        .unwrap_or_else(|| "000000".to_string());
+    let device = header_value(options.device_label.as_ref());
+    let barometric = data.points().any(|point| point.pressure_altitude.is_some());
+
+    write!(
+        out,
+        "A{MANUFACTURER}{} {}\r\n",
+        logger_id(options.device_id.as_ref()),
+        if device.is_empty() { "gpsami" } else { &device }
+    )?;
+    write!(out, "HFDTEDATE:{date},01\r\n")?;
+    write!(out, "HFFXA{FIX_ACCURACY:03}\r\n")?;
+    let headers = [
+        ("HFPLTPILOTINCHARGE", header_value(options.pilot.as_ref())),
+        ("HFCM2CREW2", header_value(options.copilot.as_ref())),
+        (
+            "HFGTYGLIDERTYPE",
+            header_value(options.glider_type.as_ref()),
+        ),
+        ("HFGIDGLIDERID", header_value(options.glider_id.as_ref())),
+        ("HFDTMGPSDATUM", "WGS84".to_string()),
+        ("HFRFWFIRMWAREVERSION", String::new()),
+        ("HFRHWHARDWAREVERSION", String::new()),
+        ("HFFTYFRTYPE", device.clone()),
+        ("HFGPSRECEIVER", String::new()),
+        (
+            "HFPRSPRESSALTSENSOR",
+            if barometric { device } else { String::new() },
+        ),
+        (
+            "HFCIDCOMPETITIONID",
+            header_value(options.competition_id.as_ref()),
+        ),
+        (
+            "HFCCLCOMPETITIONCLASS",
+            header_value(options.competition_class.as_ref()),
+        ),
+        ("HFALGALTGPS", "GEO".to_string()),
+        ("HFALPALTPRESSURE", "ISA".to_string()),
+    ];
+    for (key, value) in headers {
+        write!(out, "{key}:{value}\r\n")?;
+    }
+
+    let mut position = data
+        .points()
+        .find(|point| has_position(point))
+        .map(|point| (point.lat, point.lon))
+        .unwrap_or_default();
+    for point in data.points() {
+        if has_position(point) {
+            position = (point.lat, point.lon);
+        }
+        if let Some(record) = fix_record(point, position) {
+            write!(out, "{record}\r\n")?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_write() {
+    use crate::track::{test_data, test_point};
+
+    let point = TrackPoint {
+        elevation: Some(1230.4),
+        fix: Some(FixType::ThreeD),
+        ..test_point(0)
+    };
+    // From a barograph: no position, written at the last one.
+    let point2 = TrackPoint {
+        time: test_point(61).time,
+        pressure_altitude: Some(-12.0),
+        ..TrackPoint::default()
+    };
+    let data = test_data(vec![vec![point, TrackPoint::default(), point2]]);
+    let options = Options {
+        pilot: Some("Jane\r\nDoe".to_string()),
+        glider_type: Some("Ozone Rush".to_string()),
+        device_id: Some("baroiq".to_string()),
+        device_label: Some("Brauniger IQ".to_string()),
+        ..Options::default()
+    };
+
+    let mut output = vec![];
+    write(&mut output, &data, &options).expect("Failed to write IGC");
+    let igc = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = igc.split_terminator("\r\n").collect();
+    assert_eq!(lines[0], "AXXXBAR Brauniger IQ");
+    assert_eq!(lines[1], "HFDTEDATE:040524,01");
+    assert!(lines.contains(&"HFPLTPILOTINCHARGE:JaneDoe"));
+    assert!(lines.contains(&"HFGTYGLIDERTYPE:Ozone Rush"));
+    assert!(lines.contains(&"HFCM2CREW2:"));
+    assert!(lines.contains(&"HFFTYFRTYPE:Brauniger IQ"));
+    assert!(lines.contains(&"HFPRSPRESSALTSENSOR:Brauniger IQ"));
+    let fixes: Vec<&str> = lines
+        .iter()
+        .copied()
+        .filter(|l| l.starts_with('B'))
+        .collect();
+    assert_eq!(
+        fixes,
+        vec![
+            "B1200004530000N07330000WA0000001230",
+            "B1201014530000N07330000WV-001200000",
+        ]
+    );
+    for fix in fixes {
+        assert_eq!(fix.len(), 35);
+    }
+
+    // Past midnight UTC: the date is the first one and the times wrap.
+    let data = test_data(vec![vec![test_point(43_190), test_point(43_210)]]);
+    let mut output = vec![];
+    write(&mut output, &data, &options).expect("Failed to write IGC");
+    let igc = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = igc.split_terminator("\r\n").collect();
+    assert_eq!(lines[1], "HFDTEDATE:040524,01");
+    let times: Vec<&str> = lines
+        .iter()
+        .filter(|l| l.starts_with('B'))
+        .map(|l| &l[1..7])
+        .collect();
+    assert_eq!(times, vec!["235950", "000010"]);
+
+    // Over 24 hours.
+    let data = test_data(vec![vec![test_point(0), test_point(86_400)]]);
+    let err = write(&mut vec![], &data, &options).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let data = test_data(vec![vec![test_point(0), test_point(86_399)]]);
+    assert!(write(&mut vec![], &data, &options).is_ok());
+
+    // the minutes round up to the next degree.
+    assert_eq!(format_position(-151.999_999_9, 3, ('E', 'W')), "15200000W");
+}
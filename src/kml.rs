@@ -0,0 +1,272 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::Write;
+
+use chrono::SecondsFormat;
+use quick_xml::escape::escape;
+
+use crate::track::{GpsData, Track, TrackPoint, TrackSegment};
+
+/// Line style for the tracks. KML colors are aabbggrr.
+const TRACK_COLOR: &str = "ff0000ff";
+const TRACK_WIDTH: u32 = 3;
+/// Icon for the waypoints.
+const WAYPOINT_ICON: &str = "http://maps.google.com/mapfiles/kml/pushpin/ylw-pushpin.png";
+
+/// The KML coordinates of `point`: lon,lat[,alt]
+fn coordinates(point: &TrackPoint) -> String {
+    match point.altitude() {
+        Some(ele) => format!("{:.9},{:.9},{ele:.3}", point.lon, point.lat),
+        None => format!("{:.9},{:.9}", point.lon, point.lat),
+    }
+}
+
+/// The altitude mode element with altitudes, to not clamp them to the
+/// ground. Nothing without, for the default, on the ground.
+fn altitude_mode(has_altitude: bool) -> &'static str {
+    if has_altitude {
+        "<altitudeMode>absolute</altitudeMode>"
+    } else {
+        ""
+    }
+}
+
+/// The Point geometry of `point`.
+fn point_geometry(point: &TrackPoint) -> String {
+    format!(
+        "<Point>{}<coordinates>{}</coordinates></Point>",
+        altitude_mode(point.altitude().is_some()),
+        coordinates(point)
+    )
+}
+
+/// Write the time span of `track`, if the points have a time.
+fn write_time_span<W: Write>(out: &mut W, track: &Track) -> io::Result<()> {
+    let mut times = track
+        .segments
+        .iter()
+        .flat_map(|segment| segment.points.iter())
+        .filter_map(|point| point.time);
+    if let Some(begin) = times.next() {
+        let end = times.next_back().unwrap_or(begin);
+        writeln!(out, "        <TimeSpan>")?;
+        writeln!(
+            out,
+            "          <begin>{}</begin>",
+            begin.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+        )?;
+        writeln!(
+            out,
+            "          <end>{}</end>",
+            end.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+        )?;
+        writeln!(out, "        </TimeSpan>")?;
+    }
+    Ok(())
+}
+
+/// Write the geometry of `segment`: a line, or a point if it has only
+/// one, as a line needs two. Nothing if it is empty. The altitudes are
+/// absolute if all the points have one.
+fn write_segment<W: Write>(out: &mut W, segment: &TrackSegment) -> io::Result<()> {
+    match segment.points.as_slice() {
+        [] => Ok(()),
+        [point] => writeln!(out, "          {}", point_geometry(point)),
+        points => {
+            writeln!(out, "          <LineString>")?;
+            writeln!(out, "            <tessellate>1</tessellate>")?;
+            if points.iter().all(|point| point.altitude().is_some()) {
+                writeln!(out, "            {}", altitude_mode(true))?;
+            }
+            writeln!(out, "            <coordinates>")?;
+            for point in points {
+                writeln!(out, "              {}", coordinates(point))?;
+            }
+            writeln!(out, "            </coordinates>")?;
+            writeln!(out, "          </LineString>")
+        }
+    }
+}
+
+/// Write `data` as KML 2.2. Waypoints are placemarks, and each track
+/// is a placemark with a line per segment. Tracks without points are
+/// skipped.
+pub fn write<W: Write>(out: &mut W, data: &GpsData) -> io::Result<()> {
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(out, "<kml xmlns=\"http://www.opengis.net/kml/2.2\">")?;
+    writeln!(out, "  <Document>")?;
+    writeln!(out, "    <name>gpsami</name>")?;
+    writeln!(out, "    <Style id=\"track\">")?;
+    writeln!(out, "      <LineStyle>")?;
+    writeln!(out, "        <color>{TRACK_COLOR}</color>")?;
+    writeln!(out, "        <width>{TRACK_WIDTH}</width>")?;
+    writeln!(out, "      </LineStyle>")?;
+    writeln!(out, "    </Style>")?;
+    writeln!(out, "    <Style id=\"waypoint\">")?;
+    writeln!(out, "      <IconStyle>")?;
+    writeln!(out, "        <Icon><href>{WAYPOINT_ICON}</href></Icon>")?;
+    writeln!(out, "      </IconStyle>")?;
+    writeln!(out, "    </Style>")?;
+
+    if !data.waypoints.is_empty() {
+        writeln!(out, "    <Folder>")?;
+        writeln!(out, "      <name>Waypoints</name>")?;
+        for waypoint in &data.waypoints {
+            writeln!(out, "      <Placemark>")?;
+            if let Some(ref name) = waypoint.name {
+                writeln!(out, "        <name>{}</name>", escape(name.as_str()))?;
+            }
+            if let Some(ref desc) = waypoint.description {
+                writeln!(
+                    out,
+                    "        <description>{}</description>",
+                    escape(desc.as_str())
+                )?;
+            }
+            if let Some(time) = waypoint.point.time {
+                writeln!(
+                    out,
+                    "        <TimeStamp><when>{}</when></TimeStamp>",
+                    time.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+                )?;
+            }
+            writeln!(out, "        <styleUrl>#waypoint</styleUrl>")?;
+            writeln!(out, "        {}", point_geometry(&waypoint.point))?;
+            writeln!(out, "      </Placemark>")?;
+        }
+        writeln!(out, "    </Folder>")?;
+    }
+
+    if data.tracks.iter().any(|track| !track.is_empty()) {
+        writeln!(out, "    <Folder>")?;
+        writeln!(out, "      <name>Tracks</name>")?;
+        for (idx, track) in data.tracks.iter().enumerate() {
+            if track.is_empty() {
+                continue;
+            }
+            writeln!(out, "      <Placemark>")?;
+            match track.name {
+                Some(ref name) => writeln!(out, "        <name>{}</name>", escape(name.as_str()))?,
+                None => writeln!(out, "        <name>Track {}</name>", idx + 1)?,
+            }
+            write_time_span(out, track)?;
+            writeln!(out, "        <styleUrl>#track</styleUrl>")?;
+            writeln!(out, "        <MultiGeometry>")?;
+            for segment in &track.segments {
+                write_segment(out, segment)?;
+            }
+            writeln!(out, "        </MultiGeometry>")?;
+            writeln!(out, "      </Placemark>")?;
+        }
+        writeln!(out, "    </Folder>")?;
+    }
+
+    writeln!(out, "  </Document>")?;
+    writeln!(out, "</kml>")
+}
+
+#[test]
+fn test_write() {
+    use quick_xml::events::Event;
+
+    use crate::track::{test_point, Waypoint};
+
+    let point = test_point(0);
+    let data = GpsData {
+        tracks: vec![
+            Track {
+                name: None,
+                segments: vec![
+                    TrackSegment {
+                        points: vec![point.clone(), point.clone()],
+                    },
+                    TrackSegment {
+                        points: vec![point.clone()],
+                    },
+                    TrackSegment { points: vec![] },
+                ],
+            },
+            Track {
+                name: Some("Empty".to_string()),
+                segments: vec![TrackSegment { points: vec![] }],
+            },
+        ],
+        waypoints: vec![Waypoint {
+            name: Some("Home & away".to_string()),
+            description: None,
+            point,
+        }],
+    };
+
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write KML");
+    let kml = String::from_utf8(output).unwrap();
+    assert!(kml.contains("<name>Home &amp; away</name>"));
+    assert!(kml.contains("<name>Track 1</name>"));
+    assert!(kml.contains(
+        "<Point><altitudeMode>absolute</altitudeMode>\
+         <coordinates>-73.500000000,45.500000000,30.500</coordinates></Point>"
+    ));
+    assert!(kml.contains("            <altitudeMode>absolute</altitudeMode>\n"));
+    assert!(kml.contains("<begin>2024-05-04T12:00:00Z</begin>"));
+
+    // Check it is well formed.
+    let mut reader = quick_xml::reader::Reader::from_str(&kml);
+    let mut placemarks = 0;
+    let mut lines = 0;
+    let mut points = 0;
+    loop {
+        match reader.read_event().expect("Invalid KML") {
+            Event::Start(ref e) if e.name().as_ref() == b"Placemark" => placemarks += 1,
+            Event::Start(ref e) if e.name().as_ref() == b"LineString" => lines += 1,
+            Event::Start(ref e) if e.name().as_ref() == b"Point" => points += 1,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    assert_eq!(placemarks, 2);
+    assert_eq!(lines, 1);
+    // the waypoint and the single point segment.
+    assert_eq!(points, 2);
+    assert!(!kml.contains("Empty"));
+
+    // Without elevation, on the ground.
+    let point = TrackPoint {
+        elevation: None,
+        ..test_point(0)
+    };
+    let data = GpsData {
+        tracks: vec![Track {
+            name: None,
+            segments: vec![
+                TrackSegment {
+                    points: vec![point.clone()],
+                },
+                TrackSegment {
+                    points: vec![test_point(0), point],
+                },
+            ],
+        }],
+        waypoints: vec![],
+    };
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write KML");
+    let kml = String::from_utf8(output).unwrap();
+    assert!(kml.contains("<Point><coordinates>-73.500000000,45.500000000</coordinates></Point>"));
+    assert!(!kml.contains("altitudeMode"));
+}
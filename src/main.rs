@@ -28,18 +28,48 @@ mod utils;
 
 use mgapplication::MgApplication;
 
+mod cli;
 mod config;
+mod csv;
 mod devices;
 mod drivers;
+mod export;
 mod file_chooser_button;
+mod fit;
+mod geojson;
 mod gpsbabel;
+mod gpx;
+mod igc;
+mod kml;
 mod mgapplication;
+mod mtk;
+mod nmea;
+mod settings;
+#[cfg(test)]
+mod simulator;
+mod split;
 mod static_resources;
+mod tcx;
+mod track;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Format {
     None,
     Gpx,
     Kml,
+    GeoJson,
+    Tcx,
+    Fit,
+    Csv,
+    Nmea,
+    Igc,
+}
+
+/// Init the locale and the text domain.
+fn init_i18n() {
+    setlocale(LocaleCategory::LcAll, "");
+    bindtextdomain("gpsami", config::LOCALEDIR).expect("Coudln't bind textdomain");
+    textdomain("gpsami").expect("Couldn't find text domain");
 }
 
 /// Init Gtk and stuff.
@@ -58,15 +88,21 @@ fn init() {
 
         file_chooser_button::FileChooserButton::static_type();
 
-        setlocale(LocaleCategory::LcAll, "");
-        bindtextdomain("gpsami", config::LOCALEDIR).expect("Coudln't bind textdomain");
-        textdomain("gpsami").expect("Couldn't find text domain");
+        init_i18n();
 
         static_resources::init().expect("Could not load resources");
     });
 }
 
 fn main() {
+    // Command line mode: don't initialize Gtk.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|arg| cli::is_command(arg)).unwrap_or(false) {
+        env_logger::init();
+        init_i18n();
+        std::process::exit(cli::run(&args));
+    }
+
     init();
 
     let gapp = adw::Application::new(
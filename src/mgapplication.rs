@@ -23,32 +23,230 @@ use gtk4::prelude::*;
 use gudev::prelude::DeviceExt;
 use gudev::prelude::*;
 
+use chrono::{DateTime, Local, Utc};
 use std::cell::RefCell;
 use std::path;
 use std::rc::Rc;
-use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
+use crate::csv;
 use crate::devices;
 use crate::drivers;
+use crate::export;
 use crate::file_chooser_button::FileChooserButton;
+use crate::igc;
+use crate::settings;
+use crate::split::{self, Split};
+use crate::track::GpsData;
 use crate::utils;
 use crate::Format;
 
+#[derive(Clone, Copy, PartialEq)]
 enum UiState {
     Idle,
     InProgress,
 }
 
 pub enum MgAction {
-    RescanDevices,
+    /// A port of this type was plugged.
+    PortAdded(drivers::PortType, drivers::Port),
+    /// The port with this path was unplugged.
+    PortRemoved(path::PathBuf),
     ModelChanged(String),
     PortChanged(String),
     StartErase,
     DoneErase(drivers::Result<()>),
+    StartShutoff,
+    DoneShutoff(drivers::Result<()>),
+    Progress(drivers::Progress),
+    Cancel,
     StartDownload,
-    DoneDownload(drivers::Result<()>),
+    /// The download is done, with the files written.
+    DoneDownload(drivers::Result<Vec<path::PathBuf>>),
+    SetLogging(bool),
+    DoneSetLogging(bool, drivers::Result<()>),
+    /// The logging state, from the query that `CancelToken` cancels.
+    DoneQueryLogging(drivers::CancelToken, drivers::Result<bool>),
     SetOutputDir(path::PathBuf),
+    SetAutoSave(bool),
+    SetNewOnly(bool),
+    /// Set the file name template. Empty for the default.
+    SetTemplate(String),
+    /// Also save in this format, or not.
+    SetExtraFormat(Format, bool),
+    /// Split the downloads with this mode, `none` to not split.
+    SetSplitMode(String),
+    /// Record the time of the last point downloaded from the device.
+    SetLastPoint(String, DateTime<Utc>),
+    SetOutputFormat(Format),
+    SetCsvOptions(csv::Options),
+    /// Set the IGC header value with this key. Empty to remove it.
+    SetIgcSetting(&'static str, String),
+    /// gpsbabel was checked for the devices using it.
+    GpsBabelChecked(drivers::Result<()>),
+    ShowGpsBabelError,
+}
+
+/// The keys of the IGC header values in the settings, also the
+/// `igc_<key>_entry` widgets.
+const IGC_KEYS: &[&str] = &[
+    "pilot",
+    "copilot",
+    "glider_type",
+    "glider_id",
+    "competition_id",
+    "competition_class",
+];
+
+/// The time limit to query the logging state. Short, as nothing can
+/// tell that the device answers on the port.
+const LOGGING_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The default file name template to save automatically.
+const DEFAULT_TEMPLATE: &str = "{model}_{start:%Y-%m-%d_%H%M}";
+
+/// Where to save the download.
+enum Destination {
+    /// This file.
+    File(path::PathBuf),
+    /// A new file in `dir` named after `template`.
+    Auto {
+        dir: path::PathBuf,
+        template: String,
+        model: String,
+    },
+}
+
+/// The error for an invalid file name `template`.
+fn invalid_template(template: &str) -> drivers::Error {
+    drivers::Error::Failed(format!(
+        "{}: {template}",
+        i18n("Invalid file name template")
+    ))
+}
+
+/// The output folder in the settings `prefs`.
+fn output_dir_settings(prefs: &glib::KeyFile) -> Option<path::PathBuf> {
+    prefs
+        .string("output", "dir")
+        .ok()
+        .map(|dir| path::PathBuf::from(dir.as_str()))
+        .filter(|dir| !dir.as_os_str().is_empty())
+}
+
+/// The file name template in the settings `prefs`.
+fn template_settings(prefs: &glib::KeyFile) -> String {
+    prefs
+        .string("output", "template")
+        .map(|template| template.to_string())
+        .unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string())
+}
+
+impl Destination {
+    /// A new file in `dir` named after `template` for `model`. Fail if
+    /// there is no folder or the template is invalid, before anything
+    /// is downloaded.
+    fn auto(dir: &path::Path, template: String, model: String) -> drivers::Result<Destination> {
+        if dir.as_os_str().is_empty() {
+            return Err(drivers::Error::Failed(i18n("No output folder selected.")));
+        }
+        if export::expand_template(&template, &model, &Local::now()).is_none() {
+            return Err(invalid_template(&template));
+        }
+        Ok(Destination::Auto {
+            dir: dir.to_path_buf(),
+            template,
+            model,
+        })
+    }
+
+    /// Check that the files can be created, before downloading.
+    fn check(&self) -> drivers::Result<()> {
+        match *self {
+            Destination::File(ref file) => export::check_writable(file),
+            Destination::Auto { ref dir, .. } => export::check_writable(&dir.join("gpsami")),
+        }
+    }
+
+    /// Write each of `pieces` in `formats`. Return the files written.
+    fn write(
+        &self,
+        pieces: &[GpsData],
+        formats: &[Format],
+        options: &export::Options,
+    ) -> drivers::Result<Vec<path::PathBuf>> {
+        match *self {
+            Destination::File(ref file) => export::write_pieces(file, formats, options, pieces),
+            Destination::Auto {
+                ref dir,
+                ref template,
+                ref model,
+            } => export::write_unique_pieces(formats, options, pieces, |piece| {
+                let start = piece
+                    .points()
+                    .find_map(|point| point.time)
+                    .unwrap_or_else(Utc::now)
+                    .with_timezone(&Local);
+                let name = export::expand_template(template, model, &start)
+                    .ok_or_else(|| invalid_template(template))?;
+                Ok(export::unique_path(dir, &name, formats)?)
+            }),
+        }
+    }
+}
+
+/// A download to do.
+struct DownloadRequest {
+    cap: devices::Capability,
+    erase: bool,
+    shutoff: bool,
+    destination: Destination,
+    formats: Vec<Format>,
+    options: export::Options,
+    split: Option<Split>,
+    /// The key of the device, to record the last point downloaded.
+    device_key: Option<String>,
+    /// Only keep the points after this time.
+    since: Option<DateTime<Utc>>,
+}
+
+/// The CSV delimiters offered in the save dialog: choice id and
+/// delimiter.
+const DELIMITERS: &[(&str, char)] = &[("comma", ','), ("semicolon", ';'), ("tab", '\t')];
+
+/// The ids of the CSV choices in the save dialog. The columns are
+/// `csv-col-` followed by the column id.
+const CSV_TIME_FORMAT_CHOICE: &str = "csv-time-format";
+const CSV_DELIMITER_CHOICE: &str = "csv-delimiter";
+
+fn csv_column_choice(column: csv::Column) -> String {
+    format!("csv-col-{}", column.id())
+}
+
+/// The label of a CSV column in the save dialog.
+fn csv_column_label(column: csv::Column) -> String {
+    match column {
+        csv::Column::Time => i18n("Time"),
+        csv::Column::Latitude => i18n("Latitude"),
+        csv::Column::Longitude => i18n("Longitude"),
+        csv::Column::Elevation => i18n("Elevation"),
+        csv::Column::Speed => i18n("Speed"),
+        csv::Column::Course => i18n("Course"),
+        csv::Column::Hdop => i18n("HDOP"),
+        csv::Column::Satellites => i18n("Satellites"),
+        csv::Column::Segment => i18n("Segment"),
+    }
+}
+
+/// The reason of the error to report to the user.
+fn error_reason(err: &drivers::Error) -> String {
+    match *err {
+        drivers::Error::Timeout => i18n(
+            "The device stopped responding. Check that it is turned on and that the right port is selected.",
+        ),
+        _ => err.to_string(),
+    }
 }
 
 fn post_event(sender: &Sender<MgAction>, action: MgAction) {
@@ -63,32 +261,70 @@ fn post_event(sender: &Sender<MgAction>, action: MgAction) {
 pub struct MgApplication {
     gapp: adw::Application,
     window_id: u32,
-    content_box: gtk::Box,
+    main_grid: gtk::Grid,
+    button_box: gtk::Box,
+    progress_box: gtk::Box,
+    progress_bar: gtk::ProgressBar,
+    cancel_btn: gtk::Button,
+    auto_save_checkbtn: gtk::CheckButton,
+    new_only_checkbtn: gtk::CheckButton,
+    new_only_warning: gtk::Label,
+    extra_formats_btn: gtk::MenuButton,
     erase_checkbtn: gtk::CheckButton,
+    shutoff_checkbtn: gtk::CheckButton,
+    logging_box: gtk::Box,
+    logging_switch: gtk::Switch,
+    logging_handler: glib::SignalHandlerId,
     model_combo: gtk::ComboBox,
     model_store: gtk::ListStore,
     port_combo: gtk::ComboBox,
     port_store: gtk::ListStore,
     toast_overlay: adw::ToastOverlay,
+    gpsbabel_banner: adw::Banner,
+    /// Why gpsbabel can't drive some devices, if it can't.
+    gpsbabel_error: Option<drivers::Error>,
 
     device_manager: devices::Manager,
+    /// The ports where a device was recognized, to only select it once.
+    detected_ports: Vec<path::PathBuf>,
+    /// The ports plugged during the operation in progress, to select
+    /// once it is done.
+    plugged_ports: Vec<String>,
     prefs_store: glib::KeyFile,
 
     output_dest_dir: path::PathBuf,
+    /// The token to cancel the operation in progress, if it can be.
+    cancel: Option<drivers::CancelToken>,
+    /// The token to cancel the logging state query running, if any.
+    logging_query: Option<drivers::CancelToken>,
+    state: UiState,
     sender: Sender<MgAction>,
 }
 
 impl MgApplication {
     pub fn new(gapp: &adw::Application) -> Rc<RefCell<Self>> {
         let builder = gtk::Builder::from_resource("/net/figuiere/gpsami/mgwindow.ui");
-        let content_box = builder.object::<gtk::Box>("content_box").unwrap();
+        let window_box = builder.object::<gtk::Box>("window_box").unwrap();
+        let gpsbabel_banner: adw::Banner = builder.object("gpsbabel_banner").unwrap();
         let window = gtk::ApplicationWindow::builder()
             .application(gapp)
             .default_height(400)
             .default_width(400)
-            .child(&content_box)
+            .child(&window_box)
             .build();
+        let main_grid: gtk::Grid = builder.object("main_grid").unwrap();
+        let button_box: gtk::Box = builder.object("box1").unwrap();
+        let progress_box: gtk::Box = builder.object("progress_box").unwrap();
+        let progress_bar: gtk::ProgressBar = builder.object("progress_bar").unwrap();
+        let cancel_btn: gtk::Button = builder.object("cancel_btn").unwrap();
+        let auto_save_checkbtn: gtk::CheckButton = builder.object("auto_save_checkbtn").unwrap();
+        let new_only_checkbtn: gtk::CheckButton = builder.object("new_only_checkbtn").unwrap();
+        let new_only_warning: gtk::Label = builder.object("new_only_warning").unwrap();
+        let extra_formats_btn: gtk::MenuButton = builder.object("extra_formats_btn").unwrap();
         let erase_checkbtn: gtk::CheckButton = builder.object("erase_checkbtn").unwrap();
+        let shutoff_checkbtn: gtk::CheckButton = builder.object("shutoff_checkbtn").unwrap();
+        let logging_box: gtk::Box = builder.object("logging_box").unwrap();
+        let logging_switch: gtk::Switch = builder.object("logging_switch").unwrap();
         let model_combo: gtk::ComboBox = builder.object("model_combo").unwrap();
         let port_combo: gtk::ComboBox = builder.object("port_combo").unwrap();
         let output_dir_chooser: FileChooserButton = builder.object("output_dir_chooser").unwrap();
@@ -110,6 +346,19 @@ impl MgApplication {
                 post_event(&sender2, MgAction::PortChanged(id.to_string()));
             }
         });
+        let sender2 = sender.clone();
+        gpsbabel_banner.connect_button_clicked(move |_| {
+            post_event(&sender2, MgAction::ShowGpsBabelError);
+        });
+        let sender2 = sender.clone();
+        cancel_btn.connect_clicked(move |_| {
+            post_event(&sender2, MgAction::Cancel);
+        });
+        let sender2 = sender.clone();
+        let logging_handler = logging_switch.connect_state_set(move |_, state| {
+            post_event(&sender2, MgAction::SetLogging(state));
+            glib::Propagation::Stop
+        });
         let dload_action = gio::SimpleAction::new("download", None);
         let sender2 = sender.clone();
         dload_action.connect_activate(move |_, _| {
@@ -126,6 +375,14 @@ impl MgApplication {
         erase_action.set_enabled(false);
         window.add_action(&erase_action);
 
+        let shutoff_action = gio::SimpleAction::new("shutoff", None);
+        let sender2 = sender.clone();
+        shutoff_action.connect_activate(move |_, _| {
+            post_event(&sender2, MgAction::StartShutoff);
+        });
+        shutoff_action.set_enabled(false);
+        window.add_action(&shutoff_action);
+
         output_dir_chooser.connect_local(
             "file-set",
             true,
@@ -148,29 +405,63 @@ impl MgApplication {
 
         let device_manager = devices::Manager::new();
         let sender2 = sender.clone();
-        device_manager
-            .gudev_client
-            .connect_uevent(move |_, action, device| {
+        if let Some(client) = &device_manager.gudev_client {
+            client.connect_uevent(move |_, action, device| {
                 if let Some(subsystem) = device.subsystem() {
                     log::debug!("received event {action} {subsystem}");
                 }
-                post_event(&sender2, MgAction::RescanDevices);
+                match action {
+                    "add" => {
+                        if let Some((port_type, port)) = devices::port_from_udev(device) {
+                            post_event(&sender2, MgAction::PortAdded(port_type, port));
+                        }
+                    }
+                    "remove" => {
+                        if let Some(path) = device.device_file() {
+                            post_event(
+                                &sender2,
+                                MgAction::PortRemoved(path::PathBuf::from(path.as_str())),
+                            );
+                        }
+                    }
+                    _ => {}
+                }
             });
+        }
 
         let app = MgApplication {
             gapp: gapp.clone(),
             window_id: window.id(),
-            content_box,
+            main_grid,
+            button_box,
+            progress_box,
+            progress_bar,
+            cancel_btn,
+            auto_save_checkbtn: auto_save_checkbtn.clone(),
+            new_only_checkbtn: new_only_checkbtn.clone(),
+            new_only_warning,
+            extra_formats_btn,
             erase_checkbtn,
+            shutoff_checkbtn,
+            logging_box,
+            logging_switch,
+            logging_handler,
             model_combo,
             model_store: gtk::ListStore::new(&[glib::Type::STRING, glib::Type::STRING]),
             port_combo,
             port_store: gtk::ListStore::new(&[glib::Type::STRING, glib::Type::STRING]),
             toast_overlay,
+            gpsbabel_banner,
+            gpsbabel_error: None,
 
             device_manager,
+            detected_ports: vec![],
+            plugged_ports: vec![],
             prefs_store: glib::KeyFile::new(),
             output_dest_dir: path::PathBuf::new(),
+            cancel: None,
+            logging_query: None,
+            state: UiState::Idle,
             sender,
         };
 
@@ -195,24 +486,111 @@ impl MgApplication {
         if me.borrow_mut().load_settings().is_err() {
             log::error!("Error loading settings");
         }
+        let timeouts = me.borrow().timeouts_settings();
+        me.borrow_mut().device_manager.set_timeouts(timeouts);
+        let gpsbabel = me.borrow().prefs_store.string("gpsbabel", "path");
+        if let Ok(gpsbabel) = gpsbabel {
+            me.borrow_mut()
+                .device_manager
+                .set_gpsbabel(path::Path::new(gpsbabel.as_str()));
+        }
 
-        if let Ok(output_dir) = me.borrow().prefs_store.string("output", "dir") {
-            output_dir_chooser.set_filename(path::PathBuf::from(output_dir.as_str()));
+        let output_dir = output_dir_settings(&me.borrow().prefs_store);
+        if let Some(output_dir) = output_dir {
+            // doesn't emit "file-set".
+            output_dir_chooser.set_filename(&output_dir);
+            me.borrow_mut().set_output_destination_dir(&output_dir);
+        }
+        let template_entry: gtk::Entry = builder.object("template_entry").unwrap();
+        template_entry.set_placeholder_text(Some(DEFAULT_TEMPLATE));
+        if let Ok(template) = me.borrow().prefs_store.string("output", "template") {
+            template_entry.set_text(&template);
+        }
+        MgApplication::check_template_entry(&template_entry);
+        let sender = me.borrow().sender.clone();
+        template_entry.connect_changed(move |entry| {
+            MgApplication::check_template_entry(entry);
+            post_event(
+                &sender,
+                MgAction::SetTemplate(entry.text().trim().to_string()),
+            );
+        });
+        let auto_save = me
+            .borrow()
+            .prefs_store
+            .boolean("output", "auto_save")
+            .unwrap_or(false);
+        auto_save_checkbtn.set_active(auto_save);
+        let sender = me.borrow().sender.clone();
+        auto_save_checkbtn.connect_toggled(move |btn| {
+            post_event(&sender, MgAction::SetAutoSave(btn.is_active()));
+        });
+        let new_only = me
+            .borrow()
+            .prefs_store
+            .boolean("output", "new_only")
+            .unwrap_or(false);
+        new_only_checkbtn.set_active(new_only);
+        let sender = me.borrow().sender.clone();
+        new_only_checkbtn.connect_toggled(move |btn| {
+            post_event(&sender, MgAction::SetNewOnly(btn.is_active()));
+        });
+        let extra_formats_list: gtk::Box = builder.object("extra_formats_list").unwrap();
+        let extra_formats = me.borrow().extra_formats_settings();
+        for &format in export::FORMATS {
+            let check = gtk::CheckButton::with_label(format.label());
+            check.set_active(extra_formats.contains(&format));
+            let sender = me.borrow().sender.clone();
+            check.connect_toggled(move |btn| {
+                post_event(&sender, MgAction::SetExtraFormat(format, btn.is_active()));
+            });
+            extra_formats_list.append(&check);
+        }
+        me.borrow().update_extra_formats_btn(&extra_formats);
+        let split_combo: gtk::ComboBox = builder.object("split_combo").unwrap();
+        let split_store = gtk::ListStore::new(&[glib::Type::STRING, glib::Type::STRING]);
+        utils::setup_text_combo(&split_combo, &split_store);
+        utils::add_text_row(&split_store, "none", &i18n("One file"));
+        utils::add_text_row(&split_store, "gap", &i18n("One file per trip"));
+        utils::add_text_row(&split_store, "day", &i18n("One file per day"));
+        utils::add_text_row(&split_store, "segment", &i18n("One file per segment"));
+        let split_mode = me.borrow().split_mode();
+        split_combo.set_active_id(Some(&split_mode));
+        let sender = me.borrow().sender.clone();
+        split_combo.connect_changed(move |combo| {
+            if let Some(mode) = combo.active_id() {
+                post_event(&sender, MgAction::SetSplitMode(mode.to_string()));
+            }
+        });
+        for &key in IGC_KEYS {
+            let entry: gtk::Entry = builder.object(&format!("igc_{key}_entry")).unwrap();
+            if let Ok(value) = me.borrow().prefs_store.string("igc", key) {
+                entry.set_text(&value);
+            }
+            let sender = me.borrow().sender.clone();
+            entry.connect_changed(move |entry| {
+                post_event(
+                    &sender,
+                    MgAction::SetIgcSetting(key, entry.text().trim().to_string()),
+                );
+            });
         }
         me
     }
 
     fn do_download(&mut self) {
-        let device = self.device_manager.get_device();
-        if device.is_none() {
-            log::debug!("nodriver");
-            post_event(
-                &self.sender,
-                MgAction::DoneDownload(Err(drivers::Error::NoDriver)),
-            );
+        let device = match self.device_manager.get_device() {
+            Ok(device) => device,
+            Err(err) => {
+                log::debug!("nodriver {err}");
+                post_event(&self.sender, MgAction::DoneDownload(Err(err)));
+                return;
+            }
+        };
+        if self.auto_save_checkbtn.is_active() {
+            self.do_auto_download(device);
             return;
         }
-        let device = device.unwrap();
 
         let window = self.gapp.window_by_id(self.window_id);
         let chooser = gtk::FileChooserDialog::new(
@@ -228,14 +606,54 @@ impl MgApplication {
         if let Ok(output_dir) = self.prefs_store.string("output", "dir") {
             let _ = chooser.set_current_folder(Some(&gio::File::for_path(output_dir.as_str())));
         }
+        let last_format = self.last_format();
+        let filters: Vec<(gtk::FileFilter, Format)> = export::FORMATS
+            .iter()
+            .filter_map(|&format| {
+                let extension = format.extension()?;
+                let filter = gtk::FileFilter::new();
+                filter.set_name(Some(format.label()));
+                filter.add_pattern(&format!("*.{extension}"));
+                chooser.add_filter(&filter);
+                if format == last_format {
+                    chooser.set_filter(&filter);
+                }
+                Some((filter, format))
+            })
+            .collect();
+        // the CSV options only show with the CSV filter.
+        let csv_options = Rc::new(RefCell::new(self.csv_settings()));
+        if let Some((csv_filter, _)) = filters.iter().find(|(_, format)| *format == Format::Csv) {
+            Self::show_csv_choices(&chooser, &csv_options, last_format == Format::Csv);
+            chooser.connect_filter_notify(glib::clone!(
+                #[strong]
+                csv_filter,
+                #[strong]
+                csv_options,
+                move |chooser| {
+                    let show = chooser.filter().as_ref() == Some(&csv_filter);
+                    Self::show_csv_choices(chooser, &csv_options, show);
+                }
+            ));
+        }
+        let igc_options = self.igc_settings();
+        let extra_formats = self.extra_formats_settings();
+        let split = self.split_settings();
+        let device_key = self.device_manager.device_key();
+        let since = self.new_points_since(device_key.as_deref());
         chooser.show();
 
+        let cap = self.device_manager.capability().unwrap_or_default();
         let erase = self.erase_checkbtn.is_active();
+        let shutoff = self.shutoff_checkbtn.is_active();
+        let cancel = self.new_cancel_token();
         chooser.connect_response(glib::clone!(
             #[strong(rename_to = sender)]
             self.sender,
             #[strong]
             device,
+            #[strong]
+            cancel,
             move |chooser, r| {
                 log::debug!("Response {r}");
 
@@ -243,11 +661,45 @@ impl MgApplication {
                 match r {
                     gtk::ResponseType::Ok => {
                         if let Some(output_file) = chooser.file().and_then(|f| f.path()) {
+                            // The extension decides, otherwise the filter.
+                            let filter = chooser.filter();
+                            let format = Format::from_path(&output_file)
+                                .or_else(|| {
+                                    filters
+                                        .iter()
+                                        .find(|(f, _)| Some(f) == filter.as_ref())
+                                        .map(|(_, format)| *format)
+                                })
+                                .unwrap_or(export::FORMATS[0]);
+                            post_event(&sender, MgAction::SetOutputFormat(format));
+                            let options = export::Options {
+                                csv: Self::csv_choices(chooser, &csv_options.borrow()),
+                                igc: igc_options.clone(),
+                            };
+                            if format == Format::Csv {
+                                post_event(&sender, MgAction::SetCsvOptions(options.csv.clone()));
+                            }
+                            let mut formats = vec![format];
+                            formats.extend(extra_formats.iter().filter(|&&f| f != format));
+                            let request = DownloadRequest {
+                                cap: cap.clone(),
+                                erase,
+                                shutoff,
+                                destination: Destination::File(export::with_extension(
+                                    &output_file,
+                                    format,
+                                )),
+                                formats,
+                                options,
+                                split,
+                                device_key: device_key.clone(),
+                                since,
+                            };
                             Self::really_do_download(
                                 sender.clone(),
                                 device.clone(),
-                                erase,
-                                output_file,
+                                cancel.clone(),
+                                request,
                             );
                         }
                     }
@@ -262,36 +714,129 @@ impl MgApplication {
         ));
     }
 
+    /// Mark the template in `entry` if it is invalid. Empty is the
+    /// default.
+    fn check_template_entry(entry: &gtk::Entry) {
+        let template = entry.text();
+        let template = template.trim();
+        if template.is_empty()
+            || export::expand_template(template, "model", &Local::now()).is_some()
+        {
+            entry.remove_css_class("error");
+        } else {
+            entry.add_css_class("error");
+        }
+    }
+
+    /// The format chosen last in the save dialog.
+    fn last_format(&self) -> Format {
+        self.prefs_store
+            .string("output", "format")
+            .ok()
+            .and_then(|id| Format::from_id(id.as_str()))
+            .unwrap_or(export::FORMATS[0])
+    }
+
+    /// Download into the output folder without asking, naming the file
+    /// after the template. The extension of the template decides the
+    /// format, otherwise the last one chosen.
+    fn do_auto_download(&mut self, device: drivers::DriverRef) {
+        let template = template_settings(&self.prefs_store);
+        let model = self
+            .device_manager
+            .device_desc()
+            .map(|desc| desc.id.clone())
+            .unwrap_or_default();
+        let format = Format::from_path(path::Path::new(&template)).unwrap_or(self.last_format());
+        // fail before the device is erased.
+        let destination = match Destination::auto(&self.output_dest_dir, template, model) {
+            Ok(destination) => destination,
+            Err(err) => {
+                post_event(&self.sender, MgAction::DoneDownload(Err(err)));
+                return;
+            }
+        };
+        let mut formats = vec![format];
+        formats.extend(
+            self.extra_formats_settings()
+                .into_iter()
+                .filter(|&f| f != format),
+        );
+        let device_key = self.device_manager.device_key();
+        let request = DownloadRequest {
+            cap: self.device_manager.capability().unwrap_or_default(),
+            erase: self.erase_checkbtn.is_active(),
+            shutoff: self.shutoff_checkbtn.is_active(),
+            destination,
+            formats,
+            options: export::Options {
+                csv: self.csv_settings(),
+                igc: self.igc_settings(),
+            },
+            split: self.split_settings(),
+            since: self.new_points_since(device_key.as_deref()),
+            device_key,
+        };
+        let cancel = self.new_cancel_token();
+        Self::really_do_download(self.sender.clone(), device, cancel, request);
+    }
+
     fn really_do_download(
         sender: Sender<MgAction>,
-        device: Arc<dyn drivers::Driver + Send + Sync>,
-        erase: bool,
-        output_file: path::PathBuf,
+        device: drivers::DriverRef,
+        cancel: drivers::CancelToken,
+        request: DownloadRequest,
     ) {
-        print_on_err!(
-            thread::Builder::new().name("downloader".into()).spawn(move || {
-                let result = if device.open() {
-                    let tempdir = tempfile::tempdir();
-                    if let Ok(tempdir) = tempdir {
-                        MgAction::DoneDownload(
-                            device.download(Format::Gpx, erase, &tempdir)
-                                .and_then(|temp_output_filename| {
-                                    log::debug!(
-                                        "success {temp_output_filename:?} -> will copy to {output_file:?}"
-                                            );
-                                    std::fs::copy(temp_output_filename, output_file).map(|_| ()).map_err(drivers::Error::from)
-                                })
-                            )
-                    } else {
-                        MgAction::DoneDownload(
-                            tempdir.map(|_| ())
-                                .map_err(drivers::Error::from)
+        print_on_err!(thread::Builder::new()
+            .name("downloader".into())
+            .spawn(move || {
+                // fail before the device is erased.
+                let result = match request.destination.check() {
+                    Err(err) => Err(err),
+                    Ok(()) if !device.open() => Err(drivers::Error::Failed(i18n("Open failed."))),
+                    Ok(()) => {
+                        let progress = |p| post_event(&sender, MgAction::Progress(p));
+                        export::download_and_write(
+                            &*device,
+                            &request.cap,
+                            &export::DownloadOptions::new(request.erase, request.shutoff),
+                            &progress,
+                            &cancel,
+                            |data| {
+                                let last = data.last_time();
+                                let new_points;
+                                let data = match request.since {
+                                    Some(since) => {
+                                        let mut data = data.clone();
+                                        data.retain_after(since);
+                                        if data.is_empty() {
+                                            return Ok(vec![]);
+                                        }
+                                        new_points = data;
+                                        &new_points
+                                    }
+                                    None => data,
+                                };
+                                let pieces = match request.split {
+                                    Some(mode) => split::split(data, mode),
+                                    None => vec![data.clone()],
+                                };
+                                log::debug!("success, will save {} pieces", pieces.len());
+                                let files = request.destination.write(
+                                    &pieces,
+                                    &request.formats,
+                                    &request.options,
+                                )?;
+                                if let (Some(key), Some(last)) = (request.device_key.as_ref(), last)
+                                {
+                                    post_event(&sender, MgAction::SetLastPoint(key.clone(), last));
+                                }
+                                Ok(files)
+                            },
                         )
                     }
-                } else {
-                    MgAction::DoneDownload(Err(drivers::Error::Failed(i18n("Open failed."))))
                 };
-                post_event(&sender, result);
+                post_event(&sender, MgAction::DoneDownload(result));
             }));
     }
 
@@ -310,23 +855,22 @@ impl MgApplication {
         dialog.close();
     }
 
-    fn do_erase(&self) {
-        let device = self.device_manager.get_device();
-        if device.is_none() {
-            log::error!("nodriver");
-            post_event(
-                &self.sender,
-                MgAction::DoneErase(Err(drivers::Error::NoDriver)),
-            );
-            return;
-        }
-        let d = device.unwrap();
+    fn do_erase(&mut self) {
+        let d = match self.device_manager.get_device() {
+            Ok(device) => device,
+            Err(err) => {
+                log::error!("nodriver {err}");
+                post_event(&self.sender, MgAction::DoneErase(Err(err)));
+                return;
+            }
+        };
+        let cancel = self.new_cancel_token();
         let sender = self.sender.clone();
         print_on_err!(thread::Builder::new().name("eraser".into()).spawn(move || {
             post_event(
                 &sender,
                 if d.open() {
-                    match d.erase() {
+                    match d.erase(&cancel) {
                         Ok(_) => {
                             log::debug!("success erasing");
                             MgAction::DoneErase(Ok(()))
@@ -340,18 +884,118 @@ impl MgApplication {
         }));
     }
 
-    fn settings_dir() -> path::PathBuf {
-        // XXX replace this by glib stuff when we can.
-        // Also we treat a failure of this as fatal.
-        let mut path: path::PathBuf = dirs::home_dir().expect("Can't locate home_dir");
-        path.push(".gpsami");
-        path
+    fn do_shutoff(&self) {
+        let d = match self.device_manager.get_device() {
+            Ok(device) => device,
+            Err(err) => {
+                log::error!("nodriver {err}");
+                post_event(&self.sender, MgAction::DoneShutoff(Err(err)));
+                return;
+            }
+        };
+        let sender = self.sender.clone();
+        print_on_err!(thread::Builder::new()
+            .name("shutoff".into())
+            .spawn(move || {
+                post_event(
+                    &sender,
+                    if d.open() {
+                        MgAction::DoneShutoff(d.shutoff())
+                    } else {
+                        MgAction::DoneShutoff(Err(drivers::Error::Failed(i18n("Open failed."))))
+                    },
+                );
+            }));
+    }
+
+    /// Query the logging state of the device, with a short timeout.
+    /// Only the logging switch is insensitive meanwhile. The query is
+    /// cancelled when an operation starts or the port changes.
+    fn query_logging(&mut self) {
+        self.cancel_logging_query();
+        let settings = self.timeouts_settings();
+        let timeouts = drivers::Timeouts {
+            command: settings.command.min(LOGGING_QUERY_TIMEOUT),
+            inactivity: settings.inactivity.min(LOGGING_QUERY_TIMEOUT),
+            ..settings
+        };
+        let device = match self.device_manager.get_device_with_timeouts(timeouts) {
+            Ok(device) => device,
+            Err(err) => {
+                log::error!("nodriver {err}");
+                return;
+            }
+        };
+        self.logging_switch.set_sensitive(false);
+        let cancel = drivers::CancelToken::default();
+        self.logging_query = Some(cancel.clone());
+        let sender = self.sender.clone();
+        print_on_err!(thread::Builder::new()
+            .name("logging query".into())
+            .spawn(move || {
+                let result = if device.open() {
+                    device.logging(&cancel)
+                } else {
+                    Err(drivers::Error::Failed(i18n("Open failed.")))
+                };
+                post_event(&sender, MgAction::DoneQueryLogging(cancel, result));
+            }));
+    }
+
+    /// Cancel the logging state query running, if any.
+    fn cancel_logging_query(&mut self) {
+        if let Some(cancel) = self.logging_query.take() {
+            cancel.cancel();
+        }
+    }
+
+    /// Return true if the logging can be changed: the device can and
+    /// a port is selected.
+    fn can_set_logging(&self) -> bool {
+        self.device_manager
+            .capability()
+            .map(|cap| cap.can_log_enable)
+            .unwrap_or(false)
+            && self
+                .port_combo
+                .active_id()
+                .map(|id| !id.is_empty())
+                .unwrap_or(false)
+    }
+
+    fn do_set_logging(&self, enable: bool) {
+        let device = match self.device_manager.get_device() {
+            Ok(device) => device,
+            Err(err) => {
+                log::error!("nodriver {err}");
+                post_event(&self.sender, MgAction::DoneSetLogging(enable, Err(err)));
+                return;
+            }
+        };
+        let sender = self.sender.clone();
+        print_on_err!(thread::Builder::new()
+            .name("logging".into())
+            .spawn(move || {
+                let result = if device.open() {
+                    device.set_logging(enable)
+                } else {
+                    Err(drivers::Error::Failed(i18n("Open failed.")))
+                };
+                post_event(&sender, MgAction::DoneSetLogging(enable, result));
+            }));
+    }
+
+    /// Update the logging switch without triggering a change on the device.
+    fn update_logging_switch(&self, enabled: bool) {
+        self.logging_switch.block_signal(&self.logging_handler);
+        self.logging_switch.set_active(enabled);
+        self.logging_switch.set_state(enabled);
+        self.logging_switch.unblock_signal(&self.logging_handler);
     }
 
     fn save_settings(&self) -> Result<(), glib::Error> {
-        let mut path = Self::settings_dir();
-        path.push("gpsami.ini");
-        self.prefs_store.save_to_file(path.to_str().unwrap())
+        self.prefs_store
+            .save_to_file(settings::file().to_str().unwrap())
     }
 
     fn set_output_destination_dir(&mut self, output: &path::Path) {
@@ -359,14 +1003,14 @@ impl MgApplication {
     }
 
     pub fn load_settings(&mut self) -> Result<(), glib::Error> {
-        let mut path = Self::settings_dir();
+        let path = settings::dir();
         if let Err(e) = std::fs::create_dir_all(path.clone()) {
             return Err(glib::Error::new(
                 glib::FileError::Failed,
                 &format!("Can't create settings dir '{path:?}': {e}"),
             ));
         }
-        path.push("gpsami.ini");
+        let path = settings::file();
 
         if let Err(e) = self
             .prefs_store
@@ -379,21 +1023,421 @@ impl MgApplication {
         }
     }
 
+    /// The timeouts from the settings: seconds in the `timeouts` group.
+    fn timeouts_settings(&self) -> drivers::Timeouts {
+        let seconds = |key: &str| {
+            self.prefs_store
+                .uint64("timeouts", key)
+                .ok()
+                .map(Duration::from_secs)
+        };
+        let default = drivers::Timeouts::default();
+        drivers::Timeouts {
+            download: seconds("download").unwrap_or(default.download),
+            erase: seconds("erase").unwrap_or(default.erase),
+            command: seconds("command").unwrap_or(default.command),
+            inactivity: seconds("inactivity").unwrap_or(default.inactivity),
+        }
+    }
+
+    /// The CSV options from the settings.
+    fn csv_settings(&self) -> csv::Options {
+        let string = |key: &str| self.prefs_store.string("csv", key).ok();
+        let default = csv::Options::default();
+        csv::Options {
+            columns: string("columns")
+                .and_then(|columns| csv::parse_columns(&columns))
+                .unwrap_or(default.columns),
+            time_format: string("time")
+                .and_then(|time| csv::TimeFormat::from_id(&time))
+                .unwrap_or(default.time_format),
+            delimiter: string("delimiter")
+                .and_then(|delimiter| csv::parse_delimiter(&delimiter))
+                .unwrap_or(default.delimiter),
+        }
+    }
+
+    /// The formats to also write the download to, from the settings.
+    fn extra_formats_settings(&self) -> Vec<Format> {
+        self.prefs_store
+            .string("output", "extra_formats")
+            .ok()
+            .and_then(|formats| export::parse_formats(&formats))
+            .unwrap_or_default()
+    }
+
+    /// Show the extra formats on their button.
+    fn update_extra_formats_btn(&self, formats: &[Format]) {
+        let labels: Vec<&str> = formats.iter().map(|format| format.label()).collect();
+        if labels.is_empty() {
+            self.extra_formats_btn.set_label(&i18n("None"));
+        } else {
+            self.extra_formats_btn.set_label(&labels.join(", "));
+        }
+    }
+
+    /// Add or remove `format` from the extra formats in the settings.
+    fn set_extra_format(&mut self, format: Format, active: bool) {
+        let mut formats = self.extra_formats_settings();
+        formats.retain(|&f| f != format);
+        if active {
+            formats.push(format);
+        }
+        let formats: Vec<Format> = export::FORMATS
+            .iter()
+            .copied()
+            .filter(|format| formats.contains(format))
+            .collect();
+        if formats.is_empty() {
+            let _ = self.prefs_store.remove_key("output", "extra_formats");
+        } else {
+            let ids: Vec<&str> = formats.iter().filter_map(|format| format.id()).collect();
+            self.prefs_store
+                .set_string("output", "extra_formats", &ids.join(","));
+        }
+        if self.save_settings().is_err() {
+            log::error!("Error saving settings");
+        }
+        self.update_extra_formats_btn(&formats);
+    }
+
+    /// The time of the last point downloaded from the device `key`, if
+    /// only the new points are to be downloaded.
+    fn new_points_since(&self, key: Option<&str>) -> Option<DateTime<Utc>> {
+        if !self.new_only_checkbtn.is_active() {
+            return None;
+        }
+        settings::last_point(&self.prefs_store, key?)
+    }
+
+    /// Warn that all the points are downloaded if the device can't be
+    /// told apart from the others of its model.
+    fn update_new_only_warning(&self) {
+        let no_key = self
+            .port_combo
+            .active_id()
+            .map(|id| !id.is_empty())
+            .unwrap_or(false)
+            && self.device_manager.device_key().is_none();
+        self.new_only_warning
+            .set_visible(self.new_only_checkbtn.is_active() && no_key);
+    }
+
+    /// How to split the download, from the settings.
+    fn split_settings(&self) -> Option<Split> {
+        self.prefs_store
+            .string("output", "split")
+            .ok()
+            .and_then(|split| Split::parse(&split))
+    }
+
+    /// The mode of the split in the settings, `none` if there is none.
+    fn split_mode(&self) -> String {
+        match self.prefs_store.string("output", "split") {
+            Ok(split) if Split::parse(&split).is_some() => {
+                split.split(':').next().unwrap_or_default().to_string()
+            }
+            _ => "none".to_string(),
+        }
+    }
+
+    /// Set the mode of the split in the settings. The argument is
+    /// kept if the mode is the same.
+    fn set_split_mode(&mut self, mode: &str) {
+        if mode == "none" {
+            let _ = self.prefs_store.remove_key("output", "split");
+        } else if self.split_mode() != mode {
+            self.prefs_store.set_string("output", "split", mode);
+        }
+        if self.save_settings().is_err() {
+            log::error!("Error saving settings");
+        }
+    }
+
+    /// The IGC options from the settings, and the current device.
+    fn igc_settings(&self) -> igc::Options {
+        let string = |key: &str| {
+            self.prefs_store
+                .string("igc", key)
+                .ok()
+                .map(|value| value.to_string())
+        };
+        let desc = self.device_manager.device_desc();
+        igc::Options {
+            pilot: string("pilot"),
+            copilot: string("copilot"),
+            glider_type: string("glider_type"),
+            glider_id: string("glider_id"),
+            competition_id: string("competition_id"),
+            competition_class: string("competition_class"),
+            device_id: desc.map(|desc| desc.id.clone()),
+            device_label: desc.map(|desc| desc.label.clone()),
+        }
+    }
+
+    /// Show the CSV options in the save dialog, or hide them keeping
+    /// what was chosen in `options`.
+    fn show_csv_choices(
+        chooser: &gtk::FileChooserDialog,
+        options: &RefCell<csv::Options>,
+        show: bool,
+    ) {
+        let shown = chooser.choice(CSV_DELIMITER_CHOICE).is_some();
+        if show && !shown {
+            Self::add_csv_choices(chooser, &options.borrow());
+        } else if !show && shown {
+            let chosen = Self::csv_choices(chooser, &options.borrow());
+            *options.borrow_mut() = chosen;
+            chooser.remove_choice(CSV_TIME_FORMAT_CHOICE);
+            chooser.remove_choice(CSV_DELIMITER_CHOICE);
+            for column in csv::COLUMNS {
+                chooser.remove_choice(&csv_column_choice(*column));
+            }
+        }
+    }
+
+    /// Add the CSV options to the save dialog.
+    fn add_csv_choices(chooser: &gtk::FileChooserDialog, options: &csv::Options) {
+        let time_label = [i18n("UTC"), i18n("Local time"), i18n("Epoch")];
+        let time_choices: Vec<(&str, &str)> = csv::TIME_FORMATS
+            .iter()
+            .zip(time_label.iter())
+            .map(|(t, label)| (t.id(), label.as_str()))
+            .collect();
+        chooser.add_choice(CSV_TIME_FORMAT_CHOICE, &i18n("CSV time:"), &time_choices);
+        chooser.set_choice(CSV_TIME_FORMAT_CHOICE, options.time_format.id());
+
+        let delimiter_labels = [i18n("Comma"), i18n("Semicolon"), i18n("Tab")];
+        let delimiter_choices: Vec<(&str, &str)> = DELIMITERS
+            .iter()
+            .zip(delimiter_labels.iter())
+            .map(|((id, _), label)| (*id, label.as_str()))
+            .collect();
+        chooser.add_choice(
+            CSV_DELIMITER_CHOICE,
+            &i18n("Delimiter:"),
+            &delimiter_choices,
+        );
+        if let Some((id, _)) = DELIMITERS.iter().find(|(_, d)| *d == options.delimiter) {
+            chooser.set_choice(CSV_DELIMITER_CHOICE, id);
+        }
+
+        for column in csv::COLUMNS {
+            let id = csv_column_choice(*column);
+            chooser.add_choice(&id, &csv_column_label(*column), &[]);
+            let active = options.columns.contains(column);
+            chooser.set_choice(&id, if active { "true" } else { "false" });
+        }
+    }
+
+    /// The CSV options chosen in the save dialog. Keep `options` for
+    /// what isn't there.
+    fn csv_choices(chooser: &gtk::FileChooserDialog, options: &csv::Options) -> csv::Options {
+        let columns: Vec<csv::Column> = csv::COLUMNS
+            .iter()
+            .copied()
+            .filter(|column| chooser.choice(&csv_column_choice(*column)).as_deref() == Some("true"))
+            .collect();
+        csv::Options {
+            columns: if columns.is_empty() {
+                options.columns.clone()
+            } else {
+                columns
+            },
+            time_format: chooser
+                .choice(CSV_TIME_FORMAT_CHOICE)
+                .and_then(|id| csv::TimeFormat::from_id(id.as_str()))
+                .unwrap_or(options.time_format),
+            delimiter: chooser
+                .choice(CSV_DELIMITER_CHOICE)
+                .and_then(|id| DELIMITERS.iter().find(|(d, _)| *d == id.as_str()))
+                .map(|(_, delimiter)| *delimiter)
+                .unwrap_or(options.delimiter),
+        }
+    }
+
     /// Start the app.
     pub fn start(&mut self) {
         utils::setup_text_combo(&self.model_combo, &self.model_store);
         utils::setup_text_combo(&self.port_combo, &self.port_store);
-        self.populate_model_combo();
+        self.rescan_devices();
         if let Some(window) = self.gapp.window_by_id(self.window_id) {
             window.present();
         }
+        let errors = self.device_manager.database_errors();
+        if !errors.is_empty() {
+            let reason = errors
+                .iter()
+                .map(|err| err.to_string())
+                .collect::<Vec<String>>()
+                .join("\n");
+            self.report_error(&i18n("Error loading the device database."), &reason);
+        }
+        self.check_gpsbabel();
+    }
+
+    /// Check in a thread that gpsbabel can drive the devices using it.
+    fn check_gpsbabel(&self) {
+        let check = self.device_manager.gpsbabel_check(None);
+        let sender = self.sender.clone();
+        print_on_err!(thread::Builder::new()
+            .name("gpsbabel check".into())
+            .spawn(move || {
+                post_event(&sender, MgAction::GpsBabelChecked(check.run()));
+            }));
     }
 
-    /// Rescan devices. On start and when new device is connected.
+    /// Show why gpsbabel can't drive the devices, with their names.
+    fn show_gpsbabel_error(&self) {
+        if let Some(drivers::Error::GpsBabel {
+            ref reason,
+            ref devices,
+        }) = self.gpsbabel_error
+        {
+            let labels: Vec<&str> = devices
+                .iter()
+                .map(|id| {
+                    self.device_manager
+                        .devices_desc()
+                        .iter()
+                        .find(|desc| &desc.id == id)
+                        .map(|desc| desc.label.as_str())
+                        .unwrap_or(id.as_str())
+                })
+                .collect();
+            self.report_error(
+                &i18n("Some devices can't be used."),
+                &format!("{reason}.\n\n{}", labels.join("\n")),
+            );
+        }
+    }
+
+    /// Rescan devices. On start.
     fn rescan_devices(&mut self) {
+        self.detect_device();
         self.populate_model_combo();
     }
 
+    /// A port was plugged: add it if it is for the model, and select
+    /// it if it was selected last or the device is recognized. The
+    /// selection waits for the operation in progress to be done.
+    fn port_added(&mut self, port_type: drivers::PortType, port: drivers::Port) {
+        log::debug!("port added {port:?}");
+        let id = port.path.to_string_lossy().into_owned();
+        if self.device_manager.listens_to(port_type)
+            && utils::find_text_row(&self.port_store, &id).is_none()
+        {
+            utils::add_text_row(&self.port_store, &id, &port.id);
+        }
+        if self.state == UiState::InProgress {
+            self.plugged_ports.push(id);
+            return;
+        }
+        self.select_plugged_port(&id);
+    }
+
+    /// Select the port `id` that was plugged if it was selected last,
+    /// or the device on it if it is recognized.
+    fn select_plugged_port(&mut self, id: &str) {
+        if self.detect_device() {
+            if let Ok(model) = self.prefs_store.string("device", "model") {
+                if self.model_combo.active_id().as_deref() != Some(model.as_str()) {
+                    // model_changed() selects the port.
+                    self.model_combo.set_active_id(Some(model.as_str()));
+                    return;
+                }
+            }
+        }
+        let selected = self.port_combo.active_id();
+        if selected.as_deref() != Some(id)
+            && self.prefs_store.string("device", "port").ok().as_deref() == Some(id)
+        {
+            self.port_combo.set_active_id(Some(id));
+        }
+    }
+
+    /// A port was unplugged: remove it. Cancel the operation in
+    /// progress if it was selected. The port stays in the settings to
+    /// select it again when plugged back.
+    fn port_removed(&mut self, path: &path::Path) {
+        log::debug!("port removed {path:?}");
+        let id = path.to_string_lossy();
+        self.detected_ports.retain(|detected| detected != path);
+        self.plugged_ports.retain(|plugged| plugged.as_str() != id);
+        let selected = self.port_combo.active_id().as_deref() == Some(&*id);
+        if let Some(iter) = utils::find_text_row(&self.port_store, &id) {
+            self.port_store.remove(&iter);
+        }
+        if !selected {
+            return;
+        }
+        self.cancel_logging_query();
+        self.logging_switch.set_sensitive(false);
+        self.set_action_enabled("download", false);
+        if self.state == UiState::InProgress {
+            self.cancel();
+            self.report_error(
+                &i18n("The device was unplugged."),
+                &i18n("Plug it back in and try again."),
+            );
+        } else {
+            self.toast_overlay
+                .add_toast(adw::Toast::new(&i18n("The device was unplugged.")));
+        }
+    }
+
+    /// Select the model and the port of a newly connected device that
+    /// is recognized by its USB ids. Return true if one was. If several
+    /// models have its USB ids, ask for the model instead.
+    fn detect_device(&mut self) -> bool {
+        let model = self.prefs_store.string("device", "model").ok();
+        let detected = self.device_manager.detect_devices(model.as_deref());
+        let new = detected
+            .iter()
+            .find(|(port, _)| !self.detected_ports.contains(&port.path));
+        let labels: Vec<&str> = new
+            .map(|(_, models)| {
+                self.device_manager
+                    .devices_desc()
+                    .iter()
+                    .filter(|desc| models.contains(&desc.id))
+                    .map(|desc| desc.label.as_str())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let found = match new {
+            Some((port, models)) if models.len() == 1 => {
+                log::debug!("detected {} on {port:?}", models[0]);
+                self.prefs_store.set_string("device", "model", &models[0]);
+                self.prefs_store
+                    .set_string("device", "port", &port.path.to_string_lossy());
+                let message = format!(
+                    "{} {} ({})",
+                    i18n("Detected"),
+                    labels.join(", "),
+                    port.path.display()
+                );
+                self.toast_overlay.add_toast(adw::Toast::new(&message));
+                true
+            }
+            Some((port, models)) => {
+                // don't guess.
+                log::debug!("detected one of {models:?} on {port:?}");
+                let message = format!(
+                    "{} ({}): {}",
+                    i18n("Select the model of the device"),
+                    port.path.display(),
+                    labels.join(", ")
+                );
+                self.toast_overlay.add_toast(adw::Toast::new(&message));
+                false
+            }
+            None => false,
+        };
+        self.detected_ports = detected.into_iter().map(|(port, _)| port.path).collect();
+        found
+    }
+
     fn populate_port_combo(&mut self, ports: &[drivers::Port]) {
         self.port_store.clear();
         for port in ports {
@@ -433,21 +1477,36 @@ impl MgApplication {
             self.device_manager.set_model(id);
             let ports = self.device_manager.get_ports_for_model(id);
             self.populate_port_combo(&ports.unwrap_or_default());
+            // the port selected last, or detected.
+            if let Ok(port) = self.prefs_store.string("device", "port") {
+                self.port_combo.set_active_id(Some(port.as_ref()));
+            }
         } else {
             // XXX clear device.
         }
     }
 
     fn update_device_capability(&self, capability: &devices::Capability) {
+        self.logging_box.set_visible(capability.can_log_enable);
         self.erase_checkbtn.set_sensitive(capability.can_erase);
+        self.shutoff_checkbtn.set_sensitive(capability.can_shutoff);
+        if !capability.can_shutoff {
+            self.shutoff_checkbtn.set_active(false);
+        }
+        self.set_action_enabled("erase", capability.can_erase_only);
+        self.set_action_enabled("shutoff", capability.can_shutoff);
+    }
+
+    /// Enable or disable the window action `name`.
+    fn set_action_enabled(&self, name: &str, enabled: bool) {
         if let Some(a) = self
             .gapp
             .window_by_id(self.window_id)
             .and_then(|w| w.downcast::<gtk::ApplicationWindow>().ok())
-            .and_then(|w| w.lookup_action("erase"))
+            .and_then(|w| w.lookup_action(name))
         {
             if let Ok(sa) = a.downcast::<gio::SimpleAction>() {
-                sa.set_enabled(capability.can_erase_only);
+                sa.set_enabled(enabled);
             }
         }
     }
@@ -459,35 +1518,87 @@ impl MgApplication {
         }
 
         self.device_manager.set_port(id);
+        self.update_new_only_warning();
 
-        if let Some(a) = self
-            .gapp
-            .window_by_id(self.window_id)
-            .and_then(|w| w.downcast::<gtk::ApplicationWindow>().ok())
-            .and_then(|w| w.lookup_action("download"))
+        self.cancel_logging_query();
+        let can_log_enable = self
+            .device_manager
+            .capability()
+            .map(|cap| cap.can_log_enable)
+            .unwrap_or(false)
+            && !id.is_empty();
+        self.logging_switch.set_sensitive(can_log_enable);
+        // only when the switch is shown, not while the device is busy,
+        // and when the device was recognized on the port: selecting a
+        // port doesn't tell the device is there.
+        if can_log_enable
+            && self.state == UiState::Idle
+            && self
+                .detected_ports
+                .iter()
+                .any(|port| port.as_path() == path::Path::new(id))
         {
-            if let Ok(sa) = a.downcast::<gio::SimpleAction>() {
-                sa.set_enabled(!id.is_empty());
-            }
+            self.query_logging();
         }
+
+        self.set_action_enabled("download", !id.is_empty());
     }
 
     fn set_state(&mut self, state: UiState) {
+        self.state = state;
         match state {
             UiState::Idle => {
-                self.content_box.set_sensitive(true);
+                self.cancel = None;
+                self.main_grid.set_sensitive(true);
+                self.button_box.set_sensitive(true);
+                self.progress_box.set_visible(false);
+                for id in std::mem::take(&mut self.plugged_ports) {
+                    self.select_plugged_port(&id);
+                }
             }
             UiState::InProgress => {
-                self.content_box.set_sensitive(false);
+                // the operation needs the device.
+                if self.logging_query.is_some() {
+                    self.cancel_logging_query();
+                    self.logging_switch.set_sensitive(self.can_set_logging());
+                }
+                self.main_grid.set_sensitive(false);
+                self.button_box.set_sensitive(false);
+                self.cancel_btn.set_sensitive(false);
+                self.progress_bar.set_fraction(0.0);
+                self.progress_box.set_visible(true);
             }
         }
     }
 
+    /// Create the token to cancel the operation starting, and allow
+    /// cancelling.
+    fn new_cancel_token(&mut self) -> drivers::CancelToken {
+        let cancel = drivers::CancelToken::default();
+        self.cancel = Some(cancel.clone());
+        self.cancel_btn.set_sensitive(true);
+        cancel
+    }
+
+    fn cancel(&mut self) {
+        if let Some(ref cancel) = self.cancel {
+            log::debug!("Cancelling");
+            cancel.cancel();
+            self.cancel_btn.set_sensitive(false);
+        }
+    }
+
+    fn update_progress(&self, progress: drivers::Progress) {
+        match progress.fraction() {
+            Some(fraction) => self.progress_bar.set_fraction(fraction),
+            None => self.progress_bar.pulse(),
+        }
+    }
+
     pub fn process_event(&mut self, evt: MgAction) {
         match evt {
-            MgAction::RescanDevices => {
-                self.rescan_devices();
-            }
+            MgAction::PortAdded(port_type, port) => self.port_added(port_type, port),
+            MgAction::PortRemoved(ref path) => self.port_removed(path),
             MgAction::ModelChanged(ref id) => {
                 self.model_changed(id);
             }
@@ -504,7 +1615,25 @@ impl MgApplication {
                     Err(drivers::Error::Cancelled) => self
                         .toast_overlay
                         .add_toast(adw::Toast::new(&i18n("Erase cancelled."))),
-                    Err(e) => self.report_error(&i18n("Error erasing GPS data."), &e.to_string()),
+                    Err(e) => {
+                        self.report_error(&i18n("Error erasing GPS data."), &error_reason(&e))
+                    }
+                }
+                self.set_state(UiState::Idle);
+            }
+            MgAction::Progress(progress) => self.update_progress(progress),
+            MgAction::Cancel => self.cancel(),
+            MgAction::StartShutoff => {
+                self.set_state(UiState::InProgress);
+                self.do_shutoff();
+            }
+            MgAction::DoneShutoff(e) => {
+                match e {
+                    Ok(_) => self
+                        .toast_overlay
+                        .add_toast(adw::Toast::new(&i18n("Device powered off."))),
+                    Err(e) => self
+                        .report_error(&i18n("Error powering off the device."), &error_reason(&e)),
                 }
                 self.set_state(UiState::Idle);
             }
@@ -515,18 +1644,142 @@ impl MgApplication {
             MgAction::DoneDownload(e) => {
                 log::debug!("done download {e:?}");
                 match e {
-                    Ok(_) => self
-                        .toast_overlay
-                        .add_toast(adw::Toast::new(&i18n("Download finished."))),
+                    Ok(files) => {
+                        let message = match files.first().and_then(|file| file.file_name()) {
+                            Some(name) => {
+                                format!("{} {}", i18n("Download saved to"), name.to_string_lossy())
+                            }
+                            None => i18n("No new points to download."),
+                        };
+                        self.toast_overlay.add_toast(adw::Toast::new(&message));
+                    }
                     Err(drivers::Error::Cancelled) => self
                         .toast_overlay
                         .add_toast(adw::Toast::new(&i18n("Download cancelled."))),
                     Err(e) => {
-                        self.report_error(&i18n("Error downloading GPS data."), &e.to_string())
+                        self.report_error(&i18n("Error downloading GPS data."), &error_reason(&e))
+                    }
+                }
+                self.set_state(UiState::Idle);
+            }
+            MgAction::SetLogging(enable) => {
+                self.set_state(UiState::InProgress);
+                self.do_set_logging(enable);
+            }
+            MgAction::DoneSetLogging(enable, e) => {
+                match e {
+                    Ok(_) => {
+                        self.logging_switch.set_state(enable);
+                        self.toast_overlay.add_toast(adw::Toast::new(&if enable {
+                            i18n("Logging enabled.")
+                        } else {
+                            i18n("Logging disabled.")
+                        }));
+                    }
+                    Err(e) => {
+                        self.update_logging_switch(!enable);
+                        self.report_error(
+                            &i18n("Error changing the logging state."),
+                            &error_reason(&e),
+                        );
                     }
                 }
                 self.set_state(UiState::Idle);
             }
+            MgAction::DoneQueryLogging(cancel, e) => {
+                // a cancelled query was replaced, or an operation started.
+                if cancel.is_cancelled() {
+                    log::debug!("Logging query cancelled");
+                } else {
+                    self.logging_query = None;
+                    match e {
+                        Ok(enabled) => self.update_logging_switch(enabled),
+                        Err(e) => log::warn!("Can't query logging state: {e}"),
+                    }
+                    self.logging_switch.set_sensitive(self.can_set_logging());
+                }
+            }
+            MgAction::SetOutputFormat(format) => {
+                if let Some(id) = format.id() {
+                    self.prefs_store.set_string("output", "format", id);
+                    if self.save_settings().is_err() {
+                        log::error!("Error saving settings");
+                    }
+                }
+            }
+            MgAction::SetCsvOptions(options) => {
+                let columns: Vec<&str> = options.columns.iter().map(|c| c.id()).collect();
+                self.prefs_store
+                    .set_string("csv", "columns", &columns.join(","));
+                self.prefs_store
+                    .set_string("csv", "time", options.time_format.id());
+                let delimiter = match options.delimiter {
+                    '\t' => "tab".to_string(),
+                    delimiter => delimiter.to_string(),
+                };
+                self.prefs_store.set_string("csv", "delimiter", &delimiter);
+                if self.save_settings().is_err() {
+                    log::error!("Error saving settings");
+                }
+            }
+            MgAction::GpsBabelChecked(result) => {
+                if let Err(ref err) = result {
+                    log::warn!("gpsbabel check: {err}");
+                }
+                match result {
+                    Err(drivers::Error::GpsBabel { ref reason, .. }) => {
+                        let title = format!("{}: {reason}", i18n("Some devices can't be used"));
+                        self.gpsbabel_banner
+                            .set_title(&glib::markup_escape_text(&title));
+                        self.gpsbabel_banner.set_revealed(true);
+                    }
+                    _ => self.gpsbabel_banner.set_revealed(false),
+                }
+                self.gpsbabel_error = result.err();
+            }
+            MgAction::ShowGpsBabelError => self.show_gpsbabel_error(),
+            MgAction::SetAutoSave(auto_save) => {
+                self.prefs_store
+                    .set_boolean("output", "auto_save", auto_save);
+                if self.save_settings().is_err() {
+                    log::error!("Error saving settings");
+                }
+            }
+            MgAction::SetExtraFormat(format, active) => self.set_extra_format(format, active),
+            MgAction::SetSplitMode(mode) => self.set_split_mode(&mode),
+            MgAction::SetIgcSetting(key, value) => {
+                if value.is_empty() {
+                    let _ = self.prefs_store.remove_key("igc", key);
+                } else {
+                    self.prefs_store.set_string("igc", key, &value);
+                }
+                if self.save_settings().is_err() {
+                    log::error!("Error saving settings");
+                }
+            }
+            MgAction::SetTemplate(template) => {
+                if template.is_empty() {
+                    let _ = self.prefs_store.remove_key("output", "template");
+                } else {
+                    self.prefs_store.set_string("output", "template", &template);
+                }
+                if self.save_settings().is_err() {
+                    log::error!("Error saving settings");
+                }
+            }
+            MgAction::SetNewOnly(new_only) => {
+                self.prefs_store.set_boolean("output", "new_only", new_only);
+                if self.save_settings().is_err() {
+                    log::error!("Error saving settings");
+                }
+                self.update_new_only_warning();
+            }
+            MgAction::SetLastPoint(key, last) => {
+                settings::set_last_point(&self.prefs_store, &key, last);
+                if self.save_settings().is_err() {
+                    log::error!("Error saving settings");
+                }
+            }
             MgAction::SetOutputDir(f) => {
                 self.set_output_destination_dir(f.as_ref());
                 self.prefs_store
@@ -538,3 +1791,38 @@ impl MgApplication {
         }
     }
 }
+
+#[test]
+fn test_auto_destination_from_settings() {
+    use crate::track;
+
+    let dir = tempfile::tempdir().unwrap();
+    // as restored on startup.
+    let prefs = glib::KeyFile::new();
+    prefs.set_string("output", "dir", dir.path().to_str().unwrap());
+    prefs.set_boolean("output", "auto_save", true);
+    let output_dir = output_dir_settings(&prefs).unwrap();
+    assert_eq!(output_dir, dir.path());
+    assert_eq!(template_settings(&prefs), DEFAULT_TEMPLATE);
+
+    prefs.set_string("output", "template", "{model}_{start:%Y}.gpx");
+    let destination =
+        Destination::auto(&output_dir, template_settings(&prefs), "m241".to_string()).unwrap();
+    let data = track::test_data(vec![vec![track::test_point(0), track::test_point(60)]]);
+    let files = destination
+        .write(&[data], &[Format::Gpx], &export::Options::default())
+        .unwrap();
+    assert_eq!(files, vec![dir.path().join("m241_2024.gpx")]);
+    assert!(files[0].exists());
+
+    // no folder, no model.
+    let prefs = glib::KeyFile::new();
+    assert!(output_dir_settings(&prefs).is_none());
+    assert!(Destination::auto(
+        path::Path::new(""),
+        template_settings(&prefs),
+        "m241".to_string()
+    )
+    .is_err());
+    assert!(Destination::auto(dir.path(), template_settings(&prefs), String::new()).is_err());
+}
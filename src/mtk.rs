@@ -0,0 +1,993 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use chrono::DateTime;
+
+use crate::devices::Capability;
+use crate::drivers::{CancelToken, Driver, Error, Progress, Result, Timeouts};
+use crate::track::{FixType, GpsData, Track, TrackPoint, TrackSegment, Waypoint};
+
+/// Size of a flash sector. Each sector starts with a header.
+const SECTOR_SIZE: usize = 0x10000;
+/// Size of the sector header. Records follow.
+const SECTOR_HEADER_SIZE: usize = 0x200;
+/// Size of the chunks requested when reading the flash.
+const READ_CHUNK_SIZE: usize = 0x800;
+/// Maximum number of sentences to wait for an answer.
+const MAX_SENTENCES: usize = 200;
+/// Length of a special record (log format change, log start, etc.)
+const SPECIAL_RECORD_SIZE: usize = 16;
+
+// The log format is a bit field of the fields present in each record.
+// The fields are in the order of the bits.
+const FIELD_UTC: u32 = 1 << 0;
+const FIELD_VALID: u32 = 1 << 1;
+const FIELD_LAT: u32 = 1 << 2;
+const FIELD_LON: u32 = 1 << 3;
+const FIELD_HEIGHT: u32 = 1 << 4;
+const FIELD_SPEED: u32 = 1 << 5;
+const FIELD_TRACK: u32 = 1 << 6;
+const FIELD_DSTA: u32 = 1 << 7;
+const FIELD_DAGE: u32 = 1 << 8;
+const FIELD_PDOP: u32 = 1 << 9;
+const FIELD_HDOP: u32 = 1 << 10;
+const FIELD_VDOP: u32 = 1 << 11;
+const FIELD_NSAT: u32 = 1 << 12;
+const FIELD_SID: u32 = 1 << 13;
+const FIELD_ELEVATION: u32 = 1 << 14;
+const FIELD_AZIMUTH: u32 = 1 << 15;
+const FIELD_SNR: u32 = 1 << 16;
+const FIELD_RCR: u32 = 1 << 17;
+const FIELD_MILLISECOND: u32 = 1 << 18;
+const FIELD_DISTANCE: u32 = 1 << 19;
+
+/// VALID field value when there is no fix.
+const VALID_NO_FIX: u16 = 0x0001;
+/// VALID field value for a standard fix.
+const VALID_SPS: u16 = 0x0002;
+/// VALID field value for a differential fix.
+const VALID_DGPS: u16 = 0x0004;
+/// VALID field value for a PPS fix.
+const VALID_PPS: u16 = 0x0008;
+
+/// RCR field bit when the record was triggered by the button.
+const RCR_BUTTON: u16 = 0x0008;
+
+/// Special record type for a log format change.
+const SPECIAL_LOG_FORMAT: u8 = 0x02;
+/// Special record type for logger start / stop.
+const SPECIAL_LOG_STARTSTOP: u8 = 0x07;
+/// Special record value for logger start.
+const SPECIAL_LOG_START: u32 = 0x0106;
+
+/// PMTK182 query: the logger status.
+const QUERY_LOG_STATUS: u32 = 7;
+/// PMTK182 query: the address where the next record will be written.
+const QUERY_NEXT_WRITE_ADDRESS: u32 = 8;
+/// PMTK182 command: enable logging.
+const COMMAND_LOG_ENABLE: u32 = 4;
+/// PMTK182 command: disable logging.
+const COMMAND_LOG_DISABLE: u32 = 5;
+
+/// Logger status bit set when logging is on.
+const LOG_STATUS_ON: u32 = 0x02;
+
+/// The variant of MTK logger. They differ slightly in the record format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    /// Generic MTK logger.
+    Mtk,
+    /// Holux M-241 and M-1200E. Single precision positions, 3 bytes
+    /// height and no separator before the checksum.
+    Holux,
+}
+
+impl Variant {
+    /// The serial speed for the variant.
+    fn baud_rate(&self) -> u32 {
+        match *self {
+            Variant::Mtk => 115_200,
+            Variant::Holux => 38_400,
+        }
+    }
+
+    /// The size of the field in the record, 0 for the satellite
+    /// fields that are handled separately.
+    fn field_size(&self, field: u32) -> usize {
+        match field {
+            FIELD_UTC => 4,
+            FIELD_VALID => 2,
+            FIELD_LAT | FIELD_LON => match *self {
+                Variant::Mtk => 8,
+                Variant::Holux => 4,
+            },
+            FIELD_HEIGHT => match *self {
+                Variant::Mtk => 4,
+                Variant::Holux => 3,
+            },
+            FIELD_SPEED | FIELD_TRACK => 4,
+            FIELD_DSTA => 2,
+            FIELD_DAGE => 4,
+            FIELD_PDOP | FIELD_HDOP | FIELD_VDOP | FIELD_NSAT => 2,
+            FIELD_RCR | FIELD_MILLISECOND => 2,
+            FIELD_DISTANCE => 8,
+            _ => 0,
+        }
+    }
+}
+
+/// Why a record was rejected.
+#[derive(Debug, PartialEq)]
+enum Rejected {
+    /// Bad checksum or no position.
+    Corrupted,
+    /// Logged without a fix.
+    NoFix,
+}
+
+/// A record decoded from the log.
+struct Record {
+    point: TrackPoint,
+    /// The raw RCR field, the reason for the record.
+    rcr: Option<u16>,
+}
+
+/// The decoded log: a list of segments, split when the logger was restarted.
+#[derive(Debug, Default)]
+pub struct Log {
+    pub segments: Vec<TrackSegment>,
+    pub waypoints: Vec<Waypoint>,
+    /// Number of records that were rejected because of a bad checksum.
+    pub corrupted: usize,
+    /// Number of records skipped because they were logged without a fix.
+    pub no_fix: usize,
+}
+
+impl Log {
+    fn start_segment(&mut self) {
+        if self
+            .segments
+            .last()
+            .map(|s| !s.points.is_empty())
+            .unwrap_or(true)
+        {
+            self.segments.push(TrackSegment::default());
+        }
+    }
+
+    fn push(&mut self, point: TrackPoint) {
+        if self.segments.is_empty() {
+            self.segments.push(TrackSegment::default());
+        }
+        self.segments.last_mut().unwrap().points.push(point);
+    }
+}
+
+impl From<Log> for GpsData {
+    fn from(log: Log) -> GpsData {
+        let tracks = if log.segments.is_empty() {
+            vec![]
+        } else {
+            vec![Track {
+                name: None,
+                segments: log.segments,
+            }]
+        };
+        GpsData {
+            tracks,
+            waypoints: log.waypoints,
+        }
+    }
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn read_f32(data: &[u8]) -> f32 {
+    f32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn read_f64(data: &[u8]) -> f64 {
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(&data[0..8]);
+    f64::from_le_bytes(bytes)
+}
+
+/// Read a position field, whose size depends on the variant.
+fn read_position(data: &[u8], size: usize) -> f64 {
+    if size == 4 {
+        read_f32(data) as f64
+    } else {
+        read_f64(data)
+    }
+}
+
+/// Compute the XOR checksum used by NMEA sentences and log records.
+pub(crate) fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |acc, b| acc ^ b)
+}
+
+/// Map the VALID field to a fix type.
+fn fix_type(valid: u16) -> FixType {
+    match valid {
+        VALID_SPS => FixType::ThreeD,
+        VALID_DGPS => FixType::Dgps,
+        VALID_PPS => FixType::Pps,
+        _ => FixType::None,
+    }
+}
+
+/// Decode one record at the beginning of `data` using `format`.
+/// Return the record (if it is valid) and the size consumed,
+/// or None if the data is truncated.
+fn decode_record(
+    data: &[u8],
+    format: u32,
+    variant: Variant,
+) -> Option<(std::result::Result<Record, Rejected>, usize)> {
+    let mut point = TrackPoint::default();
+    let mut valid = None;
+    let mut rcr = None;
+    let mut has_position = false;
+    let mut pos = 0_usize;
+
+    let mut field = 1_u32;
+    while field <= FIELD_DISTANCE {
+        if format & field == 0 {
+            field <<= 1;
+            continue;
+        }
+        if field == FIELD_SID {
+            // Satellite data: a SID per satellite, followed by
+            // elevation, azimuth and SNR if they are logged.
+            let per_sat = [FIELD_ELEVATION, FIELD_AZIMUTH, FIELD_SNR]
+                .iter()
+                .filter(|f| format & **f != 0)
+                .count()
+                * 2;
+            let sid = data.get(pos..pos + 4)?;
+            let count = read_u16(&sid[2..4]) as usize;
+            if count == 0 {
+                pos += 4;
+            } else {
+                pos += count * (4 + per_sat);
+            }
+            field = FIELD_RCR;
+            continue;
+        }
+        let size = variant.field_size(field);
+        let value = data.get(pos..pos + size)?;
+        match field {
+            FIELD_UTC => {
+                point.time = DateTime::from_timestamp(read_u32(value) as i64, 0);
+            }
+            FIELD_VALID => {
+                let v = read_u16(value);
+                point.fix = Some(fix_type(v));
+                valid = Some(v);
+            }
+            FIELD_LAT => {
+                point.lat = read_position(value, size);
+                has_position = true;
+            }
+            FIELD_LON => point.lon = read_position(value, size),
+            FIELD_HEIGHT => {
+                let height = if size == 3 {
+                    f32::from_le_bytes([0, value[0], value[1], value[2]])
+                } else {
+                    read_f32(value)
+                };
+                point.elevation = Some(height as f64);
+            }
+            // Speed is in km/h.
+            FIELD_SPEED => point.speed = Some(read_f32(value) as f64 / 3.6),
+            FIELD_TRACK => point.course = Some(read_f32(value) as f64),
+            FIELD_HDOP => point.hdop = Some(read_u16(value) as f64 / 100.0),
+            FIELD_NSAT => point.satellites = Some(value[0] as u32),
+            FIELD_RCR => rcr = Some(read_u16(value)),
+            _ => {}
+        }
+        pos += size;
+        field <<= 1;
+    }
+
+    // Checksum. The Holux don't have the '*' separator.
+    let record = data.get(0..pos)?;
+    if variant == Variant::Mtk {
+        if *data.get(pos)? != b'*' {
+            return Some((Err(Rejected::Corrupted), pos + 2));
+        }
+        pos += 1;
+    }
+    let expected = *data.get(pos)?;
+    pos += 1;
+    if checksum(record) != expected || !has_position {
+        return Some((Err(Rejected::Corrupted), pos));
+    }
+    if valid == Some(VALID_NO_FIX) {
+        return Some((Err(Rejected::NoFix), pos));
+    }
+
+    Some((Ok(Record { point, rcr }), pos))
+}
+
+/// Decode the flash `data` as read from the logger.
+pub fn decode(data: &[u8], variant: Variant) -> Log {
+    let mut log = Log::default();
+
+    let mut sector = 0_usize;
+    while sector + SECTOR_HEADER_SIZE <= data.len() {
+        let header = &data[sector..sector + SECTOR_HEADER_SIZE];
+        let count = read_u16(&header[0..2]);
+        let mut format = read_u32(&header[2..6]);
+        if format == 0xffff_ffff {
+            // Unused sector: we reached the end.
+            break;
+        }
+        let sector_end = std::cmp::min(sector + SECTOR_SIZE, data.len());
+        let mut pos = sector + SECTOR_HEADER_SIZE;
+        let mut records = 0_u16;
+        let mut next_is_waypoint = false;
+        while pos < sector_end && (count == 0xffff || records < count) {
+            let remaining = &data[pos..sector_end];
+            if remaining.len() >= SPECIAL_RECORD_SIZE
+                && remaining[0..7] == [0xaa; 7]
+                && remaining[12..16] == [0xbb; 4]
+            {
+                let value = read_u32(&remaining[8..12]);
+                match remaining[7] {
+                    SPECIAL_LOG_FORMAT => format = value,
+                    SPECIAL_LOG_STARTSTOP if value == SPECIAL_LOG_START => log.start_segment(),
+                    _ => {}
+                }
+                pos += SPECIAL_RECORD_SIZE;
+                continue;
+            }
+            if variant == Variant::Holux && remaining.starts_with(b"HOLUXGR241") {
+                next_is_waypoint = remaining[10..].starts_with(b"WAYPNT");
+                pos += SPECIAL_RECORD_SIZE;
+                while pos < sector_end && data[pos] == b' ' {
+                    pos += 1;
+                }
+                continue;
+            }
+            if remaining.len() >= 4 && remaining[0..4] == [0xff; 4] {
+                // Erased flash: end of the data in this sector.
+                break;
+            }
+            match decode_record(remaining, format, variant) {
+                Some((Ok(record), size)) => {
+                    let button = record.rcr.map(|r| r & RCR_BUTTON != 0).unwrap_or(false);
+                    if next_is_waypoint || button {
+                        log.waypoints.push(Waypoint {
+                            point: record.point.clone(),
+                            ..Waypoint::default()
+                        });
+                    }
+                    log.push(record.point);
+                    next_is_waypoint = false;
+                    pos += size;
+                }
+                Some((Err(Rejected::Corrupted), size)) => {
+                    log.corrupted += 1;
+                    pos += size;
+                }
+                Some((Err(Rejected::NoFix), size)) => {
+                    log.no_fix += 1;
+                    pos += size;
+                }
+                None => break,
+            }
+            records += 1;
+        }
+        sector += SECTOR_SIZE;
+    }
+    log.segments.retain(|s| !s.points.is_empty());
+    if log.corrupted > 0 {
+        log::warn!("{} corrupted or invalid records", log.corrupted);
+    }
+    if log.no_fix > 0 {
+        log::debug!("{} records without fix", log.no_fix);
+    }
+
+    log
+}
+
+/// A PMTK connection to the logger.
+pub struct Connection<T: Read + Write> {
+    stream: T,
+    buffer: Vec<u8>,
+    /// When the operation must be finished.
+    deadline: Option<Instant>,
+    /// Maximum wait for an answer.
+    inactivity: Option<Duration>,
+    /// Stop waiting for an answer when triggered.
+    cancel: CancelToken,
+}
+
+impl<T: Read + Write> Connection<T> {
+    pub fn new(stream: T) -> Self {
+        Connection {
+            stream,
+            buffer: vec![],
+            deadline: None,
+            inactivity: None,
+            cancel: CancelToken::default(),
+        }
+    }
+
+    /// Stop waiting for answers when `cancel` is triggered.
+    pub fn set_cancel(&mut self, cancel: &CancelToken) {
+        self.cancel = cancel.clone();
+    }
+
+    /// Limit the operation to `timeout` from now, and each wait
+    /// for an answer to `inactivity`.
+    pub fn set_timeouts(&mut self, timeout: Duration, inactivity: Duration) {
+        self.deadline = Some(Instant::now() + timeout);
+        self.inactivity = Some(inactivity);
+    }
+
+    /// Return `Error::Timeout` if the deadline is passed, or if waiting
+    /// for an answer `since` has been too long. Return
+    /// `Error::Cancelled` if the cancellation was requested.
+    fn check_timeout(&self, since: Instant) -> Result<()> {
+        self.cancel.check()?;
+        let now = Instant::now();
+        let expired = self.deadline.map(|d| now >= d).unwrap_or(false);
+        let inactive = self
+            .inactivity
+            .map(|i| now.duration_since(since) >= i)
+            .unwrap_or(false);
+        if expired || inactive {
+            log::error!("timeout, expired: {expired} inactive: {inactive}");
+            return Err(Error::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Send the command. `$`, checksum and line ending are added.
+    fn send(&mut self, command: &str) -> Result<()> {
+        log::debug!("sending {command}");
+        let sentence = format!("${}*{:02X}\r\n", command, checksum(command.as_bytes()));
+        self.stream.write_all(sentence.as_bytes())?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Read a line from the stream. Reads timing out are retried until
+    /// waiting `since` is too long.
+    fn read_line(&mut self, since: Instant) -> Result<String> {
+        loop {
+            if let Some(idx) = self.buffer.iter().position(|c| *c == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(0..=idx).collect();
+                return Ok(String::from_utf8_lossy(&line).trim().to_string());
+            }
+            let mut buf = [0_u8; 1024];
+            let len = match self.stream.read(&mut buf) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                    self.check_timeout(since)?;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if len == 0 {
+                return Err(Error::Failed("Device closed".to_string()));
+            }
+            self.buffer.extend_from_slice(&buf[0..len]);
+        }
+    }
+
+    /// Read the next valid sentence. Return it without the `$` and the checksum.
+    fn read_sentence(&mut self, since: Instant) -> Result<String> {
+        loop {
+            self.check_timeout(since)?;
+            let line = self.read_line(since)?;
+            let body = match line.strip_prefix('$').and_then(|l| l.rsplit_once('*')) {
+                Some((body, sum)) => {
+                    if u8::from_str_radix(sum, 16).ok() != Some(checksum(body.as_bytes())) {
+                        log::warn!("checksum error in '{line}'");
+                        continue;
+                    }
+                    body
+                }
+                None => continue,
+            };
+            return Ok(body.to_string());
+        }
+    }
+
+    /// Wait for a sentence starting with `prefix`. Skip other sentences,
+    /// like the NMEA positions the logger emits.
+    fn wait_for(&mut self, prefix: &str) -> Result<String> {
+        let since = Instant::now();
+        for _ in 0..MAX_SENTENCES {
+            let sentence = self.read_sentence(since)?;
+            if sentence.starts_with(prefix) {
+                return Ok(sentence);
+            }
+        }
+        Err(Error::Failed(format!("No answer for {prefix}")))
+    }
+
+    /// Wait for the acknowledgement of PMTK182 command `command`.
+    fn wait_for_ack(&mut self, command: u32) -> Result<()> {
+        let ack = self.wait_for(&format!("PMTK001,182,{command},"))?;
+        match ack.rsplit(',').next() {
+            Some("3") => Ok(()),
+            Some("1") => Err(Error::Unsupported),
+            _ => Err(Error::Failed(format!("Command failed: {ack}"))),
+        }
+    }
+
+    /// Query a value from the logger.
+    fn query(&mut self, what: u32) -> Result<u32> {
+        self.send(&format!("PMTK182,2,{what}"))?;
+        let answer = self.wait_for(&format!("PMTK182,3,{what},"))?;
+        let value = answer
+            .rsplit(',')
+            .next()
+            .and_then(|v| u32::from_str_radix(v, 16).ok())
+            .ok_or_else(|| Error::Failed(format!("Invalid answer {answer}")))?;
+        self.wait_for_ack(2)?;
+        Ok(value)
+    }
+
+    /// The size of the log in bytes.
+    pub fn log_size(&mut self) -> Result<usize> {
+        self.query(QUERY_NEXT_WRITE_ADDRESS).map(|v| v as usize)
+    }
+
+    /// Read `size` bytes of the log flash, calling `progress` for each
+    /// chunk received. Stop when `cancel` is triggered.
+    pub fn read_log(
+        &mut self,
+        size: usize,
+        progress: &dyn Fn(Progress),
+        cancel: &CancelToken,
+    ) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(size);
+        while data.len() < size {
+            let address = data.len();
+            let len = std::cmp::min(READ_CHUNK_SIZE, size - address);
+            self.send(&format!("PMTK182,7,{address:08X},{len:08X}"))?;
+            while data.len() < address + len {
+                cancel.check()?;
+                let chunk = self.wait_for("PMTK182,8,")?;
+                let mut parts = chunk.splitn(4, ',').skip(2);
+                let chunk_address = parts.next().and_then(|a| usize::from_str_radix(a, 16).ok());
+                if chunk_address != Some(data.len()) {
+                    return Err(Error::Failed(format!(
+                        "Unexpected data address {chunk_address:?}"
+                    )));
+                }
+                let hex = parts.next().unwrap_or_default().as_bytes();
+                if hex.len() % 2 != 0 {
+                    return Err(Error::Failed("Invalid data".to_string()));
+                }
+                for pair in hex.chunks(2) {
+                    let byte = std::str::from_utf8(pair)
+                        .ok()
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                        .ok_or_else(|| Error::Failed("Invalid data".to_string()))?;
+                    data.push(byte);
+                }
+                progress(Progress {
+                    done: std::cmp::min(data.len(), size),
+                    total: size,
+                });
+            }
+            self.wait_for_ack(7)?;
+            log::debug!("read {} of {size}", data.len());
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    /// Erase the whole log.
+    pub fn erase(&mut self) -> Result<()> {
+        self.send("PMTK182,6,1")?;
+        self.wait_for_ack(6)
+    }
+
+    /// Enable or disable logging.
+    pub fn set_logging(&mut self, enable: bool) -> Result<()> {
+        let command = if enable {
+            COMMAND_LOG_ENABLE
+        } else {
+            COMMAND_LOG_DISABLE
+        };
+        self.send(&format!("PMTK182,{command}"))?;
+        self.wait_for_ack(command)
+    }
+
+    /// Return whether logging is enabled.
+    pub fn logging(&mut self) -> Result<bool> {
+        Ok(self.query(QUERY_LOG_STATUS)? & LOG_STATUS_ON != 0)
+    }
+}
+
+/// Native driver for MTK based loggers.
+pub struct Mtk {
+    port: String,
+    cap: Capability,
+    variant: Variant,
+    timeouts: Timeouts,
+}
+
+impl Mtk {
+    pub fn new(port: &str, capability: Capability, variant: Variant, timeouts: Timeouts) -> Self {
+        Mtk {
+            port: port.to_owned(),
+            cap: capability,
+            variant,
+            timeouts,
+        }
+    }
+
+    /// Connect for an operation that must complete within `timeout`.
+    fn connect(&self, timeout: Duration) -> Result<Connection<Box<dyn serialport::SerialPort>>> {
+        let port = serialport::new(&self.port, self.variant.baud_rate())
+            .timeout(Duration::from_secs(2))
+            .open()
+            .map_err(io::Error::from)?;
+        let mut connection = Connection::new(port);
+        connection.set_timeouts(timeout, self.timeouts.inactivity);
+        Ok(connection)
+    }
+}
+
+impl Driver for Mtk {
+    fn open(&self) -> bool {
+        !self.port.is_empty()
+    }
+
+    fn close(&self) -> bool {
+        true
+    }
+
+    /// Download and decode the log.
+    fn download(
+        &self,
+        erase: bool,
+        shutoff: bool,
+        progress: &dyn Fn(Progress),
+        cancel: &CancelToken,
+    ) -> Result<GpsData> {
+        if (erase && !self.cap.can_erase) || shutoff {
+            return Err(Error::Unsupported);
+        }
+
+        let mut connection = self.connect(self.timeouts.download)?;
+        let size = connection.log_size()?;
+        log::debug!("log size {size}");
+        let data = connection.read_log(size, progress, cancel)?;
+        let log = decode(&data, self.variant);
+
+        // last chance: once started, erasing can't be interrupted.
+        cancel.check()?;
+        if erase {
+            // the device doesn't answer until the flash is erased.
+            connection.set_timeouts(self.timeouts.erase, self.timeouts.erase);
+            connection.erase()?;
+        }
+        Ok(log.into())
+    }
+
+    fn erase(&self, cancel: &CancelToken) -> Result<()> {
+        if !self.cap.can_erase_only {
+            return Err(Error::Unsupported);
+        }
+        // the device doesn't answer until the flash is erased.
+        let mut connection = self.connect(self.timeouts.erase)?;
+        connection.set_timeouts(self.timeouts.erase, self.timeouts.erase);
+        cancel.check()?;
+        connection.erase()
+    }
+
+    fn set_logging(&self, enable: bool) -> Result<()> {
+        if !self.cap.can_log_enable {
+            return Err(Error::Unsupported);
+        }
+        self.connect(self.timeouts.command)?.set_logging(enable)
+    }
+
+    fn logging(&self, cancel: &CancelToken) -> Result<bool> {
+        if !self.cap.can_log_enable {
+            return Err(Error::Unsupported);
+        }
+        let mut connection = self.connect(self.timeouts.command)?;
+        connection.set_cancel(cancel);
+        connection.logging()
+    }
+}
+
+#[cfg(test)]
+mod test_utils {
+    use std::io::{Cursor, Read, Write};
+
+    use super::{checksum, Variant, SECTOR_HEADER_SIZE};
+
+    /// Build a record with the UTC, VALID, LAT, LON and HEIGHT fields.
+    pub fn record(variant: Variant, time: u32, lat: f64, lon: f64, height: f32) -> Vec<u8> {
+        let mut record = vec![];
+        record.extend_from_slice(&time.to_le_bytes());
+        record.extend_from_slice(&2_u16.to_le_bytes());
+        match variant {
+            Variant::Mtk => {
+                record.extend_from_slice(&lat.to_le_bytes());
+                record.extend_from_slice(&lon.to_le_bytes());
+                record.extend_from_slice(&height.to_le_bytes());
+            }
+            Variant::Holux => {
+                record.extend_from_slice(&(lat as f32).to_le_bytes());
+                record.extend_from_slice(&(lon as f32).to_le_bytes());
+                record.extend_from_slice(&height.to_le_bytes()[1..4]);
+            }
+        }
+        let sum = checksum(&record);
+        if variant == Variant::Mtk {
+            record.push(b'*');
+        }
+        record.push(sum);
+        record
+    }
+
+    /// Build a sector with `records`.
+    pub fn sector(count: u16, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut sector = vec![0xff_u8; SECTOR_HEADER_SIZE];
+        sector[0..2].copy_from_slice(&count.to_le_bytes());
+        sector[2..6].copy_from_slice(&0x1f_u32.to_le_bytes());
+        for record in records {
+            sector.extend_from_slice(record);
+        }
+        sector
+    }
+
+    /// A fake serial port: reads from a script, records what is written.
+    pub struct FakePort {
+        pub input: Cursor<Vec<u8>>,
+        pub output: Vec<u8>,
+    }
+
+    impl FakePort {
+        pub fn new(sentences: &[&str]) -> Self {
+            let mut input = vec![];
+            for sentence in sentences {
+                input.extend_from_slice(
+                    format!("${}*{:02X}\r\n", sentence, checksum(sentence.as_bytes())).as_bytes(),
+                );
+            }
+            FakePort {
+                input: Cursor::new(input),
+                output: vec![],
+            }
+        }
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_checksum() {
+    assert_eq!(checksum(b"PMTK182,2,8"), 0x33);
+    assert_eq!(checksum(b"PMTK001,182,7,3"), 0x20);
+}
+
+#[test]
+fn test_decode() {
+    use test_utils::*;
+
+    for variant in [Variant::Mtk, Variant::Holux] {
+        let mut bad = record(variant, 1_700_000_100, 45.5, -73.5, 10.0);
+        let len = bad.len();
+        bad[len - 1] ^= 0xff;
+        let mut start = vec![0xaa_u8; 7];
+        start.push(SPECIAL_LOG_STARTSTOP);
+        start.extend_from_slice(&SPECIAL_LOG_START.to_le_bytes());
+        start.extend_from_slice(&[0xbb; 4]);
+        let data = sector(
+            0xffff,
+            &[
+                record(variant, 1_700_000_000, 45.5, -73.5, 32.0),
+                bad,
+                start,
+                record(variant, 1_700_000_200, 45.25, -73.25, 64.0),
+            ],
+        );
+
+        let log = decode(&data, variant);
+        assert_eq!(log.corrupted, 1);
+        assert_eq!(log.segments.len(), 2);
+        let point = &log.segments[0].points[0];
+        assert_eq!(point.time.unwrap().timestamp(), 1_700_000_000);
+        assert_eq!(point.lat, 45.5);
+        assert_eq!(point.lon, -73.5);
+        assert_eq!(point.elevation, Some(32.0));
+        assert_eq!(point.fix, Some(FixType::ThreeD));
+        assert_eq!(log.segments[1].points[0].lat, 45.25);
+        assert!(log.waypoints.is_empty());
+    }
+}
+
+#[test]
+fn test_decode_m241_fixture() {
+    let log = decode(include_bytes!("../testdata/mtk/m241.bin"), Variant::Holux);
+    assert_eq!(log.corrupted, 1);
+    assert_eq!(log.no_fix, 1);
+    assert_eq!(log.segments.len(), 2);
+    let times: Vec<i64> = log.segments[0]
+        .points
+        .iter()
+        .map(|point| point.time.unwrap().timestamp())
+        .collect();
+    assert_eq!(
+        times,
+        vec![1_714_824_000, 1_714_824_005, 1_714_824_015, 1_714_824_020]
+    );
+    let point = &log.segments[0].points[0];
+    assert_eq!(point.lat, 45.5);
+    assert_eq!(point.lon, -73.5);
+    assert_eq!(point.elevation, Some(30.5));
+    assert_eq!(point.fix, Some(FixType::ThreeD));
+    // single precision.
+    assert!((log.segments[0].points[1].lat - 45.50025).abs() < 1e-5);
+    assert_eq!(log.segments[0].points[2].fix, Some(FixType::Dgps));
+    // after the log format change.
+    let point = &log.segments[0].points[3];
+    assert_eq!(point.speed, Some(5.0));
+    assert_eq!(point.course, Some(90.0));
+    assert_eq!(point.elevation, Some(33.5));
+
+    let point = &log.segments[1].points[0];
+    assert_eq!(point.time.unwrap().timestamp(), 1_714_827_600);
+    assert_eq!((point.lat, point.lon), (46.0, -74.0));
+    assert!((point.speed.unwrap() - 1.0).abs() < 1e-6);
+
+    assert_eq!(log.waypoints.len(), 1);
+    assert_eq!(
+        log.waypoints[0].point.time.unwrap().timestamp(),
+        1_714_824_015
+    );
+}
+
+#[test]
+fn test_decode_mtk_fixture() {
+    let log = decode(include_bytes!("../testdata/mtk/mtk.bin"), Variant::Mtk);
+    assert_eq!(log.corrupted, 1);
+    assert_eq!(log.no_fix, 1);
+    assert_eq!(log.segments.len(), 1);
+    let points = &log.segments[0].points;
+    assert_eq!(points.len(), 2);
+    assert_eq!(points[0].lat, 45.123456789);
+    assert_eq!(points[0].lon, -73.987654321);
+    assert_eq!(points[0].elevation, Some(30.5));
+    assert_eq!(points[0].hdop, Some(1.2));
+    assert_eq!(points[0].satellites, Some(7));
+    assert_eq!(points[1].time.unwrap().timestamp(), 1_714_824_002);
+    assert_eq!(points[1].satellites, Some(9));
+    // RCR button.
+    assert_eq!(log.waypoints.len(), 1);
+    assert_eq!(log.waypoints[0].point.lat, 45.123556789);
+}
+
+#[test]
+fn test_read_log() {
+    use test_utils::*;
+
+    let port = FakePort::new(&[
+        "GPGGA,,,,,,0,,,,,,,,",
+        "PMTK182,3,8,00000004",
+        "PMTK001,182,2,3",
+        "PMTK182,8,00000000,0102",
+        "PMTK182,8,00000002,0304",
+        "PMTK001,182,7,3",
+    ]);
+    let mut connection = Connection::new(port);
+    assert_eq!(connection.log_size().unwrap(), 4);
+    let progress = std::cell::RefCell::new(vec![]);
+    assert_eq!(
+        connection
+            .read_log(
+                4,
+                &|p| progress.borrow_mut().push(p.done),
+                &CancelToken::default()
+            )
+            .unwrap(),
+        vec![1, 2, 3, 4]
+    );
+    assert_eq!(*progress.borrow(), vec![2, 4]);
+    assert_eq!(
+        String::from_utf8_lossy(&connection.stream.output),
+        "$PMTK182,2,8*33\r\n$PMTK182,7,00000000,00000004*26\r\n"
+    );
+
+    let cancel = CancelToken::default();
+    cancel.cancel();
+    assert!(matches!(
+        connection.read_log(4, &|_| {}, &cancel),
+        Err(Error::Cancelled)
+    ));
+}
+
+#[test]
+fn test_timeout() {
+    use test_utils::*;
+
+    let port = FakePort::new(&["GPGGA,,,,,,0,,,,,,,,", "PMTK182,3,8,00000004"]);
+    let mut connection = Connection::new(port);
+    connection.set_timeouts(Duration::ZERO, Duration::from_secs(30));
+    assert!(matches!(connection.log_size(), Err(Error::Timeout)));
+
+    let port = FakePort::new(&["GPGGA,,,,,,0,,,,,,,,", "PMTK182,3,8,00000004"]);
+    let mut connection = Connection::new(port);
+    connection.set_timeouts(Duration::from_secs(30), Duration::ZERO);
+    assert!(matches!(connection.log_size(), Err(Error::Timeout)));
+
+    let port = FakePort::new(&["PMTK182,3,7,00000006", "PMTK001,182,2,3"]);
+    let mut connection = Connection::new(port);
+    let cancel = CancelToken::default();
+    cancel.cancel();
+    connection.set_cancel(&cancel);
+    assert!(matches!(connection.logging(), Err(Error::Cancelled)));
+}
+
+#[test]
+fn test_logging() {
+    use test_utils::*;
+
+    let port = FakePort::new(&[
+        "PMTK182,3,7,00000006",
+        "PMTK001,182,2,3",
+        "PMTK001,182,5,3",
+        "PMTK001,182,4,1",
+    ]);
+    let mut connection = Connection::new(port);
+    assert!(connection.logging().unwrap());
+    assert!(connection.set_logging(false).is_ok());
+    assert!(matches!(
+        connection.set_logging(true),
+        Err(Error::Unsupported)
+    ));
+    assert_eq!(
+        String::from_utf8_lossy(&connection.stream.output),
+        "$PMTK182,2,7*3C\r\n$PMTK182,5*20\r\n$PMTK182,4*21\r\n"
+    );
+}
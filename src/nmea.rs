@@ -0,0 +1,448 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::{BufRead, Write};
+
+use chrono::{NaiveDate, NaiveTime, Timelike};
+
+use crate::drivers::Result;
+use crate::mtk::checksum;
+use crate::track::{FixType, GpsData, Track, TrackPoint, TrackSegment};
+
+/// Knots to m/s.
+const KNOT: f64 = 1852.0 / 3600.0;
+
+/// Why a sentence was rejected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Corruption {
+    /// The checksum doesn't match.
+    Checksum,
+    /// There is no checksum.
+    MissingChecksum,
+    /// The sentence can't be parsed.
+    Malformed,
+}
+
+/// A sentence rejected when reading.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Corrupted {
+    /// The line number, starting at 1.
+    pub line: usize,
+    pub sentence: String,
+    pub corruption: Corruption,
+}
+
+/// Write `sentence` with the delimiter and the checksum.
+fn write_sentence<W: Write>(out: &mut W, sentence: &str) -> io::Result<()> {
+    write!(out, "${sentence}*{:02X}\r\n", checksum(sentence.as_bytes()))
+}
+
+/// Format `degrees` as NMEA: (d)ddmm.mmmmmm and the hemisphere.
+fn format_position(degrees: f64, deg_width: usize, hemispheres: (char, char)) -> String {
+    let micro_minutes = (degrees.abs() * 60_000_000.0).round() as u64;
+    let deg = micro_minutes / 60_000_000;
+    let minutes = (micro_minutes % 60_000_000) as f64 / 1_000_000.0;
+    let hemisphere = if degrees < 0.0 {
+        hemispheres.1
+    } else {
+        hemispheres.0
+    };
+    format!("{deg:0deg_width$}{minutes:09.6},{hemisphere}")
+}
+
+fn fix_quality(fix: Option<FixType>) -> u8 {
+    match fix {
+        Some(FixType::None) => 0,
+        Some(FixType::Dgps) => 2,
+        Some(FixType::Pps) => 3,
+        _ => 1,
+    }
+}
+
+fn fix_type(quality: u8) -> FixType {
+    match quality {
+        0 => FixType::None,
+        2 => FixType::Dgps,
+        3 => FixType::Pps,
+        _ => FixType::ThreeD,
+    }
+}
+
+/// Write the track points of `data` as $GPRMC and $GPGGA sentences.
+/// Points without a time and waypoints are ignored.
+pub fn write<W: Write>(out: &mut W, data: &GpsData) -> io::Result<()> {
+    for point in data.points() {
+        let time = match point.time {
+            Some(time) => time,
+            None => continue,
+        };
+        let hms = format!(
+            "{}.{:03}",
+            time.format("%H%M%S"),
+            time.nanosecond() / 1_000_000
+        );
+        let lat = format_position(point.lat, 2, ('N', 'S'));
+        let lon = format_position(point.lon, 3, ('E', 'W'));
+        let optional = |value: Option<f64>, precision: usize| {
+            value
+                .map(|v| format!("{v:.precision$}"))
+                .unwrap_or_default()
+        };
+
+        let status = if point.fix == Some(FixType::None) {
+            'V'
+        } else {
+            'A'
+        };
+        write_sentence(
+            out,
+            &format!(
+                "GPRMC,{hms},{status},{lat},{lon},{},{},{},,,A",
+                optional(point.speed.map(|s| s / KNOT), 3),
+                optional(point.course, 2),
+                time.format("%d%m%y"),
+            ),
+        )?;
+        write_sentence(
+            out,
+            &format!(
+                "GPGGA,{hms},{lat},{lon},{},{},{},{},M,,M,,",
+                fix_quality(point.fix),
+                point
+                    .satellites
+                    .map(|sat| format!("{sat:02}"))
+                    .unwrap_or_default(),
+                optional(point.hdop, 2),
+                optional(point.altitude(), 1),
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+/// Parse an NMEA position and its hemisphere.
+fn parse_position(value: &str, hemisphere: &str) -> Option<f64> {
+    let value: f64 = value.parse().ok()?;
+    let deg = (value / 100.0).trunc();
+    let degrees = deg + (value - deg * 100.0) / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(degrees),
+        "S" | "W" => Some(-degrees),
+        _ => None,
+    }
+}
+
+fn parse_optional<T: std::str::FromStr>(value: &str) -> std::result::Result<Option<T>, ()> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        value.parse().map(Some).map_err(|_| ())
+    }
+}
+
+/// Assemble the points from the sentences. Sentences with the same
+/// time make one point.
+#[derive(Default)]
+struct Parser {
+    date: Option<NaiveDate>,
+    current: Option<(NaiveTime, TrackPoint)>,
+    points: Vec<TrackPoint>,
+}
+
+impl Parser {
+    fn flush(&mut self) {
+        if let Some((time, mut point)) = self.current.take() {
+            point.time = self.date.map(|date| date.and_time(time).and_utc());
+            self.points.push(point);
+        }
+    }
+
+    /// The point at `time`.
+    fn point(&mut self, time: NaiveTime) -> &mut TrackPoint {
+        if self.current.as_ref().map(|c| c.0) != Some(time) {
+            self.flush();
+            self.current = Some((time, TrackPoint::default()));
+        }
+        &mut self.current.as_mut().unwrap().1
+    }
+
+    fn rmc(&mut self, fields: &[&str]) -> std::result::Result<(), ()> {
+        if fields.len() < 10 {
+            return Err(());
+        }
+        // No position, no fix.
+        if fields[3].is_empty() {
+            return Ok(());
+        }
+        let time = NaiveTime::parse_from_str(fields[1], "%H%M%S%.f").map_err(|_| ())?;
+        let date = NaiveDate::parse_from_str(fields[9], "%d%m%y").map_err(|_| ())?;
+        let lat = parse_position(fields[3], fields[4]).ok_or(())?;
+        let lon = parse_position(fields[5], fields[6]).ok_or(())?;
+        let speed = parse_optional::<f64>(fields[7])?;
+        let course = parse_optional(fields[8])?;
+
+        self.date = Some(date);
+        let point = self.point(time);
+        point.lat = lat;
+        point.lon = lon;
+        point.speed = speed.map(|s| s * KNOT);
+        point.course = course;
+        if fields[2] == "V" {
+            point.fix = Some(FixType::None);
+        }
+        Ok(())
+    }
+
+    fn gga(&mut self, fields: &[&str]) -> std::result::Result<(), ()> {
+        if fields.len() < 10 {
+            return Err(());
+        }
+        if fields[2].is_empty() {
+            return Ok(());
+        }
+        let time = NaiveTime::parse_from_str(fields[1], "%H%M%S%.f").map_err(|_| ())?;
+        let lat = parse_position(fields[2], fields[3]).ok_or(())?;
+        let lon = parse_position(fields[4], fields[5]).ok_or(())?;
+        let quality = parse_optional::<u8>(fields[6])?;
+        let satellites = parse_optional(fields[7])?;
+        let hdop = parse_optional(fields[8])?;
+        let elevation = parse_optional(fields[9])?;
+
+        let point = self.point(time);
+        point.lat = lat;
+        point.lon = lon;
+        point.fix = quality.map(fix_type);
+        point.satellites = satellites;
+        point.hdop = hdop;
+        point.elevation = elevation;
+        Ok(())
+    }
+
+    /// Parse the sentence body, without the '$' and the checksum.
+    fn sentence(&mut self, body: &str) -> std::result::Result<(), ()> {
+        let fields: Vec<&str> = body.split(',').collect();
+        // Any talker: GP, GN, GL...
+        match fields[0].get(2..) {
+            Some("RMC") => self.rmc(&fields),
+            Some("GGA") => self.gga(&fields),
+            Some(_) => Ok(()),
+            None => Err(()),
+        }
+    }
+}
+
+/// Read the NMEA sentences from `input`. All the points are in one
+/// segment. Return the data and the sentences that were rejected. A
+/// line that isn't UTF-8 is rejected as malformed.
+pub fn read<R: BufRead>(mut input: R) -> Result<(GpsData, Vec<Corrupted>)> {
+    let mut parser = Parser::default();
+    let mut corrupted = vec![];
+    let mut buffer = vec![];
+    for idx in 0.. {
+        buffer.clear();
+        if input.read_until(b'\n', &mut buffer)? == 0 {
+            break;
+        }
+        // keep the garbled sentence to report it.
+        let utf8 = std::str::from_utf8(&buffer).is_ok();
+        let line = String::from_utf8_lossy(&buffer);
+        let sentence = line.trim();
+        if sentence.is_empty() {
+            continue;
+        }
+        let result = match sentence.strip_prefix('$').map(|s| s.rsplit_once('*')) {
+            _ if !utf8 => Err(Corruption::Malformed),
+            Some(Some((body, sum))) => {
+                if u8::from_str_radix(sum, 16).ok() != Some(checksum(body.as_bytes())) {
+                    Err(Corruption::Checksum)
+                } else {
+                    parser.sentence(body).map_err(|_| Corruption::Malformed)
+                }
+            }
+            Some(None) => Err(Corruption::MissingChecksum),
+            None => Err(Corruption::Malformed),
+        };
+        if let Err(corruption) = result {
+            log::warn!("NMEA line {}: {corruption:?} '{sentence}'", idx + 1);
+            corrupted.push(Corrupted {
+                line: idx + 1,
+                sentence: sentence.to_string(),
+                corruption,
+            });
+        }
+    }
+    parser.flush();
+
+    let mut data = GpsData::default();
+    if !parser.points.is_empty() {
+        data.tracks.push(Track {
+            name: None,
+            segments: vec![TrackSegment {
+                points: parser.points,
+            }],
+        });
+    }
+    Ok((data, corrupted))
+}
+
+#[test]
+fn test_write() {
+    use crate::track::{test_data, test_point};
+
+    let point = TrackPoint {
+        speed: Some(1852.0 / 3600.0),
+        course: Some(90.0),
+        hdop: Some(1.1),
+        satellites: Some(7),
+        fix: Some(FixType::ThreeD),
+        ..test_point(0)
+    };
+    // no time: skipped.
+    let data = test_data(vec![vec![point, TrackPoint::default()]]);
+
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write NMEA");
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "$GPRMC,120000.000,A,4530.000000,N,07330.000000,W,1.000,90.00,040524,,,A*75\r\n\
+         $GPGGA,120000.000,4530.000000,N,07330.000000,W,1,07,1.10,30.5,M,,M,,*57\r\n"
+    );
+
+    // the minutes round up to the next degree.
+    assert_eq!(
+        format_position(45.999_999_999_9, 2, ('N', 'S')),
+        "4600.000000,N"
+    );
+    assert_eq!(
+        format_position(-0.000_000_001, 3, ('E', 'W')),
+        "00000.000000,W"
+    );
+}
+
+#[test]
+fn test_read() {
+    use chrono::DateTime;
+
+    let nmea = "$GPRMC,120000.000,A,4530.000000,N,07330.000000,W,1.000,90.00,040524,,,A*75\r
+$GPGGA,120000.000,4530.000000,N,07330.000000,W,1,07,1.10,30.5,M,,M,,*57\r
+$GPGSA,A,3,01,02,03,04,05,06,07,,,,,,1.80,1.10,1.40*0E\r
+$GPRMC,120001.000,A,4530.000000,N,07330.000000,W,1.000,90.00,040524,,,A*FF\r
+$GPRMC,120002.000,A,4530.000000,N,07330.000000,W,1.000,90.00,040524,,,A\r
+GPRMC,garbage\r
+$GPRMC,120003.000,A,4530.0,N*46\r
+\r
+$GNRMC,120004.500,A,4530.600000,S,07330.000000,E,,,040524,,,A*6B\r
+";
+    let (data, corrupted) = read(nmea.as_bytes()).expect("Failed to read NMEA");
+    let points: Vec<&TrackPoint> = data.points().collect();
+    assert_eq!(points.len(), 2);
+    assert_eq!(points[0].time, DateTime::from_timestamp(1_714_824_000, 0));
+    assert!((points[0].lat - 45.5).abs() < 1e-9);
+    assert!((points[0].lon + 73.5).abs() < 1e-9);
+    assert!((points[0].speed.unwrap() - KNOT).abs() < 1e-9);
+    assert_eq!(points[0].course, Some(90.0));
+    assert_eq!(points[0].satellites, Some(7));
+    assert_eq!(points[0].hdop, Some(1.1));
+    assert_eq!(points[0].elevation, Some(30.5));
+    assert_eq!(points[0].fix, Some(FixType::ThreeD));
+    assert_eq!(
+        points[1].time,
+        DateTime::from_timestamp_millis(1_714_824_004_500)
+    );
+    assert!((points[1].lat + 45.51).abs() < 1e-9);
+    assert_eq!(points[1].speed, None);
+
+    let corruptions: Vec<(usize, Corruption)> =
+        corrupted.iter().map(|c| (c.line, c.corruption)).collect();
+    assert_eq!(
+        corruptions,
+        vec![
+            (4, Corruption::Checksum),
+            (5, Corruption::MissingChecksum),
+            (6, Corruption::Malformed),
+            (7, Corruption::Malformed),
+        ]
+    );
+
+    // Garbled bytes in a sentence: the next ones are still read.
+    let mut nmea =
+        b"$GPRMC,120000.000,A,4530.0\xff0000,N,07330.000000,W,,,040524,,,A*75\r\n".to_vec();
+    nmea.extend_from_slice(b"$GNRMC,120004.500,A,4530.600000,S,07330.000000,E,,,040524,,,A*6B\r\n");
+    let (data, corrupted) = read(nmea.as_slice()).expect("Failed to read NMEA");
+    assert_eq!(data.points().count(), 1);
+    assert_eq!(corrupted.len(), 1);
+    assert_eq!(corrupted[0].line, 1);
+    assert_eq!(corrupted[0].corruption, Corruption::Malformed);
+}
+
+#[test]
+fn test_round_trip() {
+    use chrono::DateTime;
+
+    use crate::track::test_point;
+
+    // the southern and eastern hemispheres.
+    let point = TrackPoint {
+        lat: -33.856784,
+        lon: 151.215297,
+        elevation: Some(12.5),
+        speed: Some(2.5),
+        course: Some(271.25),
+        hdop: Some(0.9),
+        satellites: Some(11),
+        fix: Some(FixType::Dgps),
+        ..test_point(0)
+    };
+    let point2 = TrackPoint {
+        time: DateTime::from_timestamp_millis(1_714_824_001_250),
+        elevation: None,
+        speed: None,
+        course: None,
+        hdop: None,
+        satellites: None,
+        fix: None,
+        ..point.clone()
+    };
+    let data = GpsData {
+        tracks: vec![Track {
+            name: None,
+            segments: vec![TrackSegment {
+                points: vec![point.clone(), point2.clone()],
+            }],
+        }],
+        waypoints: vec![],
+    };
+
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write NMEA");
+    let (data2, corrupted) = read(output.as_slice()).expect("Failed to read NMEA");
+    assert!(corrupted.is_empty());
+    let points: Vec<&TrackPoint> = data2.points().collect();
+    assert_eq!(points.len(), 2);
+    assert_eq!(points[0].time, point.time);
+    assert!((points[0].lat - point.lat).abs() < 1e-7);
+    assert!((points[0].lon - point.lon).abs() < 1e-7);
+    assert!((points[0].speed.unwrap() - 2.5).abs() < 1e-3);
+    assert_eq!(points[0].course, point.course);
+    assert_eq!(points[0].hdop, point.hdop);
+    assert_eq!(points[0].satellites, point.satellites);
+    assert_eq!(points[0].elevation, point.elevation);
+    assert_eq!(points[0].fix, point.fix);
+    assert_eq!(points[1].time, point2.time);
+    assert_eq!(points[1].elevation, None);
+    assert_eq!(points[1].fix, Some(FixType::ThreeD));
+}
@@ -0,0 +1,52 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The settings file, shared by the application and the command line.
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use gtk4::glib;
+
+/// The group of the last points downloaded, by device key.
+const DOWNLOADS_GROUP: &str = "downloads";
+
+/// The directory of the settings.
+pub fn dir() -> PathBuf {
+    // XXX replace this by glib stuff when we can.
+    // Also we treat a failure of this as fatal.
+    let mut path: PathBuf = dirs::home_dir().expect("Can't locate home_dir");
+    path.push(".gpsami");
+    path
+}
+
+/// The settings file.
+pub fn file() -> PathBuf {
+    dir().join("gpsami.ini")
+}
+
+/// The time of the last point downloaded from the device `key`.
+pub fn last_point(store: &glib::KeyFile, key: &str) -> Option<DateTime<Utc>> {
+    let last = store.string(DOWNLOADS_GROUP, key).ok()?;
+    DateTime::parse_from_rfc3339(&last)
+        .ok()
+        .map(|last| last.with_timezone(&Utc))
+}
+
+/// Record the time of the last point downloaded from the device `key`.
+pub fn set_last_point(store: &glib::KeyFile, key: &str, last: DateTime<Utc>) {
+    store.set_string(DOWNLOADS_GROUP, key, &last.to_rfc3339());
+}
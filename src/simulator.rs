@@ -0,0 +1,559 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serialport::{SerialPort, TTYPort};
+
+use crate::mtk::{checksum, Variant};
+use crate::track::TrackPoint;
+
+/// How long the simulator waits for the host before calling `idle()`.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Size of the MTK sector header.
+const MTK_SECTOR_HEADER_SIZE: usize = 0x200;
+/// MTK log format of the records in `mtk_flash()`: UTC, VALID, LAT,
+/// LON and HEIGHT.
+const MTK_LOG_FORMAT: u32 = 0x1f;
+/// Bytes of flash per PMTK182,8 sentence.
+const MTK_DATA_CHUNK: usize = 0x200;
+/// Size of a SkyTraq log sector.
+pub const SKYTRAQ_SECTOR_SIZE: usize = 4096;
+
+/// A simulated device: it answers what the host writes to the port.
+pub trait Device: Send {
+    /// Handle `input` received from the host. Return the bytes to send
+    /// back. The input may be split anywhere.
+    fn receive(&mut self, input: &[u8]) -> Vec<u8>;
+    /// The bytes to send when the host has been silent, like the
+    /// positions a logger outputs.
+    fn idle(&mut self) -> Vec<u8> {
+        vec![]
+    }
+}
+
+/// A device simulated on a pseudo terminal. The drivers open `path()`
+/// like a serial port, as many times as needed. Stopped when dropped.
+pub struct Simulator<D: Device + 'static> {
+    path: String,
+    device: Arc<Mutex<D>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl<D: Device + 'static> Simulator<D> {
+    pub fn start(device: D) -> io::Result<Self> {
+        let (mut master, slave) = TTYPort::pair().map_err(io::Error::from)?;
+        master.set_timeout(POLL_INTERVAL).map_err(io::Error::from)?;
+        let path = slave
+            .name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No pseudo terminal name"))?;
+        // Until a driver opens the port, reading fails: nobody is connected.
+        drop(slave);
+        let device = Arc::new(Mutex::new(device));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new().name("simulator".into()).spawn({
+            let device = device.clone();
+            let stop = stop.clone();
+            move || Self::run(master, &device, &stop)
+        })?;
+        Ok(Simulator {
+            path,
+            device,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The path of the port to open.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The simulated device, to check its state.
+    pub fn device(&self) -> std::sync::MutexGuard<'_, D> {
+        self.device.lock().unwrap()
+    }
+
+    fn run(mut master: TTYPort, device: &Mutex<D>, stop: &AtomicBool) {
+        let mut buf = [0_u8; 1024];
+        while !stop.load(Ordering::Relaxed) {
+            let output = match master.read(&mut buf) {
+                Ok(len) => device.lock().unwrap().receive(&buf[0..len]),
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => device.lock().unwrap().idle(),
+                Err(_) => {
+                    // Not connected.
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            if let Err(err) = master.write_all(&output) {
+                log::debug!("simulator write error {err}");
+            }
+        }
+    }
+}
+
+impl<D: Device + 'static> Drop for Simulator<D> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Format an NMEA sentence, with the `$`, the checksum and the line end.
+pub fn sentence(body: &str) -> String {
+    format!("${body}*{:02X}\r\n", checksum(body.as_bytes()))
+}
+
+/// Split the complete lines out of `buffer`.
+fn take_lines(buffer: &mut Vec<u8>) -> Vec<String> {
+    let mut lines = vec![];
+    while let Some(idx) = buffer.iter().position(|c| *c == b'\n') {
+        let line: Vec<u8> = buffer.drain(0..=idx).collect();
+        lines.push(String::from_utf8_lossy(&line).trim().to_string());
+    }
+    lines
+}
+
+/// Build the flash of an MTK logger with `points` in one sector.
+pub fn mtk_flash(variant: Variant, points: &[TrackPoint]) -> Vec<u8> {
+    let mut flash = vec![0xff_u8; MTK_SECTOR_HEADER_SIZE];
+    flash[0..2].copy_from_slice(&(points.len() as u16).to_le_bytes());
+    flash[2..6].copy_from_slice(&MTK_LOG_FORMAT.to_le_bytes());
+    for point in points {
+        let mut record = vec![];
+        let time = point.time.map(|time| time.timestamp() as u32).unwrap_or(0);
+        record.extend_from_slice(&time.to_le_bytes());
+        record.extend_from_slice(&2_u16.to_le_bytes());
+        let height = point.elevation.unwrap_or(0.0) as f32;
+        match variant {
+            Variant::Mtk => {
+                record.extend_from_slice(&point.lat.to_le_bytes());
+                record.extend_from_slice(&point.lon.to_le_bytes());
+                record.extend_from_slice(&height.to_le_bytes());
+            }
+            Variant::Holux => {
+                record.extend_from_slice(&(point.lat as f32).to_le_bytes());
+                record.extend_from_slice(&(point.lon as f32).to_le_bytes());
+                record.extend_from_slice(&height.to_le_bytes()[1..4]);
+            }
+        }
+        let sum = checksum(&record);
+        if variant == Variant::Mtk {
+            record.push(b'*');
+        }
+        record.push(sum);
+        flash.extend_from_slice(&record);
+    }
+    flash
+}
+
+/// An MTK logger, answering the PMTK182 commands.
+#[derive(Debug, Default)]
+pub struct MtkLogger {
+    /// The content of the log flash.
+    pub flash: Vec<u8>,
+    pub logging: bool,
+    /// The sentences received, without `$` and checksum.
+    pub received: Vec<String>,
+    buffer: Vec<u8>,
+}
+
+impl MtkLogger {
+    pub fn new(flash: Vec<u8>) -> Self {
+        MtkLogger {
+            flash,
+            logging: true,
+            ..MtkLogger::default()
+        }
+    }
+
+    fn ack(command: &str, status: u8) -> String {
+        sentence(&format!("PMTK001,182,{command},{status}"))
+    }
+
+    /// The answer to the PMTK182 command `fields`.
+    fn answer(&mut self, fields: &[&str]) -> String {
+        match fields {
+            ["2", "7"] => {
+                let status = if self.logging { 0x02 } else { 0 };
+                sentence(&format!("PMTK182,3,7,{status:08X}")) + &Self::ack("2", 3)
+            }
+            ["2", "8"] => {
+                sentence(&format!("PMTK182,3,8,{:08X}", self.flash.len())) + &Self::ack("2", 3)
+            }
+            ["7", address, len] => {
+                let address = usize::from_str_radix(address, 16).unwrap_or(0);
+                let len = usize::from_str_radix(len, 16).unwrap_or(0);
+                let mut answer = String::new();
+                for start in (address..address + len).step_by(MTK_DATA_CHUNK) {
+                    let end = std::cmp::min(start + MTK_DATA_CHUNK, address + len);
+                    let hex: String = (start..end)
+                        .map(|i| format!("{:02X}", self.flash.get(i).unwrap_or(&0xff)))
+                        .collect();
+                    answer += &sentence(&format!("PMTK182,8,{start:08X},{hex}"));
+                }
+                answer + &Self::ack("7", 3)
+            }
+            ["6", "1"] => {
+                self.flash.clear();
+                Self::ack("6", 3)
+            }
+            ["4"] | ["5"] => {
+                self.logging = fields[0] == "4";
+                Self::ack(fields[0], 3)
+            }
+            [command, ..] => Self::ack(command, 1),
+            [] => String::new(),
+        }
+    }
+}
+
+impl Device for MtkLogger {
+    fn receive(&mut self, input: &[u8]) -> Vec<u8> {
+        self.buffer.extend_from_slice(input);
+        let mut output = String::new();
+        for line in take_lines(&mut self.buffer) {
+            let body = match line.strip_prefix('$').and_then(|l| l.rsplit_once('*')) {
+                Some((body, sum))
+                    if u8::from_str_radix(sum, 16).ok() == Some(checksum(body.as_bytes())) =>
+                {
+                    body.to_string()
+                }
+                _ => continue,
+            };
+            self.received.push(body.clone());
+            let fields: Vec<&str> = body.split(',').collect();
+            if fields.first() == Some(&"PMTK182") {
+                // The position the logger outputs, to skip.
+                output += &sentence("GPGGA,,,,,,0,,,,,,,,");
+                output += &self.answer(&fields[1..]);
+            }
+        }
+        output.into_bytes()
+    }
+}
+
+/// A logger that outputs NMEA sentences in a loop.
+#[derive(Debug, Default)]
+pub struct NmeaLogger {
+    /// The sentences, without `$` and checksum.
+    pub sentences: Vec<String>,
+    next: usize,
+}
+
+impl NmeaLogger {
+    pub fn new(sentences: &[&str]) -> Self {
+        NmeaLogger {
+            sentences: sentences.iter().map(|s| s.to_string()).collect(),
+            next: 0,
+        }
+    }
+}
+
+impl Device for NmeaLogger {
+    fn receive(&mut self, _input: &[u8]) -> Vec<u8> {
+        vec![]
+    }
+
+    fn idle(&mut self) -> Vec<u8> {
+        if self.sentences.is_empty() {
+            return vec![];
+        }
+        let output = sentence(&self.sentences[self.next]);
+        self.next = (self.next + 1) % self.sentences.len();
+        output.into_bytes()
+    }
+}
+
+/// SkyTraq message: query the software version.
+const SKYTRAQ_QUERY_VERSION: u8 = 0x02;
+/// SkyTraq message: configure the serial port.
+const SKYTRAQ_CONFIGURE_PORT: u8 = 0x05;
+/// SkyTraq message: query the log status.
+const SKYTRAQ_QUERY_LOG_STATUS: u8 = 0x17;
+/// SkyTraq message: clear the log.
+const SKYTRAQ_CLEAR_LOG: u8 = 0x19;
+/// SkyTraq message: read a log sector.
+const SKYTRAQ_READ_SECTOR: u8 = 0x1b;
+/// SkyTraq answer: the software version.
+const SKYTRAQ_VERSION: u8 = 0x80;
+const SKYTRAQ_ACK: u8 = 0x83;
+const SKYTRAQ_NACK: u8 = 0x84;
+/// SkyTraq answer: the log status.
+const SKYTRAQ_LOG_STATUS: u8 = 0x94;
+
+/// Frame a SkyTraq binary message.
+pub fn skytraq_message(payload: &[u8]) -> Vec<u8> {
+    let mut message = vec![0xa0, 0xa1];
+    message.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    message.extend_from_slice(payload);
+    message.push(checksum(payload));
+    message.extend_from_slice(b"\r\n");
+    message
+}
+
+/// A SkyTraq Venus logger, answering the binary messages. It only
+/// covers the protocol: the SkyTraq driver is gpsbabel, which the
+/// tests don't run.
+#[derive(Debug, Default)]
+pub struct SkytraqLogger {
+    /// The log sectors, `SKYTRAQ_SECTOR_SIZE` bytes each.
+    pub sectors: Vec<Vec<u8>>,
+    /// The total number of sectors of the flash.
+    pub total_sectors: u16,
+    /// The ids of the messages received.
+    pub received: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl SkytraqLogger {
+    pub fn new(sectors: Vec<Vec<u8>>) -> Self {
+        SkytraqLogger {
+            sectors,
+            total_sectors: 64,
+            ..SkytraqLogger::default()
+        }
+    }
+
+    /// The next complete payload in the buffer. Garbage before the
+    /// start of a message and messages with a bad checksum are dropped.
+    fn next_payload(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let start = self.buffer.windows(2).position(|w| w == [0xa0, 0xa1])?;
+            self.buffer.drain(0..start);
+            let len = u16::from_be_bytes([*self.buffer.get(2)?, *self.buffer.get(3)?]) as usize;
+            if self.buffer.len() < len + 7 {
+                return None;
+            }
+            let message: Vec<u8> = self.buffer.drain(0..len + 7).collect();
+            let payload = &message[4..4 + len];
+            if !payload.is_empty() && checksum(payload) == message[4 + len] {
+                return Some(payload.to_vec());
+            }
+        }
+    }
+
+    fn answer(&mut self, payload: &[u8]) -> Vec<u8> {
+        let id = payload[0];
+        self.received.push(id);
+        let mut output = skytraq_message(&[SKYTRAQ_ACK, id]);
+        match (id, payload.get(1)) {
+            (SKYTRAQ_QUERY_VERSION, _) => {
+                let mut version = vec![SKYTRAQ_VERSION, 0x01];
+                version.extend_from_slice(&[0, 1, 0, 0, 0, 1, 0, 0, 0, 0x0b, 0x05, 0x04]);
+                output.extend(skytraq_message(&version));
+            }
+            (SKYTRAQ_CONFIGURE_PORT, _) => {}
+            (SKYTRAQ_QUERY_LOG_STATUS, _) => {
+                let used = self.sectors.len() as u16;
+                let mut status = vec![SKYTRAQ_LOG_STATUS];
+                let write_pointer = self.sectors.len() * SKYTRAQ_SECTOR_SIZE;
+                status.extend_from_slice(&(write_pointer as u32).to_le_bytes());
+                status.extend_from_slice(&(self.total_sectors - used).to_le_bytes());
+                status.extend_from_slice(&self.total_sectors.to_le_bytes());
+                // Time, distance and speed thresholds: max and min.
+                for threshold in [3600_u32, 5, 100, 0, 200, 0] {
+                    status.extend_from_slice(&threshold.to_le_bytes());
+                }
+                // Logging enabled, no FIFO mode.
+                status.extend_from_slice(&[1, 0]);
+                output.extend(skytraq_message(&status));
+            }
+            (SKYTRAQ_CLEAR_LOG, _) => self.sectors.clear(),
+            (SKYTRAQ_READ_SECTOR, Some(&sector)) => {
+                let mut data = self
+                    .sectors
+                    .get(sector as usize)
+                    .cloned()
+                    .unwrap_or_default();
+                data.resize(SKYTRAQ_SECTOR_SIZE, 0xff);
+                let sum = checksum(&data);
+                output.extend_from_slice(&data);
+                output.extend_from_slice(b"END\0CHECKSUM=");
+                output.push(sum);
+                output.extend_from_slice(b"\r\n");
+            }
+            _ => output = skytraq_message(&[SKYTRAQ_NACK, id]),
+        }
+        output
+    }
+}
+
+impl Device for SkytraqLogger {
+    fn receive(&mut self, input: &[u8]) -> Vec<u8> {
+        self.buffer.extend_from_slice(input);
+        let mut output = vec![];
+        while let Some(payload) = self.next_payload() {
+            output.extend(self.answer(&payload));
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+fn points() -> Vec<TrackPoint> {
+    (0..3)
+        .map(|i| TrackPoint {
+            lat: 45.5 + i as f64 / 1024.0,
+            ..crate::track::test_point(i * 60)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn open(path: &str) -> Box<dyn SerialPort> {
+    serialport::new(path, 115_200)
+        .timeout(Duration::from_secs(2))
+        .open()
+        .expect("Failed to open the simulator")
+}
+
+#[test]
+fn test_mtk_driver() {
+    use crate::devices::Capability;
+    use crate::drivers::{CancelToken, Driver, Timeouts};
+    use crate::mtk::Mtk;
+
+    let simulator = Simulator::start(MtkLogger::new(mtk_flash(Variant::Mtk, &points())))
+        .expect("Failed to start the simulator");
+    let cap = Capability {
+        can_erase: true,
+        can_erase_only: true,
+        can_log_enable: true,
+        ..Capability::default()
+    };
+    let driver = Mtk::new(simulator.path(), cap, Variant::Mtk, Timeouts::default());
+    assert!(driver.open());
+
+    let data = driver
+        .download(false, false, &|_| {}, &CancelToken::default())
+        .expect("Download failed");
+    let downloaded: Vec<&TrackPoint> = data.points().collect();
+    assert_eq!(downloaded.len(), 3);
+    for (point, expected) in downloaded.iter().zip(points()) {
+        assert_eq!(point.time, expected.time);
+        assert_eq!(point.lat, expected.lat);
+        assert_eq!(point.elevation, expected.elevation);
+    }
+
+    assert!(driver.logging(&CancelToken::default()).unwrap());
+    driver.set_logging(false).unwrap();
+    assert!(!driver.logging(&CancelToken::default()).unwrap());
+    assert!(!simulator.device().logging);
+
+    driver.erase(&CancelToken::default()).unwrap();
+    assert!(simulator.device().flash.is_empty());
+    let data = driver
+        .download(false, false, &|_| {}, &CancelToken::default())
+        .expect("Download failed");
+    assert!(data.is_empty());
+}
+
+#[test]
+fn test_manager() {
+    use crate::devices::{DeviceDb, Manager};
+    use crate::drivers::CancelToken;
+
+    // The M-241 is a Holux, with the native driver.
+    let simulator = Simulator::start(MtkLogger::new(mtk_flash(Variant::Holux, &points())))
+        .expect("Failed to start the simulator");
+    let mut manager = Manager::with_database(DeviceDb::builtin());
+    manager.set_model("m241");
+    manager.set_port(simulator.path());
+    let device = manager.get_device().expect("No driver");
+    assert!(device.open());
+    let data = device
+        .download(true, false, &|_| {}, &CancelToken::default())
+        .expect("Download failed");
+    assert_eq!(data.points().count(), 3);
+    device.close();
+    let device = simulator.device();
+    assert!(device.flash.is_empty());
+    assert_eq!(
+        device.received,
+        vec!["PMTK182,2,8", "PMTK182,7,00000000,00000236", "PMTK182,6,1"]
+    );
+}
+
+#[test]
+fn test_nmea_logger() {
+    use std::io::{BufRead, BufReader};
+
+    let simulator = Simulator::start(NmeaLogger::new(&[
+        "GPRMC,120000,A,4530.000,N,07330.000,W,0.0,0.0,040524,,",
+        "GPGGA,120000,4530.000,N,07330.000,W,1,07,1.1,32.0,M,,M,,",
+    ]))
+    .expect("Failed to start the simulator");
+    let mut port = BufReader::new(open(simulator.path()));
+    let mut log = String::new();
+    while log.lines().count() < 4 {
+        port.read_line(&mut log).expect("Read failed");
+    }
+    // Skip the first line: it may be partial.
+    let log: Vec<&str> = log.lines().skip(1).collect();
+    let (data, corrupted) = crate::nmea::read(log.join("\n").as_bytes()).unwrap();
+    assert!(corrupted.is_empty());
+    let point = data.points().next().expect("No point");
+    assert_eq!(point.lat, 45.5);
+    assert_eq!(point.lon, -73.5);
+}
+
+#[test]
+fn test_skytraq_logger() {
+    let sector = vec![0x42_u8; 100];
+    let simulator =
+        Simulator::start(SkytraqLogger::new(vec![sector])).expect("Failed to start the simulator");
+    let mut port = open(simulator.path());
+    let mut port2 = port.try_clone().unwrap();
+    let mut read = |len: usize| {
+        let mut buf = vec![0_u8; len];
+        port.read_exact(&mut buf).expect("Read failed");
+        buf
+    };
+
+    port2
+        .write_all(&skytraq_message(&[SKYTRAQ_QUERY_LOG_STATUS]))
+        .unwrap();
+    assert_eq!(read(9), skytraq_message(&[SKYTRAQ_ACK, 0x17]));
+    let status = read(7 + 35);
+    assert_eq!(status[4], SKYTRAQ_LOG_STATUS);
+    assert_eq!(status[5..9], 4096_u32.to_le_bytes());
+    assert_eq!(status[9..11], 63_u16.to_le_bytes());
+
+    port2
+        .write_all(&skytraq_message(&[SKYTRAQ_READ_SECTOR, 0]))
+        .unwrap();
+    assert_eq!(read(9), skytraq_message(&[SKYTRAQ_ACK, 0x1b]));
+    let data = read(SKYTRAQ_SECTOR_SIZE + 16);
+    assert_eq!(data[0..100], [0x42; 100]);
+    assert_eq!(data[100], 0xff);
+    assert_eq!(
+        &data[SKYTRAQ_SECTOR_SIZE..SKYTRAQ_SECTOR_SIZE + 13],
+        b"END\0CHECKSUM="
+    );
+
+    port2.write_all(&skytraq_message(&[0x7f])).unwrap();
+    assert_eq!(read(9), skytraq_message(&[SKYTRAQ_NACK, 0x7f]));
+}
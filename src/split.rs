@@ -0,0 +1,286 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, Utc};
+
+use crate::track::{GpsData, Track, TrackPoint, TrackSegment};
+
+/// The default gap to split on, in minutes.
+const DEFAULT_GAP: i64 = 30;
+
+/// The time zone of the calendar days.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Zone {
+    Utc,
+    Local,
+    Offset(FixedOffset),
+}
+
+impl Zone {
+    fn parse(zone: &str) -> Option<Zone> {
+        match zone {
+            "utc" => Some(Zone::Utc),
+            "local" => Some(Zone::Local),
+            _ => zone.parse().ok().map(Zone::Offset),
+        }
+    }
+
+    fn date(self, time: DateTime<Utc>) -> NaiveDate {
+        match self {
+            Zone::Utc => time.date_naive(),
+            Zone::Local => time.with_timezone(&Local).date_naive(),
+            Zone::Offset(offset) => time.with_timezone(&offset).date_naive(),
+        }
+    }
+}
+
+/// How to split the tracks into several files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Split {
+    /// When there is no point for longer than the duration.
+    Gap(chrono::Duration),
+    /// On each calendar day in the time zone.
+    Day(Zone),
+    /// On each segment recorded by the device.
+    Segment,
+}
+
+impl Split {
+    /// Parse `gap[:MINUTES]`, `day[:ZONE]` or `segment`. The zone is
+    /// `utc`, `local` or an offset like `+02:00`.
+    pub fn parse(split: &str) -> Option<Split> {
+        let (mode, arg) = match split.split_once(':') {
+            Some((mode, arg)) => (mode, Some(arg)),
+            None => (split, None),
+        };
+        match (mode, arg) {
+            ("gap", None) => Some(Split::Gap(chrono::Duration::minutes(DEFAULT_GAP))),
+            ("gap", Some(minutes)) => minutes
+                .parse()
+                .ok()
+                .filter(|&minutes| minutes > 0)
+                .map(|minutes| Split::Gap(chrono::Duration::minutes(minutes))),
+            ("day", None) => Some(Split::Day(Zone::Local)),
+            ("day", Some(zone)) => Zone::parse(zone).map(Split::Day),
+            ("segment", None) => Some(Split::Segment),
+            _ => None,
+        }
+    }
+
+    /// Return true if `point` starts a new piece, after `previous`, the
+    /// last point with a time.
+    fn splits(self, previous: Option<DateTime<Utc>>, point: &TrackPoint) -> bool {
+        let (previous, time) = match (previous, point.time) {
+            (Some(previous), Some(time)) => (previous, time),
+            _ => return false,
+        };
+        match self {
+            Split::Gap(gap) => time - previous > gap,
+            Split::Day(zone) => zone.date(time) != zone.date(previous),
+            Split::Segment => false,
+        }
+    }
+}
+
+/// Split the tracks of `data` into pieces. The segments and the tracks
+/// are kept within a piece. A waypoint goes with the last piece that
+/// starts before it, or the first one.
+pub fn split(data: &GpsData, split: Split) -> Vec<GpsData> {
+    let mut pieces: Vec<GpsData> = vec![];
+    let mut current = GpsData::default();
+    let mut previous = None;
+    for track in &data.tracks {
+        let mut new_track = true;
+        for segment in &track.segments {
+            if split == Split::Segment && !current.tracks.is_empty() {
+                pieces.push(std::mem::take(&mut current));
+                new_track = true;
+            }
+            let mut new_segment = true;
+            for point in &segment.points {
+                if split.splits(previous, point) {
+                    pieces.push(std::mem::take(&mut current));
+                    new_track = true;
+                }
+                if new_track {
+                    current.tracks.push(Track {
+                        name: track.name.clone(),
+                        segments: vec![],
+                    });
+                    new_track = false;
+                    new_segment = true;
+                }
+                let segments = &mut current.tracks.last_mut().unwrap().segments;
+                if new_segment {
+                    segments.push(TrackSegment::default());
+                    new_segment = false;
+                }
+                segments.last_mut().unwrap().points.push(point.clone());
+                previous = point.time.or(previous);
+            }
+        }
+    }
+    if !current.tracks.is_empty() {
+        pieces.push(current);
+    }
+    if pieces.len() < 2 {
+        return vec![data.clone()];
+    }
+
+    let starts: Vec<Option<DateTime<Utc>>> = pieces
+        .iter()
+        .map(|piece| piece.points().find_map(|point| point.time))
+        .collect();
+    for waypoint in &data.waypoints {
+        let index = waypoint
+            .point
+            .time
+            .and_then(|time| {
+                starts
+                    .iter()
+                    .rposition(|start| start.is_some_and(|start| start <= time))
+            })
+            .unwrap_or(0);
+        pieces[index].waypoints.push(waypoint.clone());
+    }
+    pieces
+}
+
+#[cfg(test)]
+fn point_at(timestamp: i64) -> TrackPoint {
+    TrackPoint {
+        time: DateTime::from_timestamp(timestamp, 0),
+        ..TrackPoint::default()
+    }
+}
+
+#[cfg(test)]
+fn times(data: &GpsData) -> Vec<Vec<i64>> {
+    data.tracks
+        .iter()
+        .flat_map(|track| track.segments.iter())
+        .map(|segment| {
+            segment
+                .points
+                .iter()
+                .map(|point| point.time.map(|time| time.timestamp()).unwrap_or(-1))
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(
+        Split::parse("gap"),
+        Some(Split::Gap(chrono::Duration::minutes(30)))
+    );
+    assert_eq!(
+        Split::parse("gap:90"),
+        Some(Split::Gap(chrono::Duration::minutes(90)))
+    );
+    assert_eq!(Split::parse("gap:0"), None);
+    assert_eq!(Split::parse("gap:x"), None);
+    assert_eq!(Split::parse("day"), Some(Split::Day(Zone::Local)));
+    assert_eq!(Split::parse("day:utc"), Some(Split::Day(Zone::Utc)));
+    assert_eq!(
+        Split::parse("day:+02:00"),
+        Some(Split::Day(Zone::Offset(
+            FixedOffset::east_opt(7200).unwrap()
+        )))
+    );
+    assert_eq!(Split::parse("day:mars"), None);
+    assert_eq!(Split::parse("segment"), Some(Split::Segment));
+    assert_eq!(Split::parse("segment:1"), None);
+    assert_eq!(Split::parse("week"), None);
+}
+
+#[test]
+fn test_split() {
+    use crate::track::Waypoint;
+
+    // 2024-05-04 22:00 UTC, and 23:00 with a point without time.
+    let data = GpsData {
+        tracks: vec![Track {
+            name: Some("log".to_string()),
+            segments: vec![
+                TrackSegment {
+                    points: vec![point_at(1_714_860_000), point_at(1_714_860_600)],
+                },
+                TrackSegment {
+                    points: vec![
+                        point_at(1_714_863_600),
+                        TrackPoint::default(),
+                        point_at(1_714_864_200),
+                    ],
+                },
+            ],
+        }],
+        waypoints: vec![
+            Waypoint {
+                point: point_at(1_714_864_000),
+                ..Waypoint::default()
+            },
+            Waypoint::default(),
+        ],
+    };
+
+    let pieces = split(&data, Split::Gap(chrono::Duration::minutes(30)));
+    assert_eq!(pieces.len(), 2);
+    assert_eq!(times(&pieces[0]), vec![vec![1_714_860_000, 1_714_860_600]]);
+    assert_eq!(
+        times(&pieces[1]),
+        vec![vec![1_714_863_600, -1, 1_714_864_200]]
+    );
+    assert_eq!(pieces[1].tracks[0].name.as_deref(), Some("log"));
+    assert_eq!(pieces[0].waypoints.len(), 1);
+    assert_eq!(pieces[1].waypoints.len(), 1);
+    assert_eq!(
+        pieces[1].waypoints[0].point.time,
+        point_at(1_714_864_000).time
+    );
+
+    // A long gap: nothing to split.
+    let pieces = split(&data, Split::Gap(chrono::Duration::minutes(60)));
+    assert_eq!(pieces, vec![data.clone()]);
+
+    // Midnight is between the segments at +01:30, not in UTC.
+    let pieces = split(&data, Split::Day(Zone::Utc));
+    assert_eq!(pieces, vec![data.clone()]);
+    let offset = FixedOffset::east_opt(5400).unwrap();
+    let pieces = split(&data, Split::Day(Zone::Offset(offset)));
+    assert_eq!(pieces.len(), 2);
+    assert_eq!(times(&pieces[1]).len(), 1);
+
+    let pieces = split(&data, Split::Segment);
+    assert_eq!(pieces.len(), 2);
+    assert_eq!(times(&pieces[0]), vec![vec![1_714_860_000, 1_714_860_600]]);
+
+    // The waypoint doesn't go with a piece without time.
+    let mut data = data;
+    data.tracks[0].segments.insert(
+        1,
+        TrackSegment {
+            points: vec![TrackPoint::default()],
+        },
+    );
+    data.waypoints[0].point = point_at(1_714_861_000);
+    let pieces = split(&data, Split::Segment);
+    assert_eq!(pieces.len(), 3);
+    assert_eq!(times(&pieces[1]), vec![vec![-1]]);
+    assert_eq!(pieces[0].waypoints.len(), 2);
+    assert!(pieces[1].waypoints.is_empty());
+}
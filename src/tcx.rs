@@ -0,0 +1,250 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::Write;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::track::{GpsData, Track, TrackSegment};
+
+const TCX_NS: &str = "http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2";
+const TPX_NS: &str = "http://www.garmin.com/xmlschemas/ActivityExtension/v2";
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+fn write_lap<W: Write>(
+    out: &mut W,
+    segment: &TrackSegment,
+    start: DateTime<Utc>,
+    distance: &mut f64,
+) -> io::Result<()> {
+    // Time is mandatory for a Trackpoint: the distances are between
+    // the points written.
+    let segment = segment.timed();
+    let total_time = segment
+        .time_span()
+        .map(|(begin, end)| (end - begin).num_milliseconds() as f64 / 1000.0)
+        .unwrap_or(0.0);
+    writeln!(out, "      <Lap StartTime=\"{}\">", format_time(start))?;
+    writeln!(
+        out,
+        "        <TotalTimeSeconds>{total_time:.1}</TotalTimeSeconds>"
+    )?;
+    writeln!(
+        out,
+        "        <DistanceMeters>{:.1}</DistanceMeters>",
+        segment.distance()
+    )?;
+    writeln!(out, "        <Calories>0</Calories>")?;
+    writeln!(out, "        <Intensity>Active</Intensity>")?;
+    writeln!(out, "        <TriggerMethod>Manual</TriggerMethod>")?;
+    writeln!(out, "        <Track>")?;
+    let mut previous = None;
+    for point in &segment.points {
+        if let Some(previous) = previous {
+            *distance += point.distance(previous);
+        }
+        previous = Some(point);
+        let time = match point.time {
+            Some(time) => time,
+            None => continue,
+        };
+        writeln!(out, "          <Trackpoint>")?;
+        writeln!(out, "            <Time>{}</Time>", format_time(time))?;
+        writeln!(out, "            <Position>")?;
+        writeln!(
+            out,
+            "              <LatitudeDegrees>{:.9}</LatitudeDegrees>",
+            point.lat
+        )?;
+        writeln!(
+            out,
+            "              <LongitudeDegrees>{:.9}</LongitudeDegrees>",
+            point.lon
+        )?;
+        writeln!(out, "            </Position>")?;
+        if let Some(ele) = point.altitude() {
+            writeln!(out, "            <AltitudeMeters>{ele:.3}</AltitudeMeters>")?;
+        }
+        writeln!(
+            out,
+            "            <DistanceMeters>{distance:.2}</DistanceMeters>"
+        )?;
+        if let Some(speed) = point.speed {
+            writeln!(out, "            <Extensions>")?;
+            writeln!(out, "              <TPX xmlns=\"{TPX_NS}\">")?;
+            writeln!(out, "                <Speed>{speed:.3}</Speed>")?;
+            writeln!(out, "              </TPX>")?;
+            writeln!(out, "            </Extensions>")?;
+        }
+        writeln!(out, "          </Trackpoint>")?;
+    }
+    writeln!(out, "        </Track>")?;
+    writeln!(out, "      </Lap>")
+}
+
+fn write_activity<W: Write>(out: &mut W, track: &Track) -> io::Result<()> {
+    let laps: Vec<(&TrackSegment, DateTime<Utc>)> = track
+        .segments
+        .iter()
+        .filter_map(|segment| segment.time_span().map(|(begin, _)| (segment, begin)))
+        .collect();
+    // An activity is identified by its start time.
+    let id = match laps.first() {
+        Some((_, start)) => *start,
+        None => return Ok(()),
+    };
+    writeln!(out, "    <Activity Sport=\"Other\">")?;
+    writeln!(out, "      <Id>{}</Id>", format_time(id))?;
+    let mut distance = 0.0;
+    for (segment, start) in laps {
+        write_lap(out, segment, start, &mut distance)?;
+    }
+    writeln!(out, "    </Activity>")
+}
+
+/// Write the tracks of `data` as TCX. Each track is an activity and
+/// each segment a lap. Points without a time and waypoints are ignored.
+pub fn write<W: Write>(out: &mut W, data: &GpsData) -> io::Result<()> {
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(out, "<TrainingCenterDatabase xmlns=\"{TCX_NS}\">")?;
+    writeln!(out, "  <Activities>")?;
+    for track in &data.tracks {
+        write_activity(out, track)?;
+    }
+    writeln!(out, "  </Activities>")?;
+    writeln!(out, "</TrainingCenterDatabase>")
+}
+
+#[cfg(test)]
+mod decoder {
+    use chrono::DateTime;
+    use quick_xml::events::Event;
+
+    use crate::track::{TrackPoint, TrackSegment};
+
+    /// Decode the laps of a TCX document.
+    pub fn decode(tcx: &str) -> Result<Vec<TrackSegment>, String> {
+        let mut reader = quick_xml::reader::Reader::from_str(tcx);
+        let mut laps = vec![];
+        let mut point: Option<TrackPoint> = None;
+        let mut element = vec![];
+        loop {
+            match reader.read_event().map_err(|e| e.to_string())? {
+                Event::Start(ref e) => {
+                    let name = e.local_name().as_ref().to_vec();
+                    match name.as_slice() {
+                        b"Lap" => laps.push(TrackSegment::default()),
+                        b"Trackpoint" => point = Some(TrackPoint::default()),
+                        _ => {}
+                    }
+                    element = name;
+                }
+                Event::End(ref e) => {
+                    if e.local_name().as_ref() == b"Trackpoint" {
+                        let lap = laps.last_mut().ok_or("Trackpoint outside a Lap")?;
+                        lap.points.push(point.take().unwrap());
+                    }
+                    element.clear();
+                }
+                Event::Text(ref t) => {
+                    let text = t.unescape().map_err(|e| e.to_string())?;
+                    if let Some(ref mut point) = point {
+                        let value = || text.parse::<f64>().map_err(|e| e.to_string());
+                        match element.as_slice() {
+                            b"Time" => {
+                                point.time = Some(
+                                    DateTime::parse_from_rfc3339(&text)
+                                        .map_err(|e| e.to_string())?
+                                        .into(),
+                                )
+                            }
+                            b"LatitudeDegrees" => point.lat = value()?,
+                            b"LongitudeDegrees" => point.lon = value()?,
+                            b"AltitudeMeters" => point.elevation = Some(value()?),
+                            b"Speed" => point.speed = Some(value()?),
+                            _ => {}
+                        }
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        Ok(laps)
+    }
+}
+
+#[test]
+fn test_write() {
+    use crate::track::{test_point, TrackPoint};
+
+    let point = TrackPoint {
+        speed: Some(1.25),
+        ..test_point(0)
+    };
+    let point2 = TrackPoint {
+        lat: 45.501,
+        elevation: None,
+        ..test_point(60)
+    };
+    let data = GpsData {
+        tracks: vec![
+            Track {
+                name: None,
+                segments: vec![
+                    TrackSegment {
+                        points: vec![point.clone(), TrackPoint::default(), point2.clone()],
+                    },
+                    TrackSegment {
+                        points: vec![point.clone()],
+                    },
+                ],
+            },
+            // No time: not an activity.
+            Track {
+                name: None,
+                segments: vec![TrackSegment {
+                    points: vec![TrackPoint::default()],
+                }],
+            },
+        ],
+        waypoints: vec![],
+    };
+
+    let mut output = vec![];
+    write(&mut output, &data).expect("Failed to write TCX");
+    let tcx = String::from_utf8(output).unwrap();
+    assert_eq!(tcx.matches("<Activity ").count(), 1);
+    assert!(tcx.contains("<Id>2024-05-04T12:00:00Z</Id>"));
+    assert!(tcx.contains("<TotalTimeSeconds>60.0</TotalTimeSeconds>"));
+    // Not through the point without time.
+    let distance = point.distance(&point2);
+    assert!(tcx.contains(&format!(
+        "        <DistanceMeters>{distance:.1}</DistanceMeters>"
+    )));
+    assert!(tcx.contains(&format!(
+        "            <DistanceMeters>{distance:.2}</DistanceMeters>"
+    )));
+
+    let laps = decoder::decode(&tcx).expect("Failed to decode TCX");
+    assert_eq!(laps.len(), 2);
+    assert_eq!(laps[0].points, vec![point.clone(), point2]);
+    assert_eq!(laps[1].points, vec![point]);
+}
@@ -0,0 +1,364 @@
+//
+// (c) 2024 Hubert Figuière
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, Utc};
+
+/// Mean radius of the Earth, in meters.
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// The type of fix of a point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FixType {
+    None,
+    TwoD,
+    ThreeD,
+    Dgps,
+    Pps,
+}
+
+impl FixType {
+    /// The fix as the string used by GPX.
+    pub fn to_gpx(self) -> &'static str {
+        match self {
+            FixType::None => "none",
+            FixType::TwoD => "2d",
+            FixType::ThreeD => "3d",
+            FixType::Dgps => "dgps",
+            FixType::Pps => "pps",
+        }
+    }
+
+    /// The fix from the string used by GPX.
+    pub fn from_gpx(fix: &str) -> Option<FixType> {
+        match fix {
+            "none" => Some(FixType::None),
+            "2d" => Some(FixType::TwoD),
+            "3d" => Some(FixType::ThreeD),
+            "dgps" => Some(FixType::Dgps),
+            "pps" => Some(FixType::Pps),
+            _ => None,
+        }
+    }
+}
+
+/// A point, as recorded by the device.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackPoint {
+    pub time: Option<DateTime<Utc>>,
+    /// Latitude in degrees.
+    pub lat: f64,
+    /// Longitude in degrees.
+    pub lon: f64,
+    /// Elevation in meters.
+    pub elevation: Option<f64>,
+    /// Pressure altitude in meters, from a barometric sensor.
+    pub pressure_altitude: Option<f64>,
+    /// Speed in m/s.
+    pub speed: Option<f64>,
+    /// Course over ground in degrees from true north.
+    pub course: Option<f64>,
+    pub hdop: Option<f64>,
+    /// Number of satellites used for the fix.
+    pub satellites: Option<u32>,
+    pub fix: Option<FixType>,
+}
+
+impl TrackPoint {
+    /// The elevation, or the pressure altitude if there is none, as
+    /// with a barometric device. For the formats with only one altitude.
+    pub fn altitude(&self) -> Option<f64> {
+        self.elevation.or(self.pressure_altitude)
+    }
+
+    /// The distance to `other` in meters, using the haversine formula.
+    pub fn distance(&self, other: &TrackPoint) -> f64 {
+        let lat1 = self.lat.to_radians();
+        let lat2 = other.lat.to_radians();
+        let dlat = lat2 - lat1;
+        let dlon = (other.lon - self.lon).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().asin()
+    }
+}
+
+/// A continuous sequence of points.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackSegment {
+    pub points: Vec<TrackPoint>,
+}
+
+impl TrackSegment {
+    /// The length of the segment in meters.
+    pub fn distance(&self) -> f64 {
+        self.points.windows(2).map(|p| p[0].distance(&p[1])).sum()
+    }
+
+    /// The segment with only the points that have a time.
+    pub fn timed(&self) -> TrackSegment {
+        TrackSegment {
+            points: self
+                .points
+                .iter()
+                .filter(|point| point.time.is_some())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// The time of the first and the last points that have one.
+    pub fn time_span(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let mut times = self.points.iter().filter_map(|point| point.time);
+        let begin = times.next()?;
+        Some((begin, times.next_back().unwrap_or(begin)))
+    }
+}
+
+/// A track, made of segments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Track {
+    pub name: Option<String>,
+    pub segments: Vec<TrackSegment>,
+}
+
+impl Track {
+    /// Return true if no segment has points.
+    pub fn is_empty(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| segment.points.is_empty())
+    }
+}
+
+/// A waypoint, a point of interest.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Waypoint {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub point: TrackPoint,
+}
+
+/// The data downloaded from the device.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GpsData {
+    pub tracks: Vec<Track>,
+    pub waypoints: Vec<Waypoint>,
+}
+
+impl GpsData {
+    /// Return true if there is neither track points nor waypoints.
+    pub fn is_empty(&self) -> bool {
+        self.waypoints.is_empty() && self.points().next().is_none()
+    }
+
+    /// Iterate over all the track points.
+    pub fn points(&self) -> impl Iterator<Item = &TrackPoint> {
+        self.tracks
+            .iter()
+            .flat_map(|track| track.segments.iter())
+            .flat_map(|segment| segment.points.iter())
+    }
+
+    /// Iterate over all the track points, mutably.
+    pub fn points_mut(&mut self) -> impl Iterator<Item = &mut TrackPoint> {
+        self.tracks
+            .iter_mut()
+            .flat_map(|track| track.segments.iter_mut())
+            .flat_map(|segment| segment.points.iter_mut())
+    }
+
+    /// The time of the last track point or waypoint.
+    pub fn last_time(&self) -> Option<DateTime<Utc>> {
+        self.points()
+            .chain(self.waypoints.iter().map(|waypoint| &waypoint.point))
+            .filter_map(|point| point.time)
+            .max()
+    }
+
+    /// Keep only the track points and the waypoints recorded after
+    /// `since`. A point without time goes with the previous one. The
+    /// segments and tracks left empty are removed.
+    pub fn retain_after(&mut self, since: DateTime<Utc>) {
+        let mut keep = false;
+        for track in &mut self.tracks {
+            for segment in &mut track.segments {
+                segment.points.retain(|point| {
+                    if let Some(time) = point.time {
+                        keep = time > since;
+                    }
+                    keep
+                });
+            }
+            track.segments.retain(|segment| !segment.points.is_empty());
+        }
+        self.tracks.retain(|track| !track.segments.is_empty());
+        self.waypoints
+            .retain(|waypoint| waypoint.point.time.map(|time| time > since).unwrap_or(true));
+    }
+}
+
+/// A point in Montréal, `seconds` after 2024-05-04T12:00:00Z.
+#[cfg(test)]
+pub fn test_point(seconds: i64) -> TrackPoint {
+    TrackPoint {
+        time: DateTime::from_timestamp(1_714_824_000 + seconds, 0),
+        lat: 45.5,
+        lon: -73.5,
+        elevation: Some(30.5),
+        ..TrackPoint::default()
+    }
+}
+
+/// Data with a single track made of `segments`.
+#[cfg(test)]
+pub fn test_data(segments: Vec<Vec<TrackPoint>>) -> GpsData {
+    GpsData {
+        tracks: vec![Track {
+            name: None,
+            segments: segments
+                .into_iter()
+                .map(|points| TrackSegment { points })
+                .collect(),
+        }],
+        waypoints: vec![],
+    }
+}
+
+#[test]
+fn test_points() {
+    let point = TrackPoint {
+        lat: 45.5,
+        lon: -73.5,
+        ..TrackPoint::default()
+    };
+    let mut data = GpsData::default();
+    assert!(data.is_empty());
+
+    data.tracks.push(Track {
+        name: None,
+        segments: vec![
+            TrackSegment {
+                points: vec![point.clone()],
+            },
+            TrackSegment {
+                points: vec![point.clone(), point],
+            },
+        ],
+    });
+    assert!(!data.is_empty());
+    assert_eq!(data.points().count(), 3);
+
+    let barometric = TrackPoint {
+        pressure_altitude: Some(1230.4),
+        ..TrackPoint::default()
+    };
+    assert_eq!(barometric.altitude(), Some(1230.4));
+    let point = TrackPoint {
+        elevation: Some(1200.0),
+        ..barometric
+    };
+    assert_eq!(point.altitude(), Some(1200.0));
+}
+
+#[test]
+fn test_distance() {
+    let point = TrackPoint {
+        lat: 45.0,
+        lon: -73.0,
+        ..test_point(0)
+    };
+    let point2 = TrackPoint {
+        lat: 46.0,
+        ..point.clone()
+    };
+    assert!((point.distance(&point2) - 111_195.0).abs() < 1.0);
+    assert_eq!(point.distance(&point), 0.0);
+
+    let point3 = TrackPoint {
+        lat: 47.0,
+        time: test_point(60).time,
+        ..point.clone()
+    };
+    let segment = TrackSegment {
+        points: vec![point.clone(), point2, point3.clone()],
+    };
+    assert!((segment.distance() - 222_390.0).abs() < 2.0);
+    assert_eq!(
+        segment.time_span(),
+        Some((point.time.unwrap(), point3.time.unwrap()))
+    );
+    assert_eq!(TrackSegment::default().time_span(), None);
+}
+
+#[test]
+fn test_retain_after() {
+    let point_at = |timestamp| TrackPoint {
+        time: DateTime::from_timestamp(timestamp, 0),
+        ..TrackPoint::default()
+    };
+    let mut data = GpsData {
+        tracks: vec![
+            Track {
+                name: None,
+                segments: vec![TrackSegment {
+                    points: vec![point_at(100), TrackPoint::default(), point_at(200)],
+                }],
+            },
+            Track {
+                name: None,
+                segments: vec![
+                    TrackSegment {
+                        points: vec![point_at(300), TrackPoint::default(), point_at(400)],
+                    },
+                    TrackSegment {
+                        points: vec![point_at(500)],
+                    },
+                ],
+            },
+        ],
+        waypoints: vec![
+            Waypoint {
+                point: point_at(150),
+                ..Waypoint::default()
+            },
+            Waypoint {
+                point: point_at(600),
+                ..Waypoint::default()
+            },
+            Waypoint::default(),
+        ],
+    };
+    assert_eq!(data.last_time(), DateTime::from_timestamp(600, 0));
+
+    data.retain_after(DateTime::from_timestamp(250, 0).unwrap());
+    assert_eq!(data.tracks.len(), 1);
+    assert_eq!(
+        data.tracks[0].segments,
+        vec![
+            TrackSegment {
+                points: vec![point_at(300), TrackPoint::default(), point_at(400)],
+            },
+            TrackSegment {
+                points: vec![point_at(500)],
+            },
+        ]
+    );
+    assert_eq!(data.waypoints.len(), 2);
+
+    data.retain_after(DateTime::from_timestamp(600, 0).unwrap());
+    assert!(data.tracks.is_empty());
+    assert_eq!(data.waypoints, vec![Waypoint::default()]);
+    assert_eq!(GpsData::default().last_time(), None);
+}
@@ -46,3 +46,16 @@ pub fn setup_text_combo(combo: &gtk::ComboBox, model: &gtk::ListStore) {
 pub fn add_text_row(store: &gtk::ListStore, col1: &str, col2: &str) -> gtk::TreeIter {
     store.insert_with_values(None, &[(0, &String::from(col1)), (1, &String::from(col2))])
 }
+
+/// Find the row with `col1` in the first column of the list store.
+pub fn find_text_row(store: &gtk::ListStore, col1: &str) -> Option<gtk::TreeIter> {
+    let iter = store.iter_first()?;
+    loop {
+        if store.get::<String>(&iter, 0) == col1 {
+            return Some(iter);
+        }
+        if !store.iter_next(&iter) {
+            return None;
+        }
+    }
+}
@@ -0,0 +1,147 @@
+#!/usr/bin/env python3
+# Assemble the FIT reference file, byte by byte, from the FIT protocol
+# and profile documentation of the FIT SDK. Independent of the Rust
+# encoder on purpose. The points are the ones of fit::test_reference.
+
+import math
+import struct
+
+FIT_EPOCH = 631065600
+EARTH_RADIUS = 6371000.0
+T = 1714824000
+
+ENUM, UINT16, SINT32, UINT32 = 0x00, 0x84, 0x85, 0x86
+INVALID_UINT16, INVALID_UINT32 = 0xffff, 0xffffffff
+
+CRC_TABLE = [
+    0x0000, 0xcc01, 0xd801, 0x1400, 0xf001, 0x3c00, 0x2800, 0xe401,
+    0xa001, 0x6c00, 0x7800, 0xb401, 0x5000, 0x9c01, 0x8801, 0x4400,
+]
+
+
+def crc(data, crc=0):
+    for byte in data:
+        tmp = CRC_TABLE[crc & 0xf]
+        crc = (crc >> 4) & 0x0fff
+        crc = crc ^ tmp ^ CRC_TABLE[byte & 0xf]
+        tmp = CRC_TABLE[crc & 0xf]
+        crc = (crc >> 4) & 0x0fff
+        crc = crc ^ tmp ^ CRC_TABLE[(byte >> 4) & 0xf]
+    return crc
+
+
+def definition(local, mesg_num, fields):
+    data = struct.pack('<BBBHB', 0x40 | local, 0, 0, mesg_num, len(fields))
+    for number, size, base_type in fields:
+        data += struct.pack('<BBB', number, size, base_type)
+    return data
+
+
+def timestamp(t):
+    return INVALID_UINT32 if t is None else t - FIT_EPOCH
+
+
+def semicircles(degrees):
+    # 2 ** 31 - 1 is the invalid value.
+    return max(-2 ** 31, min(round(degrees * (2 ** 31 / 180.0)), 2 ** 31 - 2))
+
+
+def uint16(value):
+    value = round(value)
+    return value if 0 <= value < INVALID_UINT16 else INVALID_UINT16
+
+
+def distance(p1, p2):
+    lat1 = math.radians(p1[1])
+    lat2 = math.radians(p2[1])
+    dlat = lat2 - lat1
+    dlon = math.radians(p2[2] - p1[2])
+    a = math.sin(dlat / 2.0) ** 2 \
+        + math.cos(lat1) * math.cos(lat2) * math.sin(dlon / 2.0) ** 2
+    return 2.0 * EARTH_RADIUS * math.asin(math.sqrt(a))
+
+
+# (time, lat, lon, elevation, speed)
+SEGMENTS = [
+    [
+        (T, 45.5, -73.5, 30.4, 1.25),
+        (T + 60, 45.501, -73.5, None, None),
+    ],
+    # the time goes backwards, the elevation and the speed are out of
+    # range, and the last point has no time.
+    [
+        (T + 120, 45.502, -73.5, -600.0, 70.0),
+        (T + 90, 45.503, -73.5, 13000.0, 65.534),
+        (None, 45.503, -73.499, 12606.8, 0.0),
+    ],
+]
+
+
+# the points without a time aren't written.
+SEGMENTS = [[p for p in segment if p[0] is not None] for segment in SEGMENTS]
+SEGMENTS = [segment for segment in SEGMENTS if segment]
+
+
+def span(segment):
+    times = [p[0] for p in segment if p[0] is not None]
+    return (times[0], times[-1])
+
+
+def elapsed(begin, end):
+    return max(0, (end - begin) * 1000)
+
+
+data = definition(0, 0, [(0, 1, ENUM), (1, 2, UINT16), (2, 2, UINT16),
+                         (4, 4, UINT32)])
+spans = [span(segment) for segment in SEGMENTS]
+start = spans[0][0]
+end = spans[-1][1]
+data += struct.pack('<BBHHI', 0, 4, 255, 0, timestamp(start))
+
+data += definition(1, 20, [(253, 4, UINT32), (0, 4, SINT32), (1, 4, SINT32),
+                           (2, 2, UINT16), (5, 4, UINT32), (6, 2, UINT16)])
+total = 0.0
+for segment in SEGMENTS:
+    previous = None
+    for point in segment:
+        if previous is not None:
+            total += distance(previous, point)
+        time, lat, lon, ele, speed = point
+        data += struct.pack(
+            '<BIiiHIH', 1, timestamp(time), semicircles(lat), semicircles(lon),
+            INVALID_UINT16 if ele is None else uint16((ele + 500.0) * 5.0),
+            round(total * 100.0),
+            INVALID_UINT16 if speed is None else uint16(speed * 1000.0))
+        previous = point
+
+data += definition(2, 19, [(253, 4, UINT32), (254, 2, UINT16), (0, 1, ENUM),
+                           (1, 1, ENUM), (2, 4, UINT32), (7, 4, UINT32),
+                           (8, 4, UINT32), (9, 4, UINT32)])
+for index, segment in enumerate(SEGMENTS):
+    begin, last = spans[index]
+    length = sum(distance(segment[i], segment[i + 1])
+                 for i in range(len(segment) - 1))
+    data += struct.pack('<BIHBBIIII', 2, timestamp(last), index, 9, 1,
+                        timestamp(begin), elapsed(begin, last),
+                        elapsed(begin, last), round(length * 100.0))
+
+total_time = sum(elapsed(*s) for s in spans)
+data += definition(3, 18, [(253, 4, UINT32), (0, 1, ENUM), (1, 1, ENUM),
+                           (2, 4, UINT32), (5, 1, ENUM), (7, 4, UINT32),
+                           (8, 4, UINT32), (9, 4, UINT32), (25, 2, UINT16),
+                           (26, 2, UINT16)])
+data += struct.pack('<BIBBIBIIIHH', 3, timestamp(end), 8, 1, timestamp(start),
+                    0, elapsed(start, end), total_time, round(total * 100.0),
+                    0, len(SEGMENTS))
+
+data += definition(4, 34, [(253, 4, UINT32), (0, 4, UINT32), (1, 2, UINT16),
+                           (2, 1, ENUM), (3, 1, ENUM), (4, 1, ENUM)])
+data += struct.pack('<BIIHBBB', 4, timestamp(end), total_time, 1, 0, 26, 1)
+
+header = struct.pack('<BBHI4s', 14, 0x20, 2140, len(data), b'.FIT')
+header += struct.pack('<H', crc(header))
+file = header + data
+file += struct.pack('<H', crc(file))
+
+with open('reference.fit', 'wb') as f:
+    f.write(file)
@@ -0,0 +1,97 @@
+#!/usr/bin/env python3
+# Assemble the MTK log flash fixtures, byte by byte, from the record
+# layout documented by BT747 and gpsbabel's mtk_logger. Independent of
+# the Rust decoder on purpose.
+
+import struct
+
+SECTOR_HEADER_SIZE = 0x200
+
+UTC, VALID, LAT, LON, HEIGHT, SPEED, TRACK = (1 << n for n in range(7))
+HDOP, NSAT, RCR = 1 << 10, 1 << 12, 1 << 17
+
+
+def checksum(data):
+    sum = 0
+    for b in data:
+        sum ^= b
+    return sum
+
+
+def record(holux, fmt, time, valid, lat, lon, height, speed=0.0, track=0.0,
+           hdop=0, nsat=0, rcr=1, corrupt=False):
+    r = b''
+    if fmt & UTC:
+        r += struct.pack('<I', time)
+    if fmt & VALID:
+        r += struct.pack('<H', valid)
+    if fmt & LAT:
+        r += struct.pack('<f' if holux else '<d', lat)
+    if fmt & LON:
+        r += struct.pack('<f' if holux else '<d', lon)
+    if fmt & HEIGHT:
+        r += struct.pack('<f', height)[1:] if holux else struct.pack('<f', height)
+    if fmt & SPEED:
+        r += struct.pack('<f', speed)
+    if fmt & TRACK:
+        r += struct.pack('<f', track)
+    if fmt & HDOP:
+        r += struct.pack('<H', hdop)
+    if fmt & NSAT:
+        r += bytes([nsat, nsat + 2])
+    if fmt & RCR:
+        r += struct.pack('<H', rcr)
+    sum = checksum(r) ^ (0xff if corrupt else 0)
+    return r + (b'' if holux else b'*') + bytes([sum])
+
+
+def special(kind, value):
+    return b'\xaa' * 7 + bytes([kind]) + struct.pack('<I', value) + b'\xbb' * 4
+
+
+def header(fmt):
+    # count 0xffff: the sector being written.
+    return struct.pack('<HI', 0xffff, fmt) + b'\xff' * (SECTOR_HEADER_SIZE - 6)
+
+
+def m241():
+    fmt = UTC | VALID | LAT | LON | HEIGHT
+    fmt2 = fmt | SPEED | TRACK
+    data = header(fmt)
+    data += b'HOLUXGR241LOGGER'
+    data += record(True, fmt, 1714824000, 2, 45.5, -73.5, 30.5)
+    data += record(True, fmt, 1714824005, 2, 45.50025, -73.50025, 31.0)
+    data += record(True, fmt, 1714824010, 1, 0.0, 0.0, 0.0)
+    data += b'HOLUXGR241WAYPNT'
+    data += b'    '
+    data += record(True, fmt, 1714824015, 4, 45.5005, -73.5005, 32.0)
+    data += special(2, fmt2)
+    data += record(True, fmt2, 1714824020, 2, 45.50075, -73.50075, 33.5,
+                   speed=18.0, track=90.0)
+    data += record(True, fmt2, 1714824025, 2, 45.501, -73.501, 34.0,
+                   corrupt=True)
+    data += special(7, 0x0106)
+    data += record(True, fmt2, 1714827600, 2, 46.0, -74.0, 100.0,
+                   speed=3.6, track=180.0)
+    return data + b'\xff' * 64
+
+
+def mtk():
+    fmt = UTC | VALID | LAT | LON | HEIGHT | HDOP | NSAT | RCR
+    data = header(fmt)
+    data += record(False, fmt, 1714824000, 2, 45.123456789, -73.987654321,
+                   30.5, hdop=120, nsat=7)
+    data += record(False, fmt, 1714824001, 1, 0.0, 0.0, 0.0)
+    # RCR button: a waypoint.
+    data += record(False, fmt, 1714824002, 2, 45.123556789, -73.987554321,
+                   31.25, hdop=95, nsat=9, rcr=0x0008)
+    data += record(False, fmt, 1714824003, 2, 45.2, -73.9, 32.0,
+                   corrupt=True)
+    return data + b'\xff' * 64
+
+
+if __name__ == '__main__':
+    with open('m241.bin', 'wb') as f:
+        f.write(m241())
+    with open('mtk.bin', 'wb') as f:
+        f.write(mtk())